@@ -1,11 +1,17 @@
 //! This is the spm command line utility.
 
+use std::path::Path;
+
 use spm_core::{
-    spm::{Context, Master, Mode, Worker},
-    Args,
+    models::{
+        llama3::{LLama, LlamaConfig},
+        Generator,
+    },
+    spm::{quantize_model, split_model, Context, Master, Mode, Planner, Topology, Worker},
+    Args, Command, TopologyCommand,
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use clap::Parser;
 
 #[tokio::main]
@@ -24,6 +30,11 @@ async fn main() -> Result<()> {
         .format_target(false)
         .init();
 
+    // run offline utilities without loading the model
+    if let Some(command) = &args.command {
+        return run_command(&args, command).await;
+    }
+
     // setup context
     let ctx = Context::from_args(args)?;
 
@@ -53,3 +64,136 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+/// Run an offline utility.
+async fn run_command(args: &Args, command: &Command) -> Result<()> {
+    match command {
+        Command::Topology {
+            command: TopologyCommand::Check,
+        } => {
+            let config = LlamaConfig::from_model(Path::new(&args.model))?.into_config();
+            let topology = Topology::from_path(&args.topology)?;
+
+            topology.check(config.num_hidden_layers)?;
+
+            let mut names: Vec<&String> = topology.keys().collect();
+            names.sort();
+            for name in names {
+                let node = &topology[name];
+                println!("{name}@{} {} layers", &node.host, node.layers.len());
+            }
+            println!(
+                "{} is valid for {} layers",
+                &args.topology, config.num_hidden_layers
+            );
+
+            Ok(())
+        }
+        Command::Topology {
+            command: TopologyCommand::Plan { output },
+        } => {
+            let planner = Planner::from_args(args, LLama::MODEL_NAME).await?;
+            let plan = planner.plan()?;
+
+            for assignment in &plan {
+                println!("{assignment}");
+            }
+            let total: std::time::Duration = plan.iter().map(|a| a.time).sum();
+            println!("estimated time: {total:?}/token");
+
+            planner.to_topology(&plan).save(output)?;
+            println!("topology saved to {output}");
+
+            Ok(())
+        }
+        Command::Split { output } => {
+            let data_path = Path::new(&args.model);
+            let config = LlamaConfig::from_model(data_path)?.into_config();
+            let topology = Topology::from_path(&args.topology)?;
+
+            topology.check(config.num_hidden_layers)?;
+
+            let shards = split_model(
+                data_path,
+                &topology,
+                config.num_hidden_layers,
+                Path::new(output),
+            )?;
+            for shard in &shards {
+                println!("{shard}");
+            }
+
+            Ok(())
+        }
+        Command::Quantize { out, format, split } => {
+            let data_path = Path::new(&args.model);
+            let config = LlamaConfig::from_model(data_path)?.into_config();
+            let topology = if *split {
+                let topology = Topology::from_path(&args.topology)?;
+                topology.check(config.num_hidden_layers)?;
+                Some(topology)
+            } else {
+                None
+            };
+
+            let shards =
+                quantize_model(data_path, &config, *format, topology.as_ref(), Path::new(out))?;
+            for shard in &shards {
+                println!("{shard}");
+            }
+
+            Ok(())
+        }
+        Command::Perplexity { text } => {
+            let text = std::fs::read_to_string(text)
+                .map_err(|e| anyhow!("can't read {text}: {:?}", e))?;
+
+            // evaluate the unquantized model first to compare against it
+            let mut runs = vec![args.quantize];
+            if args.quantize.is_some() {
+                // workers keep the quantization they were started with, both runs would
+                // evaluate their layers the same way
+                let topology = Topology::from_path(&args.topology)?;
+                let mut workers: Vec<&String> = topology
+                    .iter()
+                    .filter(|(_, node)| !node.layers.is_empty())
+                    .map(|(name, _)| name)
+                    .collect();
+                if !workers.is_empty() {
+                    workers.sort();
+                    bail!(
+                        "can't compare the quantization of the layers served by {}, use a topology without workers",
+                        workers.iter().map(|w| w.as_str()).collect::<Vec<_>>().join(", ")
+                    );
+                }
+                runs.insert(0, None);
+            }
+
+            let mut baseline = None;
+            for quantize in runs {
+                let ctx = Context::from_args(Args {
+                    mode: Mode::Master,
+                    quantize,
+                    ..args.clone()
+                })?;
+                let mut llm = LLama::load(ctx).await?;
+                let perplexity = llm.perplexity(&text).await?;
+
+                match (quantize, baseline) {
+                    (Some(quantization), Some(baseline)) => println!(
+                        "{quantization}: perplexity {perplexity:.4} ({:+.2}%)",
+                        (perplexity / baseline - 1.) * 100.
+                    ),
+                    (Some(quantization), None) => {
+                        println!("{quantization}: perplexity {perplexity:.4}")
+                    }
+                    // the checkpoint itself may be quantized
+                    (None, _) => println!("{}: perplexity {perplexity:.4}", &args.model),
+                }
+                baseline = Some(perplexity);
+            }
+
+            Ok(())
+        }
+    }
+}
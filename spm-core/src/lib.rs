@@ -2,9 +2,10 @@
 #[macro_use]
 extern crate anyhow;
 
-use spm::Mode;
+use spm::{Compression, Mode};
+use utils::Quantization;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 pub mod spm;
 pub mod models;
@@ -13,6 +14,10 @@ pub mod utils;
 #[derive(Clone, Parser, Default, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
+    /// Offline utility to run instead of the master or worker.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// GPU device index.
     #[arg(long, default_value_t = 0)]
     pub device: usize,
@@ -33,12 +38,12 @@ pub struct Args {
     #[arg(long)]
     pub api: Option<String>,
 
-    /// Llama3 model data path.
-    #[arg(long, default_value = "/home/firefly/Documents/llama3/Meta-Llama-3-8B-Instruct")]
+    /// Llama3 model data path, or a .gguf file.
+    #[arg(long, global = true, default_value = "/home/firefly/Documents/llama3/Meta-Llama-3-8B-Instruct")]
     pub model: String,
 
     /// Topology file.
-    #[arg(long, default_value = "/home/firefly/Documents/llama3/Spm_llama/topology.yml")]
+    #[arg(long, global = true, default_value = "/home/firefly/Documents/llama3/Spm_llama/topology.yml")]
     pub topology: String,
 
 
@@ -72,10 +77,95 @@ pub struct Args {
     /// The context size to consider for the repeat penalty.
     #[arg(long, default_value_t = 128)]
     pub repeat_last_n: usize,
+    /// Stop generating when this text is produced, can be passed multiple times.
+    #[arg(long)]
+    pub stop: Vec<String>,
     /// Use different dtype than f16
     #[arg(long)]
     pub dtype: Option<String>,
+    /// Quantize the weights of the attention and MLP linear layers, and of the lm_head, while
+    /// loading them.
+    #[arg(long, value_enum)]
+    pub quantize: Option<Quantization>,
+    /// Compression of the tensors exchanged with the workers.
+    #[arg(long, default_value_t, value_enum)]
+    pub compression: Compression,
+    /// File with the key shared by master and workers to authenticate connections.
+    #[arg(long)]
+    pub psk_file: Option<String>,
+    /// Worker TLS certificate in PEM format.
+    #[arg(long)]
+    pub tls_cert: Option<String>,
+    /// Worker TLS private key in PEM format.
+    #[arg(long)]
+    pub tls_key: Option<String>,
+    /// Max time in seconds to wait for the response of a worker.
+    #[arg(long, default_value_t = 120)]
+    pub request_timeout: u64,
+    /// Interval in seconds between heartbeats sent to idle workers, 0 to disable.
+    #[arg(long, default_value_t = 10)]
+    pub heartbeat: u64,
+    /// Number of attempts to reconnect to a worker before giving up.
+    #[arg(long, default_value_t = 5)]
+    pub reconnect_attempts: usize,
+    /// Memory in GiB this worker can use for layers, reported to the topology planner.
+    /// Defaults to the available device memory plus the memory of the loaded layers.
+    #[arg(long)]
+    pub max_memory: Option<f64>,
     /// Run on CPU rather than on GPU.
     #[arg(long)]
     pub cpu: bool,
 }
+
+/// Offline utilities, they don't start a master or a worker.
+#[derive(Clone, Subcommand, Debug)]
+pub enum Command {
+    /// Topology file utilities.
+    Topology {
+        #[command(subcommand)]
+        command: TopologyCommand,
+    },
+    /// Split the model in a directory per worker of the topology holding only its layers, and
+    /// one for the master with the embeddings, the final norm, the lm_head and local layers.
+    Split {
+        /// Where to create the directories.
+        #[arg(long)]
+        output: String,
+    },
+    /// Quantize the linear layers of the model and write it as a GGUF file that nodes load
+    /// directly.
+    Quantize {
+        /// Output file, or directory with --split.
+        #[arg(long)]
+        out: String,
+        /// Quantization of the linear layers.
+        #[arg(long, value_enum)]
+        format: Quantization,
+        /// Write a file per worker of the topology holding only its layers, and one for the
+        /// master, in the output directory.
+        #[arg(long)]
+        split: bool,
+    },
+    /// Compute the perplexity of the model on a text file. With --quantize the text is also
+    /// evaluated without quantization to compare them, which requires a topology without
+    /// workers since they keep the quantization they were started with.
+    Perplexity {
+        /// Text file to evaluate.
+        #[arg(long)]
+        text: String,
+    },
+}
+
+/// Topology file utilities.
+#[derive(Clone, Subcommand, Debug)]
+pub enum TopologyCommand {
+    /// Validate the topology against the model configuration without connecting to workers.
+    Check,
+    /// Probe the workers listed in the topology and write a topology balancing the layers
+    /// across them according to their memory and speed.
+    Plan {
+        /// Where to write the planned topology.
+        #[arg(long)]
+        output: String,
+    },
+}
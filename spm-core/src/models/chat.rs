@@ -1,17 +1,18 @@
 use serde::{Deserialize, Serialize};
 
 /// The role of a message in a chat.
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
 pub enum MessageRole {
     /// System prompt.
-    #[serde(alias = "system")]
+    #[serde(alias = "System")]
     #[default]
     System,
     /// User prompt.
-    #[serde(alias = "user")]
+    #[serde(alias = "User")]
     User,
     /// Assistant response.
-    #[serde(alias = "assistant")]
+    #[serde(alias = "Assistant")]
     Assistant,
 }
 
@@ -30,7 +31,7 @@ impl std::fmt::Display for MessageRole {
 }
 
 /// A chat message.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
     /// Message role.
     pub role: MessageRole,
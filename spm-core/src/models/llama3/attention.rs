@@ -1,17 +1,22 @@
 //! Causal self attention implementation.
 use candle_core::{DType, Result, Tensor, D};
-use candle_nn::{linear_no_bias as linear, Linear, Module, VarBuilder};
+use candle_nn::Module;
+
+use super::{linear, QLinear};
+use crate::utils::Weights;
 
 
 #[derive(Debug, Clone)]
 pub struct CausalSelfAttention {
-    q_proj: Linear,
-    k_proj: Linear,
-    v_proj: Linear,
-    o_proj: Linear,
+    q_proj: QLinear,
+    k_proj: QLinear,
+    v_proj: QLinear,
+    o_proj: QLinear,
     num_attention_heads: usize,
     num_key_value_heads: usize,
     head_dim: usize,
+    /// Apply the rotary embeddings to interleaved pairs, as GGUF checkpoints expect.
+    interleaved_rope: bool,
 }
 
 #[inline]
@@ -32,7 +37,11 @@ impl CausalSelfAttention {
         let (_batch_size, _, seq_len, _hidden_size) = x.dims4()?;
         let cos = cache.cosine(index_pos, seq_len)?;
         let sin = cache.sine(index_pos, seq_len)?;
-        candle_nn::rotary_emb::rope(x, &cos, &sin)
+        if self.interleaved_rope {
+            candle_nn::rotary_emb::rope_i(x, &cos, &sin)
+        } else {
+            candle_nn::rotary_emb::rope(x, &cos, &sin)
+        }
     }
 
     /// Process the input tensor using the given state indexes and cache.
@@ -92,7 +101,7 @@ impl CausalSelfAttention {
         
 
         let (k, v) = cache
-            .process_kv(block_idx, k, v) // 使用kv缓存
+            .process_kv(block_idx, index_pos, k, v) // 使用kv缓存
             .map_err(|e| anyhow!("cache.process_kv(block={block_idx}) -> {e}"))?;
         // log::info!("Shape of q and k  v after process_kv: {:?} {:?} {:?}", q.shape(), k.shape(), v.shape());
         
@@ -115,9 +124,10 @@ impl CausalSelfAttention {
             let att = if seq_len == 1 {
                 att
             } else {
+                // when continuing from cached positions the query only covers the last ones
                 let mask = cache
-                    .mask(seq_len)
-                    .map_err(|e| anyhow!("cache.mask({seq_len}) -> {e}"))?
+                    .mask(seq_len, index_pos)
+                    .map_err(|e| anyhow!("cache.mask({seq_len}, {index_pos}) -> {e}"))?
                     .broadcast_as(att.shape())
                     .map_err(|e| anyhow!("mask.broadcast_as({:?}) -> {e}", att.shape()))?;
 
@@ -148,7 +158,7 @@ impl CausalSelfAttention {
     }
 
     /// Load an instance of this object from the VarBuilder object with the given configuration.
-    pub fn load(vb: VarBuilder, cfg: &super::Config) -> Result<Self> {
+    pub fn load(vb: Weights, cfg: &super::Config) -> Result<Self> {
         let size_in = cfg.hidden_size;
         let size_q = (cfg.hidden_size / cfg.num_attention_heads) * cfg.num_attention_heads;
         let size_kv = (cfg.hidden_size / cfg.num_attention_heads) * cfg.num_key_value_heads;
@@ -164,6 +174,7 @@ impl CausalSelfAttention {
             num_attention_heads: cfg.num_attention_heads,
             num_key_value_heads: cfg.num_key_value_heads,
             head_dim: cfg.hidden_size / cfg.num_attention_heads,
+            interleaved_rope: vb.is_gguf(),
         })
     }
 }
@@ -75,28 +75,57 @@ impl Cache {
         self.sin.narrow(0, index_pos, seq_len)
     }
 
-    /// Get the attention mask for the given sequence length.
-    pub fn mask(&mut self, seq_len: usize) -> Result<Tensor> {
-        if let Some(mask) = self.masks.get(&seq_len) {
-            Ok(mask.clone())
-        } else {
-            let mask: Vec<_> = (0..seq_len)
-                .flat_map(|i| (0..seq_len).map(move |j| u8::from(j > i)))
-                .collect();
-            let mask = Tensor::from_slice(&mask, (seq_len, seq_len), &self.device)?;
+    /// Get the attention mask for the given sequence length, with index_pos cached positions before it.
+    /// Only the masks of sequences starting at 0 are cached, the others depend on the length of the
+    /// reused prefix and would grow the cache without bound.
+    pub fn mask(&mut self, seq_len: usize, index_pos: usize) -> Result<Tensor> {
+        if index_pos == 0 {
+            if let Some(mask) = self.masks.get(&seq_len) {
+                return Ok(mask.clone());
+            }
+        }
+
+        let kv_len = index_pos + seq_len;
+        let mask: Vec<_> = (0..seq_len)
+            .flat_map(|i| (0..kv_len).map(move |j| u8::from(j > i + index_pos)))
+            .collect();
+        let mask = Tensor::from_slice(&mask, (seq_len, kv_len), &self.device)?;
+        if index_pos == 0 {
             self.masks.insert(seq_len, mask.clone());
-            Ok(mask)
         }
+        Ok(mask)
     }
 
     /// Process the input k and v by either generating their cache entry or applying a previously cached one.
+    /// Cached entries past index_pos belong to a previous sequence and are discarded, so that
+    /// a common prefix can be reused by just starting the next sequence at its end.
     pub fn process_kv(
         &mut self,
         block_idx: usize,
+        index_pos: usize,
         mut k: Tensor,
         mut v: Tensor,
     ) -> Result<(Tensor, Tensor)> {
         if self.use_kv_cache {
+            // drop cached positions past index_pos, they belong to a previous sequence
+            let cached = match &self.kvs[block_idx] {
+                Some((cache_k, _)) => cache_k.dims()[2],
+                None => 0,
+            };
+            if cached < index_pos {
+                candle_core::bail!(
+                    "block {block_idx} has {cached} cached positions, can't continue at {index_pos}"
+                );
+            } else if index_pos == 0 {
+                self.kvs[block_idx] = None;
+            } else if cached > index_pos {
+                let (cache_k, cache_v) = self.kvs[block_idx].take().unwrap();
+                self.kvs[block_idx] = Some((
+                    cache_k.narrow(2, 0, index_pos)?,
+                    cache_v.narrow(2, 0, index_pos)?,
+                ));
+            }
+
             // if this block_idx in cache
             if let Some((cache_k, cache_v)) = &self.kvs[block_idx] {
                 // update cache entry
@@ -134,3 +163,139 @@ impl Cache {
         self.kvs = vec![None; self.kvs.len()];
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            hidden_size: 8,
+            intermediate_size: 16,
+            vocab_size: 16,
+            num_hidden_layers: 2,
+            num_attention_heads: 2,
+            num_key_value_heads: 2,
+            rms_norm_eps: 1e-5,
+            rope_theta: 500000.0,
+            bos_token_id: None,
+            eos_token_ids: vec![],
+        }
+    }
+
+    fn cache() -> Cache {
+        Cache::new(true, DType::F32, &config(), &Device::Cpu).unwrap()
+    }
+
+    /// Return a (batch, heads, seq_len, head_dim) tensor whose positions hold their index.
+    fn positions(from: usize, seq_len: usize) -> Tensor {
+        Tensor::arange(from as f32, (from + seq_len) as f32, &Device::Cpu)
+            .unwrap()
+            .reshape((1, 1, seq_len, 1))
+            .unwrap()
+            .broadcast_as((1, 2, seq_len, 4))
+            .unwrap()
+            .contiguous()
+            .unwrap()
+    }
+
+    /// Return the positions held by a tensor built by positions().
+    fn held(t: &Tensor) -> Vec<f32> {
+        t.get(0)
+            .unwrap()
+            .get(0)
+            .unwrap()
+            .get_on_dim(1, 0)
+            .unwrap()
+            .to_vec1()
+            .unwrap()
+    }
+
+    #[test]
+    fn causal_masks() {
+        let mut cache = cache();
+
+        let mask = cache.mask(3, 0).unwrap();
+        assert_eq!(
+            mask.to_vec2::<u8>().unwrap(),
+            [[0, 1, 1], [0, 0, 1], [0, 0, 0]]
+        );
+
+        // every cached position is visible
+        let mask = cache.mask(2, 3).unwrap();
+        assert_eq!(
+            mask.to_vec2::<u8>().unwrap(),
+            [[0, 0, 0, 0, 1], [0, 0, 0, 0, 0]]
+        );
+    }
+
+    #[test]
+    fn only_prefill_masks_are_cached() {
+        let mut cache = cache();
+        cache.mask(3, 0).unwrap();
+        cache.mask(3, 0).unwrap();
+        assert_eq!(cache.masks.len(), 1);
+
+        for index_pos in 1..10 {
+            cache.mask(2, index_pos).unwrap();
+        }
+        assert_eq!(cache.masks.len(), 1);
+    }
+
+    #[test]
+    fn kv_are_appended() {
+        let mut cache = cache();
+        let (k, v) = cache
+            .process_kv(0, 0, positions(0, 3), positions(0, 3))
+            .unwrap();
+        assert_eq!(held(&k), [0., 1., 2.]);
+        assert_eq!(held(&v), [0., 1., 2.]);
+
+        let (k, v) = cache
+            .process_kv(0, 3, positions(3, 1), positions(3, 1))
+            .unwrap();
+        assert_eq!(held(&k), [0., 1., 2., 3.]);
+        assert_eq!(held(&v), [0., 1., 2., 3.]);
+
+        // blocks are cached separately
+        let (k, _) = cache
+            .process_kv(1, 0, positions(0, 1), positions(0, 1))
+            .unwrap();
+        assert_eq!(held(&k), [0.]);
+    }
+
+    #[test]
+    fn kv_past_index_pos_are_discarded() {
+        let mut cache = cache();
+        cache
+            .process_kv(0, 0, positions(0, 4), positions(0, 4))
+            .unwrap();
+
+        // continue a shorter common prefix with a different suffix
+        let (k, v) = cache
+            .process_kv(0, 2, positions(10, 2), positions(10, 2))
+            .unwrap();
+        assert_eq!(held(&k), [0., 1., 10., 11.]);
+        assert_eq!(held(&v), [0., 1., 10., 11.]);
+
+        // a new sequence starts from scratch
+        let (k, _) = cache
+            .process_kv(0, 0, positions(20, 1), positions(20, 1))
+            .unwrap();
+        assert_eq!(held(&k), [20.]);
+    }
+
+    #[test]
+    fn kv_cant_skip_positions() {
+        let mut cache = cache();
+        cache
+            .process_kv(0, 0, positions(0, 2), positions(0, 2))
+            .unwrap();
+        assert!(cache
+            .process_kv(0, 3, positions(3, 1), positions(3, 1))
+            .is_err());
+        assert!(cache
+            .process_kv(1, 1, positions(1, 1), positions(1, 1))
+            .is_err());
+    }
+}
@@ -1,6 +1,9 @@
 use std::path::Path;
 
 use anyhow::Result;
+use candle_core::quantized::gguf_file::Value;
+
+use crate::utils::{self, Gguf};
 
 /// Max supported sequence length.
 pub const MAX_SEQ_LEN: usize = 4096;
@@ -9,6 +12,24 @@ fn default_rope() -> f32 {
     10_000.0
 }
 
+/// One or more end of stream token ids, Llama 3.1 configurations list several.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(untagged)]
+pub enum EosTokenId {
+    Single(u32),
+    Multiple(Vec<u32>),
+}
+
+impl EosTokenId {
+    /// Return the token ids as a vector.
+    pub fn to_vec(&self) -> Vec<u32> {
+        match self {
+            EosTokenId::Single(id) => vec![*id],
+            EosTokenId::Multiple(ids) => ids.clone(),
+        }
+    }
+}
+
 /// LLama specific configuration.
 #[derive(Debug, Clone, serde::Deserialize)]
 pub struct LlamaConfig {
@@ -22,7 +43,7 @@ pub struct LlamaConfig {
     #[serde(default = "default_rope")]
     pub rope_theta: f32,
     pub bos_token_id: Option<u32>,
-    pub eos_token_id: Option<u32>,
+    pub eos_token_id: Option<EosTokenId>,
 }
 
 impl LlamaConfig {
@@ -36,6 +57,54 @@ impl LlamaConfig {
             .map_err(|e| anyhow!("can't parse {}: {:?}", path.display(), e))
     }
 
+    /// Load the configuration from the metadata of a GGUF file.
+    pub fn from_gguf(gguf: &Gguf) -> Result<Self> {
+        let required = |key: &str| -> Result<usize> {
+            gguf.metadata_usize(key)?
+                .ok_or_else(|| anyhow!("{key} not found in {}", gguf.path().display()))
+        };
+
+        let vocab_size = match gguf.metadata_usize("llama.vocab_size")? {
+            Some(vocab_size) => vocab_size,
+            None => match gguf.metadata("tokenizer.ggml.tokens") {
+                Some(tokens) => tokens.to_vec()?.len(),
+                None => bail!("vocabulary size not found in {}", gguf.path().display()),
+            },
+        };
+
+        Ok(Self {
+            hidden_size: required("llama.embedding_length")?,
+            intermediate_size: required("llama.feed_forward_length")?,
+            vocab_size,
+            num_hidden_layers: required("llama.block_count")?,
+            num_attention_heads: required("llama.attention.head_count")?,
+            num_key_value_heads: gguf.metadata_usize("llama.attention.head_count_kv")?,
+            rms_norm_eps: gguf
+                .metadata_f64("llama.attention.layer_norm_rms_epsilon")?
+                .unwrap_or(1e-5),
+            rope_theta: gguf
+                .metadata_f64("llama.rope.freq_base")?
+                .map(|theta| theta as f32)
+                .unwrap_or_else(default_rope),
+            bos_token_id: gguf
+                .metadata_usize("tokenizer.ggml.bos_token_id")?
+                .map(|id| id as u32),
+            eos_token_id: gguf
+                .metadata_usize("tokenizer.ggml.eos_token_id")?
+                .map(|id| EosTokenId::Single(id as u32)),
+        })
+    }
+
+    /// Load the configuration of the model in data_path, either a directory with a config.json
+    /// file or a GGUF file.
+    pub fn from_model(data_path: &Path) -> Result<Self> {
+        if utils::is_gguf(data_path) {
+            Self::from_gguf(&Gguf::open(data_path)?)
+        } else {
+            Self::from_path(&data_path.join("config.json"))
+        }
+    }
+
     /// Return the number of kv heads.
     pub fn num_key_value_heads(&self) -> usize {
         self.num_key_value_heads.unwrap_or(self.num_attention_heads)
@@ -53,7 +122,10 @@ impl LlamaConfig {
             rms_norm_eps: self.rms_norm_eps,
             rope_theta: self.rope_theta,
             bos_token_id: self.bos_token_id,
-            eos_token_id: self.eos_token_id,
+            eos_token_ids: self
+                .eos_token_id
+                .map(|eos| eos.to_vec())
+                .unwrap_or_default(),
         }
     }
 }
@@ -70,5 +142,113 @@ pub struct Config {
     pub rms_norm_eps: f64,
     pub rope_theta: f32,
     pub bos_token_id: Option<u32>,
-    pub eos_token_id: Option<u32>,
+    pub eos_token_ids: Vec<u32>,
+}
+
+impl Config {
+    /// Return the GGUF metadata describing this configuration, read back by
+    /// LlamaConfig::from_gguf.
+    pub fn to_gguf_metadata(&self) -> Vec<(String, Value)> {
+        let mut metadata = vec![
+            ("general.architecture", Value::String("llama".to_string())),
+            ("llama.embedding_length", Value::U32(self.hidden_size as u32)),
+            (
+                "llama.feed_forward_length",
+                Value::U32(self.intermediate_size as u32),
+            ),
+            ("llama.vocab_size", Value::U32(self.vocab_size as u32)),
+            ("llama.block_count", Value::U32(self.num_hidden_layers as u32)),
+            (
+                "llama.attention.head_count",
+                Value::U32(self.num_attention_heads as u32),
+            ),
+            (
+                "llama.attention.head_count_kv",
+                Value::U32(self.num_key_value_heads as u32),
+            ),
+            (
+                "llama.attention.layer_norm_rms_epsilon",
+                Value::F32(self.rms_norm_eps as f32),
+            ),
+            (
+                "llama.rope.dimension_count",
+                Value::U32((self.hidden_size / self.num_attention_heads) as u32),
+            ),
+            ("llama.rope.freq_base", Value::F32(self.rope_theta)),
+        ];
+        if let Some(bos_token_id) = self.bos_token_id {
+            metadata.push(("tokenizer.ggml.bos_token_id", Value::U32(bos_token_id)));
+        }
+        // the other end of stream tokens are found in the vocabulary
+        if let Some(eos_token_id) = self.eos_token_ids.first() {
+            metadata.push(("tokenizer.ggml.eos_token_id", Value::U32(*eos_token_id)));
+        }
+
+        metadata
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect()
+    }
+}
+
+/// The parts of generation_config.json we care about.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub struct GenerationConfig {
+    pub eos_token_id: Option<EosTokenId>,
+}
+
+impl GenerationConfig {
+    /// Load the generation configuration from the given path.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        log::info!("loading generation configuration from {}", path.display());
+
+        let data =
+            std::fs::read(path).map_err(|e| anyhow!("can't read {}: {:?}", path.display(), e))?;
+        serde_json::from_slice(&data)
+            .map_err(|e| anyhow!("can't parse {}: {:?}", path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(eos_token_id: &str) -> Config {
+        let json = format!(
+            r#"{{"hidden_size": 64, "intermediate_size": 128, "vocab_size": 300,
+                "num_hidden_layers": 2, "num_attention_heads": 4, "rms_norm_eps": 1e-5
+                {eos_token_id}}}"#
+        );
+        serde_json::from_str::<LlamaConfig>(&json)
+            .unwrap()
+            .into_config()
+    }
+
+    #[test]
+    fn eos_token_id_scalar_or_list() {
+        assert_eq!(parse(r#", "eos_token_id": 128001"#).eos_token_ids, [128001]);
+        assert_eq!(
+            parse(r#", "eos_token_id": [128001, 128008, 128009]"#).eos_token_ids,
+            [128001, 128008, 128009]
+        );
+        assert!(parse(r#", "eos_token_id": []"#).eos_token_ids.is_empty());
+        assert!(parse(r#", "eos_token_id": null"#).eos_token_ids.is_empty());
+        assert!(parse("").eos_token_ids.is_empty());
+
+        let e = serde_json::from_str::<GenerationConfig>(r#"{"eos_token_id": "</s>"}"#);
+        assert!(e.is_err());
+    }
+
+    #[test]
+    fn missing_values_have_defaults() {
+        let config = parse("");
+        assert_eq!(config.num_key_value_heads, config.num_attention_heads);
+        assert_eq!(config.rope_theta, default_rope());
+        assert_eq!(config.bos_token_id, None);
+
+        // generation_config.json only matters for its end of stream tokens
+        let config: GenerationConfig =
+            serde_json::from_str(r#"{"temperature": 0.6, "top_p": 0.9}"#).unwrap();
+        assert!(config.eos_token_id.is_none());
+    }
 }
@@ -0,0 +1,145 @@
+//! Incremental detokenization.
+use anyhow::Result;
+use tokenizers::Tokenizer;
+
+/// Decodes a stream of token ids into text.
+///
+/// Byte-level BPE tokens can split a multi-byte character, and some decoders add or strip
+/// spaces depending on the surrounding tokens, so decoding tokens one by one breaks the text.
+/// This decodes every new token together with a window of the previous ones and only
+/// returns text once the bytes decoded so far form complete UTF-8 characters.
+#[derive(Debug, Default)]
+pub struct TokenDecoder {
+    ids: Vec<u32>,
+    // start of the window of already returned tokens used as decoding context
+    prefix_offset: usize,
+    // start of the tokens whose text hasn't been returned yet
+    read_offset: usize,
+}
+
+impl TokenDecoder {
+    /// Create a new decoder.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start a new stream, optionally using the last prompt token as decoding context.
+    pub fn reset(&mut self, context: Option<u32>) {
+        self.ids.clear();
+        self.ids.extend(context);
+        self.prefix_offset = 0;
+        self.read_offset = self.ids.len();
+    }
+
+    /// Add a token and return its text if complete, or an empty string if more tokens are needed.
+    pub fn next_token(&mut self, tokenizer: &Tokenizer, id: u32) -> Result<String> {
+        self.ids.push(id);
+
+        let prefix_text = self.decode(tokenizer, self.prefix_offset, self.read_offset)?;
+        let new_text = self.decode(tokenizer, self.prefix_offset, self.ids.len())?;
+
+        // an incomplete character is decoded as the replacement character
+        if new_text.len() <= prefix_text.len() || new_text.ends_with('\u{FFFD}') {
+            return Ok(String::new());
+        }
+
+        let text = text_after(&prefix_text, &new_text);
+        self.slide();
+
+        Ok(text)
+    }
+
+    /// Return the text of the tokens not returned yet, an incomplete character left at the end
+    /// of the stream is decoded as the replacement character.
+    pub fn flush(&mut self, tokenizer: &Tokenizer) -> Result<String> {
+        if self.read_offset == self.ids.len() {
+            return Ok(String::new());
+        }
+
+        let prefix_text = self.decode(tokenizer, self.prefix_offset, self.read_offset)?;
+        let new_text = self.decode(tokenizer, self.prefix_offset, self.ids.len())?;
+        let text = text_after(&prefix_text, &new_text);
+        self.slide();
+
+        Ok(text)
+    }
+
+    /// Slide the window and drop what's not needed as context anymore.
+    fn slide(&mut self) {
+        self.ids.drain(..self.read_offset);
+        self.prefix_offset = 0;
+        self.read_offset = self.ids.len();
+    }
+
+    fn decode(&self, tokenizer: &Tokenizer, from: usize, to: usize) -> Result<String> {
+        tokenizer
+            .decode(&self.ids[from..to], false)
+            .map_err(anyhow::Error::msg)
+    }
+}
+
+/// Return the text decoded past the context.
+fn text_after(prefix_text: &str, new_text: &str) -> String {
+    match new_text.strip_prefix(prefix_text) {
+        Some(text) => text.to_string(),
+        // the context decodes differently now, only keep what's past it
+        None => new_text
+            .char_indices()
+            .find(|(idx, _)| *idx >= prefix_text.len())
+            .map(|(idx, _)| new_text[idx..].to_string())
+            .unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::llama3::testing;
+
+    /// Decode the ids one at a time, return the text returned for every token and the flushed one.
+    fn decode(tokenizer: &Tokenizer, ids: &[u32]) -> (Vec<String>, String) {
+        let mut decoder = TokenDecoder::new();
+        let texts = ids
+            .iter()
+            .map(|id| decoder.next_token(tokenizer, *id).unwrap())
+            .collect();
+        (texts, decoder.flush(tokenizer).unwrap())
+    }
+
+    #[test]
+    fn characters_split_across_tokens() {
+        let tokenizer = testing::tokenizer();
+        let text = "the café costs 12€ 😀";
+        let ids = tokenizer.encode(text, false).unwrap().get_ids().to_vec();
+
+        let (texts, flushed) = decode(&tokenizer, &ids);
+        assert_eq!(texts.concat(), text);
+        assert!(texts.iter().all(|text| !text.contains('\u{FFFD}')));
+        assert_eq!(flushed, "");
+
+        // the bytes of a character are only returned once complete
+        let ids = tokenizer.encode("€", false).unwrap().get_ids().to_vec();
+        assert_eq!(ids.len(), 3);
+        let (texts, _) = decode(&tokenizer, &ids);
+        assert_eq!(texts, ["", "", "€"]);
+    }
+
+    #[test]
+    fn incomplete_character_is_flushed() {
+        let tokenizer = testing::tokenizer();
+        let mut ids = tokenizer.encode("ok ", false).unwrap().get_ids().to_vec();
+        let euro = tokenizer.encode("€", false).unwrap().get_ids().to_vec();
+        ids.extend(&euro[..2]);
+
+        let (texts, flushed) = decode(&tokenizer, &ids);
+        assert_eq!(texts.concat(), "ok ");
+        assert_eq!(flushed, "\u{FFFD}");
+
+        // nothing is left after flushing
+        let mut decoder = TokenDecoder::new();
+        decoder.next_token(&tokenizer, euro[0]).unwrap();
+        assert_eq!(decoder.flush(&tokenizer).unwrap(), "\u{FFFD}");
+        assert_eq!(decoder.flush(&tokenizer).unwrap(), "");
+        assert_eq!(decoder.next_token(&tokenizer, ids[0]).unwrap(), "o");
+    }
+}
@@ -1,50 +1,335 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
 use anyhow::Result;
 use async_trait::async_trait;
-use candle_core::{DType, IndexOp, Tensor};
-use candle_nn::{linear_no_bias as linear, Embedding, Linear, Module, RmsNorm};
+use candle_core::{quantized::gguf_file::Value, DType, IndexOp, Tensor, D};
+use candle_nn::{Embedding, Module, RmsNorm};
 use candle_transformers::generation::{LogitsProcessor, Sampling};
-use tokenizers::Tokenizer;
+use tokenizers::{
+    models::bpe::BPE,
+    pre_tokenizers::{
+        byte_level::ByteLevel,
+        sequence::Sequence,
+        split::{Split, SplitPattern},
+    },
+    AddedToken, SplitDelimiterBehavior, Tokenizer,
+};
 
 use crate::{
-    spm::{Context, Forwarder},
-    models::{chat::Message, Generator, Token},
+    spm::{Auth, Client, ClientOptions, Context, Forwarder, Health, Hello, Reconnected, ReplicaSet},
+    models::{chat::Message, params::GenerationParams, Generator, Token},
+    utils::{Gguf, ModelFingerprint},
 };
 
-use super::{transformer::Transformer, History};
+use super::{
+    transformer::Transformer, GenerationConfig, History, QLinear, TokenDecoder, MAX_SEQ_LEN,
+};
 
 /// Default end of stream token if not found in configuration.
 const DEFAULT_EOS_TOKEN: &str = "</s>";
 
-/// Load the tokenizer and return the first tokens from the prompt in context.
-fn load_tokenizer(ctx: &Context) -> Result<(Tokenizer, Option<u32>)> {
-    let tokenizer_filename = ctx.data_path.join("tokenizer.json");
+/// Special tokens ending a turn or the whole text.
+const END_OF_STREAM_TOKENS: &[&str] = &["<|end_of_text|>", "<|eot_id|>", "<|eom_id|>"];
+
+/// Pre-tokenization pattern of the Llama 3 tokenizer.
+const LLAMA3_SPLIT_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+/// GGUF token type of regular tokens.
+const GGUF_TOKEN_TYPE_NORMAL: i32 = 1;
+/// GGUF token type of control tokens.
+const GGUF_TOKEN_TYPE_CONTROL: i32 = 3;
+/// GGUF token type of added tokens that are not special.
+const GGUF_TOKEN_TYPE_USER_DEFINED: i32 = 4;
+/// GGUF token type of the ids missing from the vocabulary.
+const GGUF_TOKEN_TYPE_UNUSED: i32 = 5;
+
+/// Build the byte level BPE tokenizer of Llama 3 from the vocabulary embedded in a GGUF file.
+fn tokenizer_from_gguf(gguf: &Gguf) -> Result<Tokenizer> {
+    let model = gguf
+        .metadata("tokenizer.ggml.model")
+        .map(|model| model.to_string())
+        .transpose()?;
+    if model.map(|m| m.as_str()) != Some("gpt2") {
+        bail!("unsupported tokenizer model {model:?}, expected gpt2");
+    }
+    // the pre-tokenization splits the text before the merges, only the Llama 3 one is supported
+    match gguf.metadata("tokenizer.ggml.pre") {
+        Some(pre) => {
+            let pre = pre.to_string()?;
+            if pre != "llama-bpe" {
+                bail!("unsupported tokenizer pre-tokenization {pre}, expected llama-bpe");
+            }
+        }
+        None => log::warn!("no tokenizer pre-tokenization found, using llama-bpe"),
+    }
+
+    let tokens = match gguf.metadata("tokenizer.ggml.tokens") {
+        Some(tokens) => tokens.to_vec()?,
+        None => bail!("no vocabulary in {}", gguf.path().display()),
+    };
+    let token_types = match gguf.metadata("tokenizer.ggml.token_type") {
+        Some(types) => types
+            .to_vec()?
+            .iter()
+            .map(|t| t.to_i32())
+            .collect::<Result<Vec<_>, _>>()?,
+        None => vec![],
+    };
+
+    let mut vocab = HashMap::new();
+    let mut special = vec![];
+    for (id, token) in tokens.iter().enumerate() {
+        let token = token.to_string()?;
+        vocab.insert(token.to_string(), id as u32);
+        if token_types.get(id) == Some(&GGUF_TOKEN_TYPE_CONTROL) {
+            special.push(AddedToken::from(token.to_string(), true));
+        }
+    }
+
+    let mut merges = vec![];
+    if let Some(values) = gguf.metadata("tokenizer.ggml.merges") {
+        for merge in values.to_vec()? {
+            let merge = merge.to_string()?;
+            match merge.split_once(' ') {
+                Some((a, b)) => merges.push((a.to_string(), b.to_string())),
+                None => bail!("invalid merge '{merge}'"),
+            }
+        }
+    }
+
+    let bpe = BPE::builder()
+        .vocab_and_merges(vocab, merges)
+        .ignore_merges(true)
+        .build()
+        .map_err(anyhow::Error::msg)?;
+
+    let split = Split::new(
+        SplitPattern::Regex(LLAMA3_SPLIT_PATTERN.to_string()),
+        SplitDelimiterBehavior::Isolated,
+        false,
+    )
+    .map_err(anyhow::Error::msg)?;
+
+    let mut tokenizer = Tokenizer::new(bpe);
+    tokenizer
+        .with_pre_tokenizer(Sequence::new(vec![
+            split.into(),
+            ByteLevel::new(false, true, false).into(),
+        ]))
+        .with_decoder(ByteLevel::default());
+    tokenizer.add_special_tokens(&special);
+
+    Ok(tokenizer)
+}
+
+/// Return the GGUF metadata embedding the byte level BPE tokenizer of tokenizer.json, read back
+/// by tokenizer_from_gguf.
+pub fn tokenizer_to_gguf_metadata(tokenizer_filename: &Path) -> Result<Vec<(String, Value)>> {
+    let data = std::fs::read(tokenizer_filename)
+        .map_err(|e| anyhow!("can't read {}: {:?}", tokenizer_filename.display(), e))?;
+    let json: serde_json::Value = serde_json::from_slice(&data)
+        .map_err(|e| anyhow!("can't parse {}: {:?}", tokenizer_filename.display(), e))?;
+
+    let model = &json["model"];
+    if model["type"].as_str() != Some("BPE") {
+        bail!("unsupported tokenizer model {}, expected BPE", &model["type"]);
+    }
+    let vocab = match model["vocab"].as_object() {
+        Some(vocab) => vocab,
+        None => bail!("no vocabulary in {}", tokenizer_filename.display()),
+    };
+
+    // id -> (token, type)
+    let mut tokens: HashMap<u64, (String, i32)> = HashMap::new();
+    for (token, id) in vocab {
+        if let Some(id) = id.as_u64() {
+            tokens.insert(id, (token.to_string(), GGUF_TOKEN_TYPE_NORMAL));
+        }
+    }
+    for added in json["added_tokens"].as_array().into_iter().flatten() {
+        if let (Some(id), Some(content)) = (added["id"].as_u64(), added["content"].as_str()) {
+            let token_type = if added["special"].as_bool() == Some(true) {
+                GGUF_TOKEN_TYPE_CONTROL
+            } else {
+                GGUF_TOKEN_TYPE_USER_DEFINED
+            };
+            tokens.insert(id, (content.to_string(), token_type));
+        }
+    }
+
+    // GGUF vocabularies are indexed by id, fill the gaps
+    let size = tokens.keys().max().map(|id| id + 1).unwrap_or_default();
+    let mut token_values = vec![];
+    let mut type_values = vec![];
+    for id in 0..size {
+        let (token, token_type) = tokens
+            .remove(&id)
+            .unwrap_or_else(|| (format!("[PAD{id}]"), GGUF_TOKEN_TYPE_UNUSED));
+        token_values.push(Value::String(token));
+        type_values.push(Value::I32(token_type));
+    }
+
+    // merges are either "a b" strings or ["a", "b"] pairs depending on the tokenizers version
+    let mut merges = vec![];
+    for merge in model["merges"].as_array().into_iter().flatten() {
+        let merge = match merge {
+            serde_json::Value::String(merge) => merge.to_string(),
+            serde_json::Value::Array(pair) if pair.len() == 2 => format!(
+                "{} {}",
+                pair[0].as_str().unwrap_or_default(),
+                pair[1].as_str().unwrap_or_default()
+            ),
+            merge => bail!("invalid merge {merge}"),
+        };
+        merges.push(Value::String(merge));
+    }
+
+    Ok(vec![
+        (
+            "tokenizer.ggml.model".to_string(),
+            Value::String("gpt2".to_string()),
+        ),
+        (
+            "tokenizer.ggml.pre".to_string(),
+            Value::String("llama-bpe".to_string()),
+        ),
+        ("tokenizer.ggml.tokens".to_string(), Value::Array(token_values)),
+        ("tokenizer.ggml.token_type".to_string(), Value::Array(type_values)),
+        ("tokenizer.ggml.merges".to_string(), Value::Array(merges)),
+    ])
+}
 
-    log::info!("loading tokenizer from {}", tokenizer_filename.display());
+/// Load the tokenizer and return it with the set of end of stream token ids.
+fn load_tokenizer(ctx: &Context) -> Result<(Tokenizer, HashSet<u32>)> {
+    let tokenizer = if let Some(gguf) = &ctx.gguf {
+        log::info!("loading tokenizer from {}", gguf.path().display());
+
+        tokenizer_from_gguf(gguf)?
+    } else {
+        let tokenizer_filename = ctx.data_path.join("tokenizer.json");
+
+        log::info!("loading tokenizer from {}", tokenizer_filename.display());
+
+        Tokenizer::from_file(tokenizer_filename).map_err(anyhow::Error::msg)?
+    };
+
+    let mut eos_token_ids: HashSet<u32> = ctx.config.eos_token_ids.iter().copied().collect();
+
+    let generation_config_filename = ctx.data_path.join("generation_config.json");
+    if generation_config_filename.is_file() {
+        if let Some(eos) = GenerationConfig::from_path(&generation_config_filename)?.eos_token_id {
+            eos_token_ids.extend(eos.to_vec());
+        }
+    }
+
+    for (id, token) in tokenizer.get_added_tokens_decoder() {
+        if token.special && END_OF_STREAM_TOKENS.contains(&token.content.as_str()) {
+            eos_token_ids.insert(id);
+        }
+    }
 
-    let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(anyhow::Error::msg)?;
+    if eos_token_ids.is_empty() {
+        if let Some(id) = tokenizer.token_to_id(DEFAULT_EOS_TOKEN) {
+            eos_token_ids.insert(id);
+        }
+    }
 
-    let eos_token_id = ctx
-        .config
-        .eos_token_id
-        .or_else(|| tokenizer.token_to_id(DEFAULT_EOS_TOKEN));
+    if eos_token_ids.is_empty() {
+        log::warn!("no end of stream tokens found, generation will only stop at max tokens");
+    } else {
+        let mut ids: Vec<_> = eos_token_ids.iter().collect();
+        ids.sort();
+        log::info!("end of stream tokens: {:?}", ids);
+    }
 
-    Ok((tokenizer, eos_token_id))
+    Ok((tokenizer, eos_token_ids))
 }
 
-/// Create the logit sampling logic from the context.
-fn create_logits_processor(ctx: &Context) -> LogitsProcessor {
-    let temperature = ctx.args.temperature;
+/// Create the logit sampling logic from the generation parameters.
+fn create_logits_processor(params: &GenerationParams) -> LogitsProcessor {
+    let temperature = params.temperature;
     let sampling = if temperature <= 0. {
         Sampling::ArgMax
     } else {
-        match (ctx.args.top_k, ctx.args.top_p) {
+        match (params.top_k, params.top_p) {
             (None, None) => Sampling::All { temperature },
             (Some(k), None) => Sampling::TopK { k, temperature },
             (None, Some(p)) => Sampling::TopP { p, temperature },
             (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
         }
     };
-    LogitsProcessor::from_sampling(ctx.args.seed, sampling)
+    LogitsProcessor::from_sampling(params.seed, sampling)
+}
+
+/// Make sure the worker serves the client layer with the same weights and dtype loaded by the
+/// master, expected fingerprints are cached by set of layers.
+fn verify_worker(
+    dtype: DType,
+    client: &Client,
+    fingerprint: &ModelFingerprint,
+    expected: &mut HashMap<Vec<String>, String>,
+) -> Result<()> {
+    let info = client.info();
+    if !info.layers.iter().any(|l| l == client.layer_name()) {
+        bail!(
+            "worker {} doesn't serve {}",
+            client.ident(),
+            client.layer_name()
+        );
+    }
+
+    let dtype = dtype.as_str();
+    if info.dtype != dtype {
+        bail!(
+            "worker {} uses dtype {} for {}, expected {}",
+            client.ident(),
+            &info.dtype,
+            client.layer_name(),
+            dtype
+        );
+    }
+
+    if !expected.contains_key(&info.layers) {
+        expected.insert(info.layers.clone(), fingerprint.layers(&info.layers)?);
+    }
+    if expected[&info.layers] != info.fingerprint {
+        bail!(
+            "worker {} serves a different {}, fingerprint {} doesn't match {}",
+            client.ident(),
+            client.layer_name(),
+            &info.fingerprint,
+            &expected[&info.layers]
+        );
+    }
+
+    Ok(())
+}
+
+/// Group contiguous blocks running on the same node, returning the layer range and where it
+/// runs, e.g. ("model.layers.0-15", "local").
+fn layers_summary(blocks: &[Box<dyn Forwarder>]) -> Vec<(String, String)> {
+    // (first layer, last layer, node)
+    let mut groups: Vec<(&str, &str, &str)> = vec![];
+    for block in blocks {
+        match groups.last_mut() {
+            Some((_, last, ident)) if *ident == block.ident() => *last = block.layer_name(),
+            _ => groups.push((block.layer_name(), block.layer_name(), block.ident())),
+        }
+    }
+
+    groups
+        .into_iter()
+        .map(|(first, last, ident)| {
+            let range = match last.rsplit_once('.') {
+                Some((_, stop)) if first != last => format!("{first}-{stop}"),
+                _ => first.to_string(),
+            };
+            (range, ident.to_string())
+        })
+        .collect()
 }
 
 /// LLama main class.
@@ -52,16 +337,18 @@ pub struct LLama {
     ctx: Context,
 
     tokenizer: Tokenizer,
+    decoder: TokenDecoder,
     embedding: Embedding,
-    eos_token_id: Option<u32>,
+    eos_token_ids: HashSet<u32>,
     index_pos: usize,
     generated: usize,
 
     blocks: Vec<Box<dyn Forwarder>>,
 
     ln_f: RmsNorm,
-    lm_head: Linear,
+    lm_head: QLinear,
 
+    params: GenerationParams,
     logits_processor: LogitsProcessor,
 
     history: History,
@@ -82,7 +369,7 @@ impl LLama {
 
         // log::info!("X = {}", &x);
 
-        while block_idx < (num_blocks - 1) {
+        while block_idx < num_blocks {
             let curr_block_id = self.blocks[block_idx].ident().to_owned();
             if curr_block_id == "local" {
                 // log::info!("x={:?} idx={idx} block={block_idx}", x.shape());
@@ -113,7 +400,12 @@ impl LLama {
                     .forward_batch(&x, batch, &mut self.ctx.cache)
                     .await
                     .map_err(|e| {
-                        anyhow!("error in forward batch operation for block {block_idx}: {e}")
+                        // the caller processes the whole conversation again
+                        if e.is::<Reconnected>() {
+                            e
+                        } else {
+                            anyhow!("error in forward batch operation for block {block_idx}: {e}")
+                        }
                     })?;
             }
 
@@ -156,11 +448,6 @@ impl LLama {
     }
 
     fn start_dialog_prompt(&mut self) -> Result<()> {
-        // make sure we start clean
-        self.tokens.clear();
-        self.ctx.cache.clear();
-        self.index_pos = 0;
-
         log::debug!("generating history tokens ...");
 
         // generate raw from history
@@ -169,16 +456,43 @@ impl LLama {
         log::debug!("dialog={}", &dialog);
 
         // tokenize raw
-        self.tokens = self
+        let tokens = self
             .tokenizer
             .encode(dialog, false) // do not add special tokens as we already added them
             .map_err(anyhow::Error::msg)?
             .get_ids()
             .to_vec();
 
+        // the kv-cache holds the first index_pos tokens of the previous run, keep the ones
+        // shared with this prompt and only prefill the rest, always leaving at least one token
+        let reused = self.tokens[..self.index_pos]
+            .iter()
+            .zip(tokens.iter())
+            .take_while(|(a, b)| a == b)
+            .count()
+            .min(tokens.len().saturating_sub(1));
+
+        self.tokens = tokens;
+        self.index_pos = reused;
+
         log::debug!("encoded={:?}", &self.tokens);
 
-        log::debug!("history tokens: {}", self.tokens.len());
+        log::debug!(
+            "history tokens: {} ({} cached, {} to prefill)",
+            self.tokens.len(),
+            reused,
+            self.tokens.len() - reused
+        );
+
+        if self.tokens.len() >= MAX_SEQ_LEN {
+            bail!(
+                "prompt is {} tokens long, max sequence length is {}",
+                self.tokens.len(),
+                MAX_SEQ_LEN
+            );
+        }
+
+        self.decoder.reset(self.tokens.last().copied());
 
         Ok(())
     }
@@ -193,21 +507,21 @@ impl Generator for LLama {
     /// Load this model from the context.
     async fn load(ctx: Context) -> Result<Box<Self>> {
         log::info!("loading embeddings ...");
-        let embedding: Embedding = candle_nn::embedding(
+        let embedding: Embedding = super::embedding(
             ctx.config.vocab_size,
             ctx.config.hidden_size,
             ctx.var_builder.pp("model.embed_tokens"),
         )?;
 
         log::info!("loading lm_head ...");
-        let lm_head = linear(
+        let lm_head = super::linear(
             ctx.config.hidden_size,
             ctx.config.vocab_size,
             ctx.var_builder.pp("lm_head"),
         )?;
 
         log::info!("loading model.norm ...");
-        let ln_f = candle_nn::rms_norm(
+        let ln_f = super::rms_norm(
             ctx.config.hidden_size,
             ctx.config.rms_norm_eps,
             ctx.var_builder.pp("model.norm"),
@@ -216,38 +530,82 @@ impl Generator for LLama {
         log::info!("loading {} blocks ...", ctx.config.num_hidden_layers);
 
         let mut blocks: Vec<Box<dyn Forwarder>> = vec![];
+        let hello = Hello::new(
+            Self::MODEL_NAME,
+            &ctx.config_hash,
+            ctx.dtype,
+            ctx.args.compression,
+        );
+        let fingerprint = ctx.fingerprint()?;
+        let mut expected = HashMap::new();
+        let auth = Auth::from_args(&ctx.args)?;
+        let options = ClientOptions::from_args(&ctx.args);
+
+        // workers that failed, shared by the replica sets so a dead node is skipped everywhere
+        let health: Health = Default::default();
 
         for i in 0..ctx.config.num_hidden_layers {
             let block_layer_name = format!("model.layers.{i}");
-            if let Some((node_name, node)) = ctx.topology.get_node_for_layer(&block_layer_name) 
-            {
-                log::debug!("node {node_name} will serve {}", &block_layer_name);
-                blocks.push(Box::new(
-                    crate::spm::Client::new(ctx.device.clone(), &node.host, &block_layer_name)
-                        .await?,
-                ));
+            let nodes = ctx.topology.get_nodes_for_layer(&block_layer_name);
+            if !nodes.is_empty() {
+                let mut clients = vec![];
+                for (node_name, node) in &nodes {
+                    log::debug!("node {node_name} will serve {}", &block_layer_name);
+                    let client = Client::new(
+                        ctx.device.clone(),
+                        &node.host,
+                        &block_layer_name,
+                        &hello,
+                        &auth.for_node(node)?,
+                        &options,
+                    )
+                    .await;
+                    // replicas are optional as long as one of the nodes is up
+                    let client = match client {
+                        Ok(client) => client,
+                        Err(e) if nodes.len() > 1 => {
+                            log::warn!("replica {node_name} of {} is unavailable: {e}", &block_layer_name);
+                            continue;
+                        }
+                        Err(e) => return Err(e),
+                    };
+                    verify_worker(ctx.dtype, &client, &fingerprint, &mut expected)?;
+                    clients.push(client);
+                }
+
+                if clients.is_empty() {
+                    bail!("none of the nodes serving {} are reachable", &block_layer_name);
+                } else if clients.len() == 1 {
+                    blocks.push(Box::new(clients.remove(0)));
+                } else {
+                    blocks.push(Box::new(ReplicaSet::new(clients, health.clone())?));
+                }
+            } else {
+                log::debug!("{} will be served locally", &block_layer_name);
+                blocks.push(Transformer::load(
+                    block_layer_name.clone(),
+                    ctx.var_builder.pp(&block_layer_name),
+                    &ctx.config,
+                )?);
             }
-            // else 
-            // {
-            //     log::debug!("{} will be served locally", &block_layer_name);
-            //     blocks.push(Transformer::load(
-            //         block_layer_name.clone(),
-            //         ctx.var_builder.pp(&block_layer_name),
-            //         &ctx.config,
-            //     )?);
-            // }
         }
 
         for block in &blocks {
             log::info!("  {}", block)
         }
+
+        log::info!("layers:");
+        for (range, ident) in layers_summary(&blocks) {
+            log::info!("  {range} -> {ident}");
+        }
         //    model.layers.31@192.168.1.87:10120 [cuda<2> linux-x86_64 latency=0ms]
 
-        let (tokenizer, eos_token_id) = load_tokenizer(&ctx)?;
+        let (tokenizer, eos_token_ids) = load_tokenizer(&ctx)?;
         let tokens = vec![];
         let history = History::new();
 
-        let logits_processor = create_logits_processor(&ctx);
+        let params = GenerationParams::from(&ctx.args);
+        let logits_processor = create_logits_processor(&params);
         let index_pos = 0;
 
         log::info!(
@@ -259,16 +617,18 @@ impl Generator for LLama {
 
         Ok(Box::new(Self {
             tokenizer,
+            decoder: TokenDecoder::new(),
             tokens,
             generated,
             history,
-            eos_token_id,
+            eos_token_ids,
             index_pos,
             ctx,
             embedding,
             blocks,
             ln_f,
             lm_head,
+            params,
             logits_processor,
         }))
     }
@@ -280,12 +640,35 @@ impl Generator for LLama {
     }
 
     /// Reset the chat pipeline state.
+    /// The tokens and kv-cache of the last run are kept, so that the prefix they share with
+    /// the next prompt doesn't need to be processed again.
     fn reset(&mut self) -> Result<()> {
-        self.tokens.clear();
         self.history.clear();
+        self.generated = 0;
+        Ok(())
+    }
+
+    /// Drop the tokens and kv-cache of the last run, locally and on the workers.
+    async fn clear_cache(&mut self) -> Result<()> {
+        self.tokens.clear();
         self.ctx.cache.clear();
         self.index_pos = 0;
-        self.generated = 0;
+
+        // every block holds its own connection with its own cache on the worker
+        for block in self.blocks.iter_mut() {
+            block.reset_cache().await.map_err(|e| {
+                anyhow!("error resetting cache of {}: {e}", block.layer_name())
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Set the sampling parameters of the next generation.
+    fn set_params(&mut self, params: GenerationParams) -> Result<()> {
+        params.validate()?;
+        self.logits_processor = create_logits_processor(&params);
+        self.params = params;
         Ok(())
     }
 
@@ -299,42 +682,50 @@ impl Generator for LLama {
         }
 
         let num_tokens = self.tokens.len();
-        let (context_size, context_index) = if self.ctx.cache.with_kv_cache() && index > 0 {
-            (1, self.index_pos)
+        // only process the tokens that are not in the kv-cache yet
+        let mut context_index = if self.ctx.cache.with_kv_cache() {
+            self.index_pos
         } else {
-            (num_tokens, 0)
+            0
         };
 
-        let context_offset = num_tokens.saturating_sub(context_size);
-        let context_tokens = &self.tokens[context_offset..];
-        let num_context_tokens = context_tokens.len();
+        let mut attempts = 0;
+        let logits = loop {
+            let context_tokens = &self.tokens[context_index..];
 
-        let input = Tensor::new(context_tokens, &self.ctx.device)?
-            .unsqueeze(0)
-            .map_err(|e| anyhow!("error squeezing context tokens: {e}"))?;
+            let input = Tensor::new(context_tokens, &self.ctx.device)?
+                .unsqueeze(0)
+                .map_err(|e| anyhow!("error squeezing context tokens: {e}"))?;
 
-        // log::info!("input={:?} context_index={context_index}", input.shape());
+            // log::info!("input={:?} context_index={context_index}", input.shape());
 
-        let logits = self
-            .forward(&input, context_index)
-            .await
-            .map_err(|e| anyhow!("error in model.forward: {e}"))?;
+            match self.forward(&input, context_index).await {
+                Ok(logits) => break logits,
+                // a worker lost its kv-cache, rebuild it from the token history
+                Err(e) if e.is::<Reconnected>() && attempts < self.blocks.len() => {
+                    log::warn!("{e}, processing the {num_tokens} tokens again ...");
+                    attempts += 1;
+                    context_index = 0;
+                }
+                Err(e) => return Err(anyhow!("error in model.forward: {e}")),
+            }
+        };
 
         let logits = logits
             .squeeze(0)
             .map_err(|e| anyhow!("error squeezing logits: {e}"))?;
 
-        let logits = if self.ctx.args.repeat_penalty == 1. {
+        let logits = if self.params.repeat_penalty == 1. {
             logits
         } else {
-            let start_at = num_tokens.saturating_sub(self.ctx.args.repeat_last_n);
+            let start_at = num_tokens.saturating_sub(self.params.repeat_last_n);
             candle_transformers::utils::apply_repeat_penalty(
                 &logits,
-                self.ctx.args.repeat_penalty,
+                self.params.repeat_penalty,
                 &self.tokens[start_at..],
             )?
         };
-        self.index_pos += num_context_tokens;
+        self.index_pos = num_tokens;
 
         let next_token = self
             .logits_processor
@@ -345,19 +736,449 @@ impl Generator for LLama {
 
         Ok(Token {
             id: next_token,
-            text: match self.tokenizer.decode(&[next_token], false) {
+            text: match self.decoder.next_token(&self.tokenizer, next_token) {
                 Ok(s) => Some(s),
                 Err(e) => {
                     log::error!("could not decode token {next_token}: {e}");
                     None
                 }
             },
-            is_end_of_stream: Some(next_token) == self.eos_token_id,
+            is_end_of_stream: self.eos_token_ids.contains(&next_token),
         })
     }
 
+    /// Return the text of the generated tokens that wasn't returned yet, at the end of generation.
+    fn flush_text(&mut self) -> Result<String> {
+        self.decoder.flush(&self.tokenizer)
+    }
+
     /// Return the number of generated tokens so far.
     fn generated_tokens(&self) -> usize {
         self.generated
     }
+
+    /// Return the number of tokens the current prompt was encoded to.
+    fn prompt_tokens(&self) -> usize {
+        self.tokens.len() - self.generated
+    }
+
+    /// Return the max number of tokens, prompt included, the model can attend to.
+    fn max_sequence_length(&self) -> usize {
+        MAX_SEQ_LEN
+    }
+
+    /// Return the perplexity of the model on text. Tokens are processed one at a time, like
+    /// while generating, so that the workers and the kv-cache are exercised the same way.
+    async fn perplexity(&mut self, text: &str) -> Result<f64> {
+        self.clear_cache().await?;
+
+        let mut tokens = self
+            .tokenizer
+            .encode(text, false)
+            .map_err(anyhow::Error::msg)?
+            .get_ids()
+            .to_vec();
+        if let Some(bos_token_id) = self.ctx.config.bos_token_id {
+            tokens.insert(0, bos_token_id);
+        }
+        if tokens.len() > MAX_SEQ_LEN {
+            log::warn!(
+                "text is {} tokens long, only evaluating the first {MAX_SEQ_LEN}",
+                tokens.len()
+            );
+            tokens.truncate(MAX_SEQ_LEN);
+        }
+        if tokens.len() < 2 {
+            bail!("text is too short to compute its perplexity");
+        }
+
+        log::info!("evaluating {} tokens ...", tokens.len());
+
+        let mut nll = 0f64;
+        for index in 0..tokens.len() - 1 {
+            let mut context_index = index;
+            let mut attempts = 0;
+            let logits = loop {
+                let input =
+                    Tensor::new(&tokens[context_index..=index], &self.ctx.device)?.unsqueeze(0)?;
+                match self.forward(&input, context_index).await {
+                    Ok(logits) => break logits,
+                    // a worker lost its kv-cache, rebuild it from the previous tokens
+                    Err(e) if e.is::<Reconnected>() && attempts < self.blocks.len() => {
+                        log::warn!("{e}, processing the {} tokens again ...", index + 1);
+                        attempts += 1;
+                        context_index = 0;
+                    }
+                    Err(e) => return Err(anyhow!("error in model.forward: {e}")),
+                }
+            };
+
+            let log_probs = candle_nn::ops::log_softmax(&logits.squeeze(0)?, D::Minus1)?;
+            nll -= log_probs
+                .i(tokens[index + 1] as usize)?
+                .to_scalar::<f32>()? as f64;
+        }
+
+        self.clear_cache().await?;
+
+        Ok((nll / (tokens.len() - 1) as f64).exp())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::{
+        models::llama3::{
+            testing::{self, Checkpoint},
+            Cache, Config,
+        },
+        spm::{quantize_model, stand_in, Compression, Worker, PROBE_LAYER},
+        utils::{Quantization, Weights},
+        Args,
+    };
+
+    #[test]
+    fn tokenizer_round_trip() {
+        let checkpoint = Checkpoint::new("tokenizer-round-trip");
+        let output = checkpoint.dir.join("model.gguf");
+        quantize_model(
+            &checkpoint.dir,
+            &checkpoint.config,
+            Quantization::Q8_0,
+            None,
+            &output,
+        )
+        .unwrap();
+
+        let expected = Tokenizer::from_file(checkpoint.dir.join("tokenizer.json")).unwrap();
+        let tokenizer = tokenizer_from_gguf(&Gguf::open(&output).unwrap()).unwrap();
+        assert_eq!(
+            tokenizer.get_vocab_size(true),
+            expected.get_vocab_size(true)
+        );
+
+        for text in [
+            "hello, the world! 12345",
+            "they'll tell   the\n\nhell\ttabs ",
+            "héllo wörld 你好 🦀",
+            "<|begin_of_text|>the end<|eot_id|>",
+        ] {
+            let ids = tokenizer.encode(text, false).unwrap().get_ids().to_vec();
+            assert_eq!(
+                ids,
+                expected.encode(text, false).unwrap().get_ids(),
+                "{text:?}"
+            );
+            assert_eq!(
+                tokenizer.decode(&ids, false).unwrap(),
+                expected.decode(&ids, false).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn unsupported_pre_tokenization() {
+        let checkpoint = Checkpoint::new("tokenizer-pre");
+        let mut metadata =
+            tokenizer_to_gguf_metadata(&checkpoint.dir.join("tokenizer.json")).unwrap();
+        for (key, value) in metadata.iter_mut() {
+            if key == "tokenizer.ggml.pre" {
+                *value = Value::String("qwen2".to_string());
+            }
+        }
+        let metadata: Vec<(&str, &Value)> = metadata.iter().map(|(k, v)| (k.as_str(), v)).collect();
+
+        let output = checkpoint.dir.join("tokenizer.gguf");
+        let mut file = std::fs::File::create(&output).unwrap();
+        candle_core::quantized::gguf_file::write(&mut file, &metadata, &[]).unwrap();
+
+        let err = tokenizer_from_gguf(&Gguf::open(&output).unwrap()).unwrap_err();
+        assert!(err.to_string().contains("qwen2"), "{err}");
+    }
+
+    /// Return the end of stream tokens found when loading the checkpoint, sorted.
+    fn end_of_stream_tokens(checkpoint: &Checkpoint) -> Vec<u32> {
+        let args = node_args(checkpoint, "127.0.0.1:10128", "master", &["model.layers.1"]);
+        let (_, eos_token_ids) = load_tokenizer(&Context::from_args(args).unwrap()).unwrap();
+        let mut ids: Vec<u32> = eos_token_ids.into_iter().collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn end_of_stream_tokens_are_merged() {
+        let checkpoint = Checkpoint::new("end-of-stream");
+        let mut tokenizer = testing::tokenizer();
+        tokenizer.add_special_tokens(&[AddedToken::from("<|eom_id|>", true)]);
+        // only special tokens end the stream
+        tokenizer.add_tokens(&[AddedToken::from("</s>", false)]);
+        tokenizer
+            .save(checkpoint.dir.join("tokenizer.json"), false)
+            .unwrap();
+        let id = |token: &str| tokenizer.token_to_id(token).unwrap();
+        let mut expected = vec![id("<|end_of_text|>"), id("<|eot_id|>"), id("<|eom_id|>")];
+        assert_eq!(checkpoint.config.eos_token_ids, [expected[0]]);
+
+        expected.sort();
+        assert_eq!(end_of_stream_tokens(&checkpoint), expected);
+
+        // generation_config.json adds to the ones of config.json
+        let generation_config = checkpoint.dir.join("generation_config.json");
+        std::fs::write(&generation_config, r#"{"eos_token_id": [1, 2]}"#).unwrap();
+        let mut merged = [expected.clone(), vec![1, 2]].concat();
+        merged.sort();
+        assert_eq!(end_of_stream_tokens(&checkpoint), merged);
+
+        std::fs::write(&generation_config, r#"{"eos_token_id": 3}"#).unwrap();
+        let mut merged = [expected.clone(), vec![3]].concat();
+        merged.sort();
+        assert_eq!(end_of_stream_tokens(&checkpoint), merged);
+
+        std::fs::write(&generation_config, r#"{"do_sample": true}"#).unwrap();
+        assert_eq!(end_of_stream_tokens(&checkpoint), expected);
+    }
+
+    #[tokio::test]
+    async fn workers_with_other_weights_are_refused() {
+        let checkpoint = Checkpoint::new("verify-worker");
+        let fingerprint = ModelFingerprint::new(&checkpoint.dir).unwrap();
+        let layers = [PROBE_LAYER.to_string()];
+        let expected = fingerprint.layers(&layers).unwrap();
+
+        let connect = |address: String, dtype: DType| async move {
+            let hello = Hello::new(
+                stand_in::MODEL_NAME,
+                stand_in::MODEL_NAME,
+                dtype,
+                Compression::None,
+            );
+            Client::new(
+                candle_core::Device::Cpu,
+                &address,
+                PROBE_LAYER,
+                &hello,
+                &Auth::default(),
+                &stand_in::client_options(),
+            )
+            .await
+            .unwrap()
+        };
+
+        let address = stand_in::spawn_serving(&expected, DType::F16)
+            .await
+            .unwrap();
+        let client = connect(address, DType::F16).await;
+        verify_worker(DType::F16, &client, &fingerprint, &mut HashMap::new()).unwrap();
+
+        let err = verify_worker(DType::F32, &client, &fingerprint, &mut HashMap::new())
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("uses dtype f16"), "{err}");
+        assert!(err.contains(client.ident()), "{err}");
+        assert!(err.contains(PROBE_LAYER), "{err}");
+
+        let address = stand_in::spawn_serving("other", DType::F16).await.unwrap();
+        let client = connect(address, DType::F16).await;
+        let err = verify_worker(DType::F16, &client, &fingerprint, &mut HashMap::new())
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("fingerprint other doesn't match"), "{err}");
+        assert!(err.contains(client.ident()), "{err}");
+        assert!(err.contains(PROBE_LAYER), "{err}");
+    }
+
+    /// Return the arguments of a node running the checkpoint, with the given layers on worker w1.
+    fn node_args(checkpoint: &Checkpoint, address: &str, mode: &str, layers: &[&str]) -> Args {
+        let name = format!("topology-{}.yml", layers.join("-"));
+        let topology = checkpoint.dir.join(name);
+        let layers = layers.join(", ");
+        std::fs::write(
+            &topology,
+            format!("w1:\n  host: {address}\n  layers: [{layers}]\n"),
+        )
+        .unwrap();
+
+        Args::parse_from([
+            "spm",
+            "--mode",
+            mode,
+            "--name",
+            "w1",
+            "--address",
+            address,
+            "--model",
+            checkpoint.dir.to_str().unwrap(),
+            "--topology",
+            topology.to_str().unwrap(),
+            "--dtype",
+            "f32",
+            "--cpu",
+            "--temperature",
+            "0",
+            "--heartbeat",
+            "0",
+        ])
+    }
+
+    async fn start_worker(
+        checkpoint: &Checkpoint,
+        address: &str,
+        layers: &[&str],
+    ) -> stand_in::Server {
+        let ctx = Context::from_args(node_args(checkpoint, address, "worker", layers)).unwrap();
+        stand_in::Server::start(Worker::<LLama>::new(ctx).await.unwrap()).unwrap()
+    }
+
+    async fn start_master(checkpoint: &Checkpoint, address: &str, layers: &[&str]) -> Box<LLama> {
+        let ctx = Context::from_args(node_args(checkpoint, address, "master", layers)).unwrap();
+        let mut llama = LLama::load(ctx).await.unwrap();
+        llama
+            .add_message(Message::user("tell the hell".to_string()))
+            .unwrap();
+        llama
+    }
+
+    #[tokio::test]
+    async fn restarted_workers_are_caught_up() {
+        let checkpoint = Checkpoint::new("restarted-worker");
+        let address = stand_in::free_address().unwrap();
+        let worker = start_worker(&checkpoint, &address, &["model.layers.1"]).await;
+
+        let mut llama = start_master(&checkpoint, &address, &["model.layers.1"]).await;
+        let mut expected = vec![];
+        for index in 0..4 {
+            expected.push(llama.next_token(index).await.unwrap().id);
+        }
+
+        // the worker loses the kv-cache of the conversation halfway
+        let mut llama = start_master(&checkpoint, &address, &["model.layers.1"]).await;
+        let mut tokens = vec![];
+        for index in 0..2 {
+            tokens.push(llama.next_token(index).await.unwrap().id);
+        }
+        worker.stop().await;
+        let worker = start_worker(&checkpoint, &address, &["model.layers.1"]).await;
+        for index in 2..4 {
+            tokens.push(llama.next_token(index).await.unwrap().id);
+        }
+        assert_eq!(tokens, expected);
+
+        worker.stop().await;
+    }
+
+    #[tokio::test]
+    async fn trailing_local_layers_are_run() {
+        let checkpoint = Checkpoint::new("mixed-topology");
+        let address = stand_in::free_address().unwrap();
+        let worker = start_worker(&checkpoint, &address, &["model.layers.0"]).await;
+
+        let mut mixed = start_master(&checkpoint, &address, &["model.layers.0"]).await;
+        assert_eq!(
+            layers_summary(&mixed.blocks),
+            [
+                ("model.layers.0".to_string(), address.clone()),
+                ("model.layers.1".to_string(), "local".to_string())
+            ]
+        );
+        let mut local = start_master(&checkpoint, &address, &[]).await;
+        assert_eq!(
+            layers_summary(&local.blocks),
+            [("model.layers.0-1".to_string(), "local".to_string())]
+        );
+
+        // every block runs, whatever node runs the last one
+        let tokens = Tensor::new(&[[1u32, 2, 3]], &candle_core::Device::Cpu).unwrap();
+        let mut x = local.embedding.forward(&tokens).unwrap();
+        for (block_idx, block) in local.blocks.iter_mut().enumerate() {
+            x = block
+                .forward_mut(&x, 0, block_idx, &mut local.ctx.cache)
+                .await
+                .unwrap();
+        }
+        let x = local.ln_f.forward(&x).unwrap().i((.., 2, ..)).unwrap();
+        let expected = local.lm_head.forward(&x).unwrap();
+
+        for llama in [&mut local, &mut mixed] {
+            let logits = llama.forward(&tokens, 0).await.unwrap();
+            let diff = (logits - &expected)
+                .unwrap()
+                .abs()
+                .unwrap()
+                .max_all()
+                .unwrap()
+                .to_scalar::<f32>()
+                .unwrap();
+            assert!(diff < 1e-4, "{diff}");
+        }
+
+        worker.stop().await;
+    }
+
+    /// A block only known by its layer and where it runs.
+    #[derive(Debug)]
+    struct Placed(String, &'static str);
+
+    impl std::fmt::Display for Placed {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{}@{}", self.0, self.1)
+        }
+    }
+
+    #[async_trait]
+    impl Forwarder for Placed {
+        fn load(_: String, _: Weights, _: &Config) -> Result<Box<Self>> {
+            bail!("placed blocks aren't loaded")
+        }
+
+        async fn forward(&self, _: &Tensor, _: usize, _: usize, _: &mut Cache) -> Result<Tensor> {
+            bail!("placed blocks don't run")
+        }
+
+        async fn forward_mut(
+            &mut self,
+            x: &Tensor,
+            index_pos: usize,
+            block_idx: usize,
+            cache: &mut Cache,
+        ) -> Result<Tensor> {
+            self.forward(x, index_pos, block_idx, cache).await
+        }
+
+        fn layer_name(&self) -> &str {
+            &self.0
+        }
+
+        fn ident(&self) -> &str {
+            self.1
+        }
+    }
+
+    #[test]
+    fn contiguous_layers_are_summarized() {
+        let nodes = ["local", "local", "local", "w1", "w1", "w2", "local", "w1"];
+        let blocks: Vec<Box<dyn Forwarder>> = nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| Box::new(Placed(format!("model.layers.{i}"), node)) as _)
+            .collect();
+
+        let summary = layers_summary(&blocks);
+        let summary: Vec<(&str, &str)> = summary
+            .iter()
+            .map(|(range, node)| (range.as_str(), node.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("model.layers.0-2", "local"),
+                ("model.layers.3-4", "w1"),
+                ("model.layers.5", "w2"),
+                ("model.layers.6", "local"),
+                ("model.layers.7", "w1"),
+            ]
+        );
+    }
 }
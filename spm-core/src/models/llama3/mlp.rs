@@ -1,13 +1,16 @@
 use candle_core::{Result, Tensor};
-use candle_nn::{linear_no_bias as linear, Linear, Module, VarBuilder};
+use candle_nn::Module;
+
+use super::{linear, QLinear};
+use crate::utils::Weights;
 
 /// Multi-perceptron implementation.
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone)]
 pub struct MLP {
-    gate_proj: Linear,
-    up_proj: Linear,
-    down_proj: Linear,
+    gate_proj: QLinear,
+    up_proj: QLinear,
+    down_proj: QLinear,
 }
 
 impl MLP {
@@ -18,7 +21,7 @@ impl MLP {
     }
 
     /// Load this block from the VarBuilder given the specific configuration.
-    pub fn load(vb: VarBuilder, cfg: &super::Config) -> Result<Self> {
+    pub fn load(vb: Weights, cfg: &super::Config) -> Result<Self> {
         let h_size = cfg.hidden_size;
         let i_size = cfg.intermediate_size;
         let gate_proj = linear(h_size, i_size, vb.pp("gate_proj"))?;
@@ -2,15 +2,21 @@
 mod attention;
 mod cache;
 mod config;
+mod decoder;
 mod history;
 mod llama;
 mod mlp;
+mod nn;
+#[cfg(test)]
+pub(crate) mod testing;
 mod transformer;
 
 pub use attention::*;
 pub use cache::*;
 pub use config::*;
+pub use decoder::*;
 pub use history::*;
 pub use llama::*;
 pub use mlp::*;
+pub use nn::*;
 pub use transformer::*;
@@ -0,0 +1,42 @@
+//! Layers loaded from the model Weights, linear layers keep quantized weights quantized.
+use candle_core::{quantized::QMatMul, DType, Module, Result, Tensor};
+use candle_nn::{Embedding, RmsNorm};
+
+use crate::utils::Weights;
+
+/// Linear layer without bias, the weight can be quantized.
+#[derive(Debug, Clone)]
+pub struct QLinear {
+    weight: QMatMul,
+}
+
+impl Module for QLinear {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        match &self.weight {
+            // quantized matmul only works on f32
+            QMatMul::QTensor(_) if x.dtype() != DType::F32 => self
+                .weight
+                .forward(&x.to_dtype(DType::F32)?)?
+                .to_dtype(x.dtype()),
+            weight => weight.forward(x),
+        }
+    }
+}
+
+/// Load a linear layer without bias.
+pub fn linear(in_dim: usize, out_dim: usize, vb: Weights) -> Result<QLinear> {
+    let weight = vb.get_matmul((out_dim, in_dim), "weight")?;
+    Ok(QLinear { weight })
+}
+
+/// Load a RMS normalization layer.
+pub fn rms_norm(size: usize, eps: f64, vb: Weights) -> Result<RmsNorm> {
+    let weight = vb.get(size, "weight")?;
+    Ok(RmsNorm::new(weight, eps))
+}
+
+/// Load an embedding layer, quantized embeddings are dequantized.
+pub fn embedding(in_size: usize, out_size: usize, vb: Weights) -> Result<Embedding> {
+    let embeddings = vb.get((in_size, out_size), "weight")?;
+    Ok(Embedding::new(embeddings, out_size))
+}
@@ -0,0 +1,168 @@
+//! Small synthetic Llama 3 checkpoints used by the tests.
+use std::{collections::HashMap, path::PathBuf};
+
+use candle_core::{Device, Tensor};
+use tokenizers::{
+    models::bpe::BPE,
+    pre_tokenizers::{
+        byte_level::ByteLevel,
+        sequence::Sequence,
+        split::{Split, SplitPattern},
+    },
+    AddedToken, SplitDelimiterBehavior, Tokenizer,
+};
+
+use super::Config;
+
+/// Pre-tokenization pattern found in the tokenizer.json of Llama 3.
+const SPLIT_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+/// Merges of the synthetic tokenizer, on top of the byte level alphabet.
+const MERGES: &[(&str, &str)] = &[
+    ("Ġ", "t"),
+    ("h", "e"),
+    ("Ġt", "he"),
+    ("l", "l"),
+    ("e", "ll"),
+    ("1", "2"),
+];
+
+/// Special tokens of the synthetic tokenizer.
+const SPECIAL_TOKENS: &[&str] = &["<|begin_of_text|>", "<|end_of_text|>", "<|eot_id|>"];
+
+/// A checkpoint with random weights, a byte level BPE tokenizer, its config.json and a single
+/// safetensors file, removed when dropped.
+pub struct Checkpoint {
+    pub dir: PathBuf,
+    pub config: Config,
+}
+
+impl Checkpoint {
+    /// Write a checkpoint in a new temporary directory named after the test.
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("spm-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let tokenizer = tokenizer();
+        tokenizer.save(dir.join("tokenizer.json"), false).unwrap();
+
+        let config = Config {
+            hidden_size: 64,
+            intermediate_size: 128,
+            vocab_size: tokenizer.get_vocab_size(true),
+            num_hidden_layers: 2,
+            num_attention_heads: 4,
+            num_key_value_heads: 2,
+            rms_norm_eps: 1e-5,
+            rope_theta: 500000.0,
+            bos_token_id: tokenizer.token_to_id(SPECIAL_TOKENS[0]),
+            eos_token_ids: vec![tokenizer.token_to_id(SPECIAL_TOKENS[1]).unwrap()],
+        };
+        let config_json = serde_json::json!({
+            "hidden_size": config.hidden_size,
+            "intermediate_size": config.intermediate_size,
+            "vocab_size": config.vocab_size,
+            "num_hidden_layers": config.num_hidden_layers,
+            "num_attention_heads": config.num_attention_heads,
+            "num_key_value_heads": config.num_key_value_heads,
+            "rms_norm_eps": config.rms_norm_eps,
+            "rope_theta": config.rope_theta,
+            "bos_token_id": config.bos_token_id,
+            "eos_token_id": config.eos_token_ids[0],
+        });
+        std::fs::write(dir.join("config.json"), config_json.to_string()).unwrap();
+        candle_core::safetensors::save(&tensors(&config), dir.join("model.safetensors")).unwrap();
+
+        Self { dir, config }
+    }
+}
+
+impl Drop for Checkpoint {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Build a byte level BPE tokenizer with the Llama 3 pre-tokenization.
+pub fn tokenizer() -> Tokenizer {
+    let mut alphabet: Vec<char> = ByteLevel::alphabet().into_iter().collect();
+    alphabet.sort();
+    let mut vocab: HashMap<String, u32> = alphabet
+        .iter()
+        .enumerate()
+        .map(|(id, c)| (c.to_string(), id as u32))
+        .collect();
+    let mut merges = vec![];
+    for (a, b) in MERGES {
+        vocab.insert(format!("{a}{b}"), vocab.len() as u32);
+        merges.push((a.to_string(), b.to_string()));
+    }
+
+    let bpe = BPE::builder()
+        .vocab_and_merges(vocab, merges)
+        .ignore_merges(true)
+        .build()
+        .unwrap();
+    let split = Split::new(
+        SplitPattern::Regex(SPLIT_PATTERN.to_string()),
+        SplitDelimiterBehavior::Isolated,
+        false,
+    )
+    .unwrap();
+
+    let mut tokenizer = Tokenizer::new(bpe);
+    tokenizer
+        .with_pre_tokenizer(Sequence::new(vec![
+            split.into(),
+            ByteLevel::new(false, true, false).into(),
+        ]))
+        .with_decoder(ByteLevel::default());
+    let special: Vec<AddedToken> = SPECIAL_TOKENS
+        .iter()
+        .map(|token| AddedToken::from(token.to_string(), true))
+        .collect();
+    tokenizer.add_special_tokens(&special);
+
+    tokenizer
+}
+
+/// Return random weights for every tensor of the model.
+fn tensors(config: &Config) -> HashMap<String, Tensor> {
+    let hidden = config.hidden_size;
+    let kv = hidden / config.num_attention_heads * config.num_key_value_heads;
+    let mut shapes = vec![
+        (
+            "model.embed_tokens.weight".to_string(),
+            vec![config.vocab_size, hidden],
+        ),
+        ("model.norm.weight".to_string(), vec![hidden]),
+        (
+            "lm_head.weight".to_string(),
+            vec![config.vocab_size, hidden],
+        ),
+    ];
+    for i in 0..config.num_hidden_layers {
+        let block = [
+            ("self_attn.q_proj", vec![hidden, hidden]),
+            ("self_attn.k_proj", vec![kv, hidden]),
+            ("self_attn.v_proj", vec![kv, hidden]),
+            ("self_attn.o_proj", vec![hidden, hidden]),
+            ("mlp.gate_proj", vec![config.intermediate_size, hidden]),
+            ("mlp.up_proj", vec![config.intermediate_size, hidden]),
+            ("mlp.down_proj", vec![hidden, config.intermediate_size]),
+            ("input_layernorm", vec![hidden]),
+            ("post_attention_layernorm", vec![hidden]),
+        ];
+        for (name, shape) in block {
+            shapes.push((format!("model.layers.{i}.{name}.weight"), shape));
+        }
+    }
+
+    shapes
+        .into_iter()
+        .map(|(name, shape)| {
+            let tensor = Tensor::randn(0f32, 0.1, shape, &Device::Cpu).unwrap();
+            (name, tensor)
+        })
+        .collect()
+}
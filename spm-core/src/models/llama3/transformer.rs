@@ -1,10 +1,10 @@
 use anyhow::Result;
 use candle_core::Tensor;
-use candle_nn::{Module, RmsNorm, VarBuilder};
+use candle_nn::{Module, RmsNorm};
 
 use async_trait::async_trait;
 
-use crate::spm::Forwarder;
+use crate::{spm::Forwarder, utils::Weights};
 
 use super::{Cache, CausalSelfAttention, Config, MLP};
 
@@ -26,12 +26,11 @@ impl std::fmt::Display for Transformer {
 
 #[async_trait]
 impl Forwarder for Transformer {
-    fn load(name: String, vb: VarBuilder, cfg: &Config) -> Result<Box<Self>> {
+    fn load(name: String, vb: Weights, cfg: &Config) -> Result<Box<Self>> {
         let attn = super::CausalSelfAttention::load(vb.pp("self_attn"), cfg)?;
         let mlp = super::MLP::load(vb.pp("mlp"), cfg)?;
-        let rms_1 =
-            candle_nn::rms_norm(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("input_layernorm"))?;
-        let rms_2 = candle_nn::rms_norm(
+        let rms_1 = super::rms_norm(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("input_layernorm"))?;
+        let rms_2 = super::rms_norm(
             cfg.hidden_size,
             cfg.rms_norm_eps,
             vb.pp("post_attention_layernorm"),
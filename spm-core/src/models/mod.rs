@@ -1,17 +1,21 @@
 pub mod chat;
 pub mod llama3;
+pub mod params;
 
 use crate::spm::{Context, Forwarder};
 
 use anyhow::Result;
 use async_trait::async_trait;
 use chat::Message;
+use params::GenerationParams;
+use serde::Serialize;
 
 /// A token.
 pub struct Token {
     /// Numerical identifier.
     pub id: u32,
-    /// Resolved text token or None if not present in the tokenizer.
+    /// Resolved text, empty if the token only completes with the next ones,
+    /// or None if it could not be decoded.
     pub text: Option<String>,
     /// Set to true if the stream of tokens is over.
     pub is_end_of_stream: bool,
@@ -31,6 +35,29 @@ impl std::fmt::Display for Token {
     }
 }
 
+/// The reason why a generation ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FinishReason {
+    /// The model produced an end of stream token.
+    Stop,
+    /// The max number of tokens or the end of the context window was reached.
+    Length,
+}
+
+impl std::fmt::Display for FinishReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                FinishReason::Stop => "stop",
+                FinishReason::Length => "length",
+            }
+        )
+    }
+}
+
 /// A model must implement this trait in order to be usable by the spm framework.
 #[async_trait]
 pub trait Generator {
@@ -45,11 +72,24 @@ pub trait Generator {
 
     /// Add a message to the chat.
     fn add_message(&mut self, message: Message) -> Result<()>;
-    /// Clear chat history.
+    /// Clear chat history, state shared with the next prompt can be kept by the model.
     fn reset(&mut self) -> Result<()>;
+    /// Drop any cached state, locally and on the workers.
+    async fn clear_cache(&mut self) -> Result<()>;
+    /// Set the sampling parameters of the next generation.
+    fn set_params(&mut self, params: GenerationParams) -> Result<()>;
 
     /// Return the next token.
     async fn next_token(&mut self, index: usize) -> Result<Token>;
+    /// Return the text of the generated tokens that wasn't returned yet, at the end of generation.
+    fn flush_text(&mut self) -> Result<String>;
     /// Return the number of generated tokens so far.
     fn generated_tokens(&self) -> usize;
+    /// Return the number of tokens the current prompt was encoded to.
+    fn prompt_tokens(&self) -> usize;
+    /// Return the max number of tokens, prompt included, the model can attend to.
+    fn max_sequence_length(&self) -> usize;
+
+    /// Return the perplexity of the model on text, dropping any cached state.
+    async fn perplexity(&mut self, text: &str) -> Result<f64>;
 }
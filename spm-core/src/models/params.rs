@@ -0,0 +1,256 @@
+use anyhow::Result;
+
+use crate::Args;
+
+/// Parameters of a single generation, defaults are taken from the command line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenerationParams {
+    /// The seed to use when generating random samples.
+    pub seed: u64,
+    /// The temperature used to generate samples, 0 means greedy sampling.
+    pub temperature: f64,
+    /// Nucleus sampling probability cutoff.
+    pub top_p: Option<f64>,
+    /// Only sample among the top K samples.
+    pub top_k: Option<usize>,
+    /// Penalty to be applied for repeating tokens, 1. means no penalty.
+    pub repeat_penalty: f32,
+    /// The context size to consider for the repeat penalty.
+    pub repeat_last_n: usize,
+    /// Max number of tokens to generate.
+    pub max_tokens: usize,
+    /// Stop generating when any of these strings is produced.
+    pub stop: Vec<String>,
+}
+
+impl From<&Args> for GenerationParams {
+    fn from(args: &Args) -> Self {
+        Self {
+            seed: args.seed,
+            temperature: args.temperature,
+            top_p: args.top_p,
+            top_k: args.top_k,
+            repeat_penalty: args.repeat_penalty,
+            repeat_last_n: args.repeat_last_n,
+            max_tokens: args.sample_len,
+            stop: args.stop.clone(),
+        }
+    }
+}
+
+impl GenerationParams {
+    /// Return an error if any of the parameters is out of its valid range.
+    pub fn validate(&self) -> Result<()> {
+        if !self.temperature.is_finite() || self.temperature < 0.0 {
+            bail!("temperature must be >= 0, got {}", self.temperature);
+        }
+        if let Some(p) = self.top_p {
+            if !(p > 0.0 && p <= 1.0) {
+                bail!("top_p must be in (0, 1], got {p}");
+            }
+        }
+        if self.top_k == Some(0) {
+            bail!("top_k must be > 0");
+        }
+        if !self.repeat_penalty.is_finite() || self.repeat_penalty <= 0.0 {
+            bail!("repeat_penalty must be > 0, got {}", self.repeat_penalty);
+        }
+        if self.max_tokens == 0 {
+            bail!("max_tokens must be > 0");
+        }
+        if self.stop.iter().any(|s| s.is_empty()) {
+            bail!("stop sequences can't be empty");
+        }
+        Ok(())
+    }
+
+    /// Set a parameter by name from its string representation, "none" unsets optional ones.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        let mut params = self.clone();
+        let value = value.trim();
+        let invalid = |e: &dyn std::fmt::Display| anyhow!("invalid value '{value}' for {name}: {e}");
+
+        match name {
+            "seed" => params.seed = value.parse().map_err(|e| invalid(&e))?,
+            "temperature" => params.temperature = value.parse().map_err(|e| invalid(&e))?,
+            "top_p" => {
+                params.top_p = if value == "none" {
+                    None
+                } else {
+                    Some(value.parse().map_err(|e| invalid(&e))?)
+                }
+            }
+            "top_k" => {
+                params.top_k = if value == "none" {
+                    None
+                } else {
+                    Some(value.parse().map_err(|e| invalid(&e))?)
+                }
+            }
+            "repeat_penalty" => params.repeat_penalty = value.parse().map_err(|e| invalid(&e))?,
+            "repeat_last_n" => params.repeat_last_n = value.parse().map_err(|e| invalid(&e))?,
+            "max_tokens" => params.max_tokens = value.parse().map_err(|e| invalid(&e))?,
+            "stop" => {
+                if value == "none" {
+                    params.stop.clear();
+                } else {
+                    // allow new lines and tabs to be typed as escape sequences
+                    params
+                        .stop
+                        .push(value.replace("\\n", "\n").replace("\\t", "\t"));
+                }
+            }
+            _ => bail!("unknown parameter {name}"),
+        }
+
+        params.validate()?;
+        *self = params;
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for GenerationParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let or_none = |v: Option<String>| v.unwrap_or_else(|| "none".to_string());
+        write!(
+            f,
+            "seed={} temperature={} top_p={} top_k={} repeat_penalty={} repeat_last_n={} max_tokens={} stop={:?}",
+            self.seed,
+            self.temperature,
+            or_none(self.top_p.map(|p| p.to_string())),
+            or_none(self.top_k.map(|k| k.to_string())),
+            self.repeat_penalty,
+            self.repeat_last_n,
+            self.max_tokens,
+            self.stop
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn params() -> GenerationParams {
+        GenerationParams::from(&Args::parse_from(["spm"]))
+    }
+
+    /// Return the params with a single change applied.
+    fn with(change: impl FnOnce(&mut GenerationParams)) -> GenerationParams {
+        let mut params = params();
+        change(&mut params);
+        params
+    }
+
+    #[test]
+    fn command_line_defaults_are_valid() {
+        params().validate().unwrap();
+    }
+
+    #[test]
+    fn out_of_range_params_are_invalid() {
+        for (params, error) in [
+            (with(|p| p.temperature = -0.1), "temperature"),
+            (with(|p| p.temperature = f64::NAN), "temperature"),
+            (with(|p| p.temperature = f64::INFINITY), "temperature"),
+            (with(|p| p.top_p = Some(0.0)), "top_p"),
+            (with(|p| p.top_p = Some(1.01)), "top_p"),
+            (with(|p| p.top_p = Some(f64::NAN)), "top_p"),
+            (with(|p| p.top_k = Some(0)), "top_k"),
+            (with(|p| p.repeat_penalty = 0.0), "repeat_penalty"),
+            (with(|p| p.repeat_penalty = f32::NAN), "repeat_penalty"),
+            (with(|p| p.max_tokens = 0), "max_tokens"),
+            (
+                with(|p| p.stop = vec!["end".to_string(), String::new()]),
+                "stop",
+            ),
+        ] {
+            let e = params.validate().unwrap_err().to_string();
+            assert!(e.contains(error), "{params}: {e}");
+        }
+    }
+
+    #[test]
+    fn boundary_params_are_valid() {
+        for params in [
+            with(|p| p.temperature = 0.0),
+            with(|p| p.top_p = Some(1.0)),
+            with(|p| p.top_p = Some(f64::MIN_POSITIVE)),
+            with(|p| p.top_k = Some(1)),
+            with(|p| p.max_tokens = 1),
+            with(|p| p.stop = vec![" ".to_string()]),
+        ] {
+            params.validate().unwrap();
+        }
+    }
+
+    #[test]
+    fn set_parses_values() {
+        let mut params = params();
+        params.set("seed", "42").unwrap();
+        params.set("temperature", " 0.5 ").unwrap();
+        params.set("top_p", "0.9").unwrap();
+        params.set("top_k", "40").unwrap();
+        params.set("repeat_penalty", "1.2").unwrap();
+        params.set("repeat_last_n", "64").unwrap();
+        params.set("max_tokens", "10").unwrap();
+        params.set("stop", "\\n\\n").unwrap();
+        params.set("stop", "END").unwrap();
+
+        assert_eq!(
+            params,
+            GenerationParams {
+                seed: 42,
+                temperature: 0.5,
+                top_p: Some(0.9),
+                top_k: Some(40),
+                repeat_penalty: 1.2,
+                repeat_last_n: 64,
+                max_tokens: 10,
+                stop: vec!["\n\n".to_string(), "END".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn set_none_unsets_optional_params() {
+        let mut params = with(|p| {
+            p.top_p = Some(0.9);
+            p.top_k = Some(40);
+            p.stop = vec!["END".to_string()];
+        });
+        params.set("top_p", "none").unwrap();
+        params.set("top_k", "none").unwrap();
+        params.set("stop", "none").unwrap();
+        assert_eq!(params.top_p, None);
+        assert_eq!(params.top_k, None);
+        assert!(params.stop.is_empty());
+
+        // other params can't be unset
+        assert!(params.set("temperature", "none").is_err());
+        assert!(params.set("max_tokens", "none").is_err());
+    }
+
+    #[test]
+    fn failed_set_leaves_params_unchanged() {
+        let mut params = with(|p| p.stop = vec!["END".to_string()]);
+        let before = params.clone();
+        for (name, value) in [
+            ("temperature", "-1"),
+            ("temperature", "NaN"),
+            ("temperature", "hot"),
+            ("top_p", "0"),
+            ("top_k", "0"),
+            ("top_k", "-1"),
+            ("max_tokens", "0"),
+            ("repeat_penalty", "0"),
+            ("stop", ""),
+            ("unknown", "1"),
+        ] {
+            assert!(params.set(name, value).is_err(), "{name}={value}");
+            assert_eq!(params, before, "{name}={value}");
+        }
+    }
+}
@@ -0,0 +1,734 @@
+//! OpenAI compatible chat completion api.
+use std::sync::Arc;
+
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, Mutex};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+use crate::models::{
+    chat::{Message, MessageRole},
+    params::GenerationParams,
+    FinishReason, Generator,
+};
+
+use super::Master;
+
+/// Max size of a chat completion request body.
+const MAX_REQUEST_SIZE: usize = 16 * 1024 * 1024;
+
+/// Shared master state, only one generation can run at any given time.
+type State<G> = web::Data<Arc<Mutex<Master<G>>>>;
+
+/// Server-sent events channel used while streaming a completion.
+type EventSender = mpsc::UnboundedSender<Result<web::Bytes, actix_web::Error>>;
+
+/// A chat completion request, unsupported fields are ignored.
+#[derive(Debug, Deserialize)]
+struct ChatRequest {
+    /// Chat messages.
+    pub messages: Vec<Message>,
+    /// If true, stream the response as chat.completion.chunk events.
+    #[serde(default)]
+    pub stream: bool,
+    /// Streaming options.
+    #[serde(default)]
+    pub stream_options: Option<StreamOptions>,
+    /// Sampling temperature.
+    pub temperature: Option<f64>,
+    /// Nucleus sampling probability cutoff.
+    pub top_p: Option<f64>,
+    /// Only sample among the top K samples.
+    pub top_k: Option<usize>,
+    /// Sampling seed.
+    pub seed: Option<u64>,
+    /// Penalty to be applied for repeating tokens.
+    pub repeat_penalty: Option<f32>,
+    /// Max number of tokens to generate.
+    #[serde(alias = "max_completion_tokens")]
+    pub max_tokens: Option<usize>,
+    /// Stop sequences.
+    pub stop: Option<StopRequest>,
+}
+
+/// Stop sequences can be passed either as a single string or a list.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum StopRequest {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl ChatRequest {
+    /// Return the generation parameters of this request, using defaults for missing ones.
+    fn params(&self, defaults: &GenerationParams) -> Result<GenerationParams> {
+        let mut params = defaults.clone();
+        if let Some(temperature) = self.temperature {
+            params.temperature = temperature;
+        }
+        if self.top_p.is_some() {
+            params.top_p = self.top_p;
+        }
+        if self.top_k.is_some() {
+            params.top_k = self.top_k;
+        }
+        if let Some(seed) = self.seed {
+            params.seed = seed;
+        }
+        if let Some(repeat_penalty) = self.repeat_penalty {
+            params.repeat_penalty = repeat_penalty;
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            params.max_tokens = max_tokens;
+        }
+        match &self.stop {
+            Some(StopRequest::Single(stop)) => params.stop = vec![stop.clone()],
+            Some(StopRequest::Multiple(stop)) => params.stop = stop.clone(),
+            None => {}
+        }
+        params.validate()?;
+        Ok(params)
+    }
+}
+
+/// Options for streamed completions.
+#[derive(Debug, Default, Deserialize)]
+struct StreamOptions {
+    /// If true, send a last chunk with the token usage of the completion.
+    #[serde(default)]
+    pub include_usage: bool,
+}
+
+/// A single completion choice.
+#[derive(Debug, Serialize)]
+struct Choice {
+    pub index: usize,
+    pub message: Message,
+    pub finish_reason: FinishReason,
+}
+
+/// Token usage statistics of a completion.
+#[derive(Debug, Serialize)]
+struct Usage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+}
+
+/// A chat completion response.
+#[derive(Debug, Serialize)]
+struct ChatResponse {
+    pub id: String,
+    pub object: String,
+    pub created: u64,
+    pub model: String,
+    pub choices: Vec<Choice>,
+    pub usage: Usage,
+}
+
+impl ChatResponse {
+    /// Create a chat.completion object from the assistant response.
+    pub fn from_assistant_response(
+        model: &str,
+        content: String,
+        finish_reason: FinishReason,
+        usage: Usage,
+    ) -> Self {
+        Self {
+            id: format!("chatcmpl-{}", uuid::Uuid::new_v4()),
+            object: "chat.completion".to_string(),
+            created: unix_timestamp(),
+            model: model.to_string(),
+            choices: vec![Choice {
+                index: 0,
+                message: Message::assistant(content),
+                finish_reason,
+            }],
+            usage,
+        }
+    }
+}
+
+/// The incremental message content of a streamed choice.
+#[derive(Debug, Default, Serialize)]
+struct Delta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<MessageRole>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+}
+
+/// A single streamed completion choice.
+#[derive(Debug, Serialize)]
+struct ChunkChoice {
+    pub index: usize,
+    pub delta: Delta,
+    pub finish_reason: Option<FinishReason>,
+}
+
+/// A chat completion chunk, sent as a server-sent event while streaming.
+#[derive(Debug, Serialize)]
+struct ChatChunk {
+    pub id: String,
+    pub object: String,
+    pub created: u64,
+    pub model: String,
+    pub choices: Vec<ChunkChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+}
+
+/// Builds the chunks of a single streamed completion, sharing id and creation time.
+struct ChunkBuilder {
+    id: String,
+    created: u64,
+    model: String,
+}
+
+impl ChunkBuilder {
+    fn new(model: &str) -> Self {
+        Self {
+            id: format!("chatcmpl-{}", uuid::Uuid::new_v4()),
+            created: unix_timestamp(),
+            model: model.to_string(),
+        }
+    }
+
+    fn chunk(&self, choices: Vec<ChunkChoice>, usage: Option<Usage>) -> ChatChunk {
+        ChatChunk {
+            id: self.id.clone(),
+            object: "chat.completion.chunk".to_string(),
+            created: self.created,
+            model: self.model.clone(),
+            choices,
+            usage,
+        }
+    }
+
+    /// The first chunk, only carrying the assistant role.
+    fn role(&self) -> ChatChunk {
+        self.chunk(
+            vec![ChunkChoice {
+                index: 0,
+                delta: Delta {
+                    role: Some(MessageRole::Assistant),
+                    content: Some(String::new()),
+                },
+                finish_reason: None,
+            }],
+            None,
+        )
+    }
+
+    /// A chunk with the text of a single token.
+    fn content(&self, content: &str) -> ChatChunk {
+        self.chunk(
+            vec![ChunkChoice {
+                index: 0,
+                delta: Delta {
+                    role: None,
+                    content: Some(content.to_string()),
+                },
+                finish_reason: None,
+            }],
+            None,
+        )
+    }
+
+    /// The last chunk of the choice with its finish reason.
+    fn finish(&self, finish_reason: FinishReason) -> ChatChunk {
+        self.chunk(
+            vec![ChunkChoice {
+                index: 0,
+                delta: Delta::default(),
+                finish_reason: Some(finish_reason),
+            }],
+            None,
+        )
+    }
+
+    /// The optional usage chunk sent after the last choice chunk.
+    fn usage(&self, usage: Usage) -> ChatChunk {
+        self.chunk(vec![], Some(usage))
+    }
+}
+
+/// An OpenAI style error object.
+#[derive(Debug, Serialize)]
+struct ErrorResponse {
+    pub error: ErrorDetail,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorDetail {
+    pub message: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+}
+
+impl ErrorResponse {
+    fn new(kind: &str, message: String) -> Self {
+        Self {
+            error: ErrorDetail {
+                message,
+                kind: kind.to_string(),
+            },
+        }
+    }
+}
+
+/// Return the current unix timestamp in seconds.
+fn unix_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Send a server-sent event with the given payload, fails if the client disconnected.
+fn send_event<T: Serialize>(tx: &EventSender, payload: &T) -> Result<()> {
+    let data = serde_json::to_string(payload)?;
+    tx.send(Ok(web::Bytes::from(format!("data: {data}\n\n"))))
+        .map_err(|_| anyhow!("client disconnected"))
+}
+
+/// Return the token usage of the last generation.
+fn last_usage<G: Generator>(master: &Master<G>) -> Usage {
+    let prompt_tokens = master.model.prompt_tokens();
+    let completion_tokens = master.model.generated_tokens();
+    Usage {
+        prompt_tokens,
+        completion_tokens,
+        total_tokens: prompt_tokens + completion_tokens,
+    }
+}
+
+/// Run a generation for the given messages, calling stream for every token.
+async fn chat<G, S>(
+    master: &mut Master<G>,
+    messages: Vec<Message>,
+    params: &GenerationParams,
+    stream: S,
+) -> Result<(FinishReason, Usage)>
+where
+    G: Generator + Send + Sync + 'static,
+    S: FnMut(&str) -> Result<()>,
+{
+    // the kv-cache is kept for the prefix shared with the previous request
+    master.model.reset()?;
+    for message in messages {
+        master.model.add_message(message)?;
+    }
+
+    let finish_reason = master.generate(params, stream).await?;
+
+    Ok((finish_reason, last_usage(master)))
+}
+
+/// Run a generation for the given messages and stream its tokens as chat.completion.chunk events.
+async fn chat_stream<G>(
+    state: Arc<Mutex<Master<G>>>,
+    client: String,
+    request: ChatRequest,
+    params: GenerationParams,
+    tx: EventSender,
+) where
+    G: Generator + Send + Sync + 'static,
+{
+    let include_usage = request
+        .stream_options
+        .as_ref()
+        .map(|opts| opts.include_usage)
+        .unwrap_or(false);
+    let chunks = ChunkBuilder::new(G::MODEL_NAME);
+    let mut master = state.lock().await;
+
+    // a failed send stops the generation, so that the master is released as soon as the
+    // client disconnects
+    let res = async {
+        send_event(&tx, &chunks.role())?;
+        let (finish_reason, usage) = chat(&mut master, request.messages, &params, |data| {
+            // empty data signals the end of the stream
+            if data.is_empty() {
+                Ok(())
+            } else {
+                send_event(&tx, &chunks.content(data))
+            }
+        })
+        .await?;
+
+        send_event(&tx, &chunks.finish(finish_reason))?;
+        if include_usage {
+            send_event(&tx, &chunks.usage(usage))?;
+        }
+        Ok::<(), anyhow::Error>(())
+    }
+    .await;
+
+    if let Err(e) = res {
+        if tx.is_closed() {
+            log::info!("[{}] client disconnected, generation stopped", &client);
+            return;
+        }
+        log::error!("[{}] chat error: {:?}", &client, e);
+        let _ = send_event(&tx, &ErrorResponse::new("server_error", e.to_string()));
+    }
+
+    let _ = tx.send(Ok(web::Bytes::from_static(b"data: [DONE]\n\n")));
+}
+
+/// POST /v1/chat/completions
+async fn chat_completions<G>(
+    state: State<G>,
+    defaults: web::Data<GenerationParams>,
+    req: HttpRequest,
+    body: web::Json<ChatRequest>,
+) -> HttpResponse
+where
+    G: Generator + Send + Sync + 'static,
+{
+    let client = req
+        .peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_else(|| "?".to_string());
+
+    let request = body.into_inner();
+    if request.messages.is_empty() {
+        return HttpResponse::BadRequest().json(ErrorResponse::new(
+            "invalid_request_error",
+            "messages can't be empty".to_string(),
+        ));
+    }
+
+    let params = match request.params(&defaults) {
+        Ok(params) => params,
+        Err(e) => {
+            return HttpResponse::BadRequest()
+                .json(ErrorResponse::new("invalid_request_error", e.to_string()))
+        }
+    };
+
+    log::info!(
+        "starting chat for {} ({} messages, {}) ...",
+        &client,
+        request.messages.len(),
+        &params
+    );
+
+    if request.stream {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let state = state.get_ref().clone();
+
+        actix_web::rt::spawn(chat_stream(state, client, request, params, tx));
+
+        return HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .insert_header(("Cache-Control", "no-cache"))
+            .streaming(UnboundedReceiverStream::new(rx));
+    }
+
+    let mut content = String::new();
+    let mut master = state.lock().await;
+    let res = chat(&mut master, request.messages, &params, |data| {
+        content += data;
+        Ok(())
+    })
+    .await;
+    match res {
+        Ok((finish_reason, usage)) => HttpResponse::Ok().json(
+            ChatResponse::from_assistant_response(G::MODEL_NAME, content, finish_reason, usage),
+        ),
+        Err(e) => {
+            log::error!("[{}] chat error: {:?}", &client, e);
+            HttpResponse::InternalServerError()
+                .json(ErrorResponse::new("server_error", e.to_string()))
+        }
+    }
+}
+
+/// Register the api routes and the state they share.
+fn configure<G>(
+    cfg: &mut web::ServiceConfig,
+    state: Arc<Mutex<Master<G>>>,
+    defaults: GenerationParams,
+) where
+    G: Generator + Send + Sync + 'static,
+{
+    cfg.app_data(web::Data::new(state))
+        .app_data(web::Data::new(defaults))
+        .app_data(web::JsonConfig::default().limit(MAX_REQUEST_SIZE))
+        .route(
+            "/v1/chat/completions",
+            web::post().to(chat_completions::<G>),
+        )
+        .route(
+            "/api/v1/chat/completions",
+            web::post().to(chat_completions::<G>),
+        );
+}
+
+/// Start the api server on the given address and serve requests using the master.
+pub(crate) async fn start<G>(master: Master<G>, address: &str) -> Result<()>
+where
+    G: Generator + Send + Sync + 'static,
+{
+    log::info!("starting api on http://{} ...", address);
+
+    let defaults = master.default_params();
+    let state = Arc::new(Mutex::new(master));
+
+    HttpServer::new(move || {
+        App::new().configure(|cfg| configure(cfg, state.clone(), defaults.clone()))
+    })
+    .bind(address)
+    .map_err(|e| anyhow!("can't bind api to {address}: {e}"))?
+    .run()
+    .await
+    .map_err(|e| anyhow!(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{call_service, init_service, read_body, read_body_json, TestRequest};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{
+        models::llama3::testing::Checkpoint,
+        spm::master::scripted::{self, ScriptedModel},
+    };
+
+    /// Return the shared state of a master generating with the model, and its default parameters.
+    fn state(
+        checkpoint: &Checkpoint,
+        model: ScriptedModel,
+    ) -> (Arc<Mutex<Master<ScriptedModel>>>, GenerationParams) {
+        let master = scripted::master(checkpoint, model);
+        let defaults = master.default_params();
+        (Arc::new(Mutex::new(master)), defaults)
+    }
+
+    fn post(uri: &str, body: Value) -> TestRequest {
+        TestRequest::post().uri(uri).set_json(body)
+    }
+
+    #[actix_web::test]
+    async fn completions_are_served_on_both_routes() {
+        let checkpoint = Checkpoint::new("api-completions");
+        let (state, defaults) = state(&checkpoint, ScriptedModel::new(&["Hello", " world"]));
+        let app = init_service(App::new().configure(|cfg| configure(cfg, state, defaults))).await;
+
+        for uri in ["/v1/chat/completions", "/api/v1/chat/completions"] {
+            let body = json!({
+                "messages": [
+                    {"role": "system", "content": "be brief"},
+                    {"role": "user", "content": "say hello please"},
+                ]
+            });
+            let resp = call_service(&app, post(uri, body).to_request()).await;
+            assert_eq!(resp.status(), 200, "{uri}");
+
+            let resp: Value = read_body_json(resp).await;
+            assert!(resp["id"].as_str().unwrap().starts_with("chatcmpl-"));
+            assert_eq!(resp["object"], "chat.completion");
+            assert_eq!(resp["model"], ScriptedModel::MODEL_NAME);
+            assert!(resp["created"].as_u64().unwrap() > 0);
+            assert_eq!(
+                resp["choices"],
+                json!([{
+                    "index": 0,
+                    "message": {"role": "assistant", "content": "Hello world"},
+                    "finish_reason": "stop",
+                }])
+            );
+            // two tokens and the end of stream for a prompt of five words
+            assert_eq!(
+                resp["usage"],
+                json!({"prompt_tokens": 5, "completion_tokens": 3, "total_tokens": 8})
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn request_params_are_applied() {
+        let checkpoint = Checkpoint::new("api-params");
+        let mut model = ScriptedModel::new(&["a", "b", "c"]);
+        model.endless = true;
+        let (state, defaults) = state(&checkpoint, model);
+        let app = init_service(
+            App::new().configure(|cfg| configure(cfg, state.clone(), defaults.clone())),
+        )
+        .await;
+
+        let body = json!({
+            "messages": [{"role": "user", "content": "go"}],
+            "max_tokens": 4,
+            "temperature": 0.5,
+            "stop": "c",
+        });
+        let resp = call_service(&app, post("/v1/chat/completions", body).to_request()).await;
+        assert_eq!(resp.status(), 200);
+        let resp: Value = read_body_json(resp).await;
+        assert_eq!(resp["choices"][0]["message"]["content"], "ab");
+        assert_eq!(resp["choices"][0]["finish_reason"], "stop");
+
+        let params = state.lock().await.model.params.clone().unwrap();
+        assert_eq!(params.max_tokens, 4);
+        assert_eq!(params.temperature, 0.5);
+        assert_eq!(params.stop, ["c"]);
+        // missing ones come from the command line
+        assert_eq!(params.repeat_penalty, defaults.repeat_penalty);
+        assert_eq!(params.seed, defaults.seed);
+
+        let body = json!({
+            "messages": [{"role": "user", "content": "go"}],
+            "max_completion_tokens": 2,
+        });
+        let resp = call_service(&app, post("/v1/chat/completions", body).to_request()).await;
+        let resp: Value = read_body_json(resp).await;
+        assert_eq!(resp["choices"][0]["message"]["content"], "ab");
+        assert_eq!(resp["choices"][0]["finish_reason"], "length");
+    }
+
+    /// Split a server-sent events body in the data of its events.
+    fn events(body: &[u8]) -> Vec<String> {
+        let body = std::str::from_utf8(body).unwrap();
+        assert!(body.ends_with("\n\n"), "{body:?}");
+        body.trim_end_matches("\n\n")
+            .split("\n\n")
+            .map(|event| event.strip_prefix("data: ").unwrap().to_string())
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn completions_are_streamed_as_chunks() {
+        let checkpoint = Checkpoint::new("api-stream");
+        let (state, defaults) = state(&checkpoint, ScriptedModel::new(&["Hello", " world"]));
+        let app = init_service(App::new().configure(|cfg| configure(cfg, state, defaults))).await;
+
+        let body = json!({
+            "messages": [{"role": "user", "content": "say hello"}],
+            "stream": true,
+            "stream_options": {"include_usage": true},
+        });
+        let resp = call_service(&app, post("/api/v1/chat/completions", body).to_request()).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(
+            resp.headers().get("content-type").unwrap(),
+            "text/event-stream"
+        );
+
+        let events = events(&read_body(resp).await);
+        assert_eq!(events.last().unwrap(), "[DONE]");
+        let chunks: Vec<Value> = events[..events.len() - 1]
+            .iter()
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect();
+        let id = chunks[0]["id"].as_str().unwrap();
+        assert!(id.starts_with("chatcmpl-"));
+        for chunk in &chunks {
+            assert_eq!(chunk["id"], id);
+            assert_eq!(chunk["object"], "chat.completion.chunk");
+            assert_eq!(chunk["model"], ScriptedModel::MODEL_NAME);
+            assert_eq!(chunk["created"], chunks[0]["created"]);
+        }
+
+        let choices: Vec<&Value> = chunks.iter().map(|chunk| &chunk["choices"]).collect();
+        assert_eq!(
+            choices,
+            [
+                &json!([{"index": 0, "delta": {"role": "assistant", "content": ""}, "finish_reason": null}]),
+                &json!([{"index": 0, "delta": {"content": "Hello"}, "finish_reason": null}]),
+                &json!([{"index": 0, "delta": {"content": " world"}, "finish_reason": null}]),
+                &json!([{"index": 0, "delta": {}, "finish_reason": "stop"}]),
+                &json!([]),
+            ]
+        );
+        // only the last chunk has the usage
+        assert!(chunks[..4].iter().all(|chunk| chunk.get("usage").is_none()));
+        assert_eq!(
+            chunks[4]["usage"],
+            json!({"prompt_tokens": 2, "completion_tokens": 3, "total_tokens": 5})
+        );
+    }
+
+    #[actix_web::test]
+    async fn disconnected_clients_stop_the_generation() {
+        let checkpoint = Checkpoint::new("api-disconnect");
+        let mut model = ScriptedModel::new(&["more"]);
+        model.endless = true;
+        let (state, defaults) = state(&checkpoint, model);
+
+        let request: ChatRequest = serde_json::from_value(json!({
+            "messages": [{"role": "user", "content": "never stop"}],
+            "stream": true,
+            "max_tokens": 100000,
+        }))
+        .unwrap();
+        let params = request.params(&defaults).unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let generation = actix_web::rt::spawn(chat_stream(
+            state.clone(),
+            "test".to_string(),
+            request,
+            params,
+            tx,
+        ));
+
+        // wait for a few tokens, then disconnect
+        for _ in 0..4 {
+            rx.recv().await.unwrap().unwrap();
+        }
+        drop(rx);
+
+        tokio::time::timeout(std::time::Duration::from_secs(10), generation)
+            .await
+            .expect("the generation didn't stop")
+            .unwrap();
+        let generated = state.lock().await.model.generated_tokens();
+        assert!(
+            (3..100).contains(&generated),
+            "{generated} tokens generated"
+        );
+    }
+
+    #[actix_web::test]
+    async fn invalid_requests_are_rejected() {
+        let checkpoint = Checkpoint::new("api-invalid");
+        let (state, defaults) = state(&checkpoint, ScriptedModel::new(&["Hello"]));
+        let app =
+            init_service(App::new().configure(|cfg| configure(cfg, state.clone(), defaults))).await;
+
+        let user = json!([{"role": "user", "content": "hi"}]);
+        for body in [
+            json!({"messages": []}),
+            json!({"messages": user, "temperature": -1.0}),
+            json!({"messages": user, "top_p": 0.0}),
+            json!({"messages": user, "top_k": 0}),
+            json!({"messages": user, "max_tokens": 0}),
+            json!({"messages": user, "stop": ["end", ""]}),
+        ] {
+            let resp = call_service(
+                &app,
+                post("/v1/chat/completions", body.clone()).to_request(),
+            )
+            .await;
+            assert_eq!(resp.status(), 400, "{body}");
+            let resp: Value = read_body_json(resp).await;
+            assert_eq!(resp["error"]["type"], "invalid_request_error", "{body}");
+            assert!(!resp["error"]["message"].as_str().unwrap().is_empty());
+        }
+
+        // malformed bodies are refused by the json extractor
+        let resp = call_service(
+            &app,
+            post("/v1/chat/completions", json!({"messages": "hi"})).to_request(),
+        )
+        .await;
+        assert!(resp.status().is_client_error());
+
+        // nothing reached the model
+        assert!(state.lock().await.model.params.is_none());
+    }
+}
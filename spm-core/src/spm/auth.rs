@@ -0,0 +1,444 @@
+//! Authentication of the connections between the master and the workers.
+//!
+//! Workers can serve TLS with their own certificate, which the master pins for every node in
+//! the topology file, and they can require the master to prove it knows a pre-shared key by
+//! answering an HMAC challenge right after the Hello message.
+use std::{fmt::Debug, path::Path, sync::Arc};
+
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    rustls::{
+        self,
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{ring, CryptoProvider},
+        pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+        ClientConfig, DigitallySignedStruct, ServerConfig, SignatureScheme,
+    },
+    TlsAcceptor, TlsConnector,
+};
+
+use super::Node;
+use crate::Args;
+
+/// Size in bytes of the random challenge sent by workers.
+const CHALLENGE_SIZE: usize = 32;
+
+/// A connection between master and worker, either plain TCP or TLS.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send + Sync + Debug {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync + Debug> Stream for T {}
+
+/// Credentials used by the master to connect to a worker.
+#[derive(Clone, Default)]
+pub struct Auth {
+    /// Pinned worker certificate, if set the connection uses TLS.
+    cert: Option<CertificateDer<'static>>,
+    /// Pre-shared key used to answer the worker challenge.
+    psk: Option<Vec<u8>>,
+}
+
+impl Debug for Auth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // never print the key
+        f.debug_struct("Auth")
+            .field("tls", &self.cert.is_some())
+            .field("psk", &self.psk.is_some())
+            .finish()
+    }
+}
+
+impl Auth {
+    /// Load the pre-shared key from the command line arguments, if any.
+    pub fn from_args(args: &Args) -> Result<Self> {
+        let psk = match &args.psk_file {
+            Some(path) => Some(read_psk(path)?),
+            None => None,
+        };
+        Ok(Self { cert: None, psk })
+    }
+
+    /// Return the credentials to connect to the node, pinning its certificate if configured.
+    pub fn for_node(&self, node: &Node) -> Result<Self> {
+        let cert = match &node.cert {
+            Some(path) => Some(
+                load_certs(path)?
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| anyhow!("no certificate found in {path}"))?,
+            ),
+            None => None,
+        };
+        Ok(Self {
+            cert,
+            psk: self.psk.clone(),
+        })
+    }
+
+    /// Connect to the worker address, using TLS if its certificate is pinned.
+    pub async fn connect(&self, address: &str) -> Result<Box<dyn Stream>> {
+        let stream = TcpStream::connect(address)
+            .await
+            .map_err(|e| anyhow!("can't connect to {address}: {e}"))?;
+
+        let cert = match &self.cert {
+            Some(cert) => cert.clone(),
+            None => return Ok(Box::new(stream)),
+        };
+
+        let provider = Arc::new(ring::default_provider());
+        let config = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier { cert, provider }))
+            .with_no_client_auth();
+
+        // the certificate is pinned, so the name is only used for SNI
+        let host = address.rsplit_once(':').map(|(h, _)| h).unwrap_or(address);
+        let server_name = ServerName::try_from(host.to_string())
+            .unwrap_or_else(|_| ServerName::try_from("localhost").unwrap());
+
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(server_name, stream)
+            .await
+            .map_err(|e| anyhow!("tls handshake with {address} failed: {e}"))?;
+
+        Ok(Box::new(stream))
+    }
+
+    /// Return the response to a worker challenge.
+    pub fn respond(&self, challenge: &[u8]) -> Result<Vec<u8>> {
+        match &self.psk {
+            Some(psk) => Ok(hmac(psk, challenge).finalize().into_bytes().to_vec()),
+            None => bail!("no --psk-file provided"),
+        }
+    }
+}
+
+/// Authentication required by a worker.
+pub struct WorkerAuth {
+    tls: Option<TlsAcceptor>,
+    psk: Option<Vec<u8>>,
+}
+
+impl WorkerAuth {
+    /// Load the worker certificate, key and pre-shared key from the command line arguments.
+    pub fn from_args(args: &Args) -> Result<Self> {
+        let tls = match (&args.tls_cert, &args.tls_key) {
+            (Some(cert), Some(key)) => {
+                let config =
+                    ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                        .with_safe_default_protocol_versions()?
+                        .with_no_client_auth()
+                        .with_single_cert(load_certs(cert)?, load_key(key)?)
+                        .map_err(|e| anyhow!("invalid certificate or key: {e}"))?;
+                Some(TlsAcceptor::from(Arc::new(config)))
+            }
+            (None, None) => None,
+            _ => bail!("--tls-cert and --tls-key must be provided together"),
+        };
+        let psk = match &args.psk_file {
+            Some(path) => Some(read_psk(path)?),
+            None => None,
+        };
+        Ok(Self { tls, psk })
+    }
+
+    /// Return a description of the authentication methods in use.
+    pub fn describe(&self) -> String {
+        match (self.tls.is_some(), self.psk.is_some()) {
+            (true, true) => "tls+psk",
+            (true, false) => "tls",
+            (false, true) => "psk",
+            (false, false) => "none",
+        }
+        .to_string()
+    }
+
+    /// Accept a master connection, performing the TLS handshake if enabled.
+    pub async fn accept(&self, socket: TcpStream) -> Result<Box<dyn Stream>> {
+        match &self.tls {
+            Some(acceptor) => Ok(Box::new(
+                acceptor
+                    .accept(socket)
+                    .await
+                    .map_err(|e| anyhow!("tls handshake failed: {e}"))?,
+            )),
+            None => Ok(Box::new(socket)),
+        }
+    }
+
+    /// Return a new random challenge if the master must prove it knows the pre-shared key.
+    pub fn challenge(&self) -> Option<Vec<u8>> {
+        self.psk.as_ref().map(|_| {
+            let mut challenge = vec![0u8; CHALLENGE_SIZE];
+            rand::thread_rng().fill_bytes(&mut challenge);
+            challenge
+        })
+    }
+
+    /// Return true if the response to the challenge is valid.
+    pub fn verify(&self, challenge: &[u8], response: &[u8]) -> bool {
+        match &self.psk {
+            // constant time comparison
+            Some(psk) => hmac(psk, challenge).verify_slice(response).is_ok(),
+            None => true,
+        }
+    }
+}
+
+fn hmac(key: &[u8], challenge: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts keys of any size");
+    mac.update(b"spm-auth");
+    mac.update(challenge);
+    mac
+}
+
+fn read_psk(path: &str) -> Result<Vec<u8>> {
+    let data = std::fs::read(path).map_err(|e| anyhow!("can't read {path}: {e}"))?;
+    let psk = data.trim_ascii().to_vec();
+    if psk.is_empty() {
+        bail!("pre-shared key in {path} is empty");
+    }
+    Ok(psk)
+}
+
+fn load_certs(path: impl AsRef<Path>) -> Result<Vec<CertificateDer<'static>>> {
+    let path = path.as_ref();
+    let data = std::fs::read(path).map_err(|e| anyhow!("can't read {}: {e}", path.display()))?;
+    rustls_pemfile::certs(&mut data.as_slice())
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| anyhow!("can't parse {}: {e}", path.display()))
+}
+
+fn load_key(path: impl AsRef<Path>) -> Result<PrivateKeyDer<'static>> {
+    let path = path.as_ref();
+    let data = std::fs::read(path).map_err(|e| anyhow!("can't read {}: {e}", path.display()))?;
+    rustls_pemfile::private_key(&mut data.as_slice())
+        .map_err(|e| anyhow!("can't parse {}: {e}", path.display()))?
+        .ok_or_else(|| anyhow!("no private key found in {}", path.display()))
+}
+
+/// Accepts only the exact certificate configured for the node, this allows self-signed
+/// certificates without a certificate authority.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    cert: CertificateDer<'static>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        if end_entity.as_ref() == self.cert.as_ref() {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+
+    use super::*;
+    use crate::spm::{stand_in, Client};
+
+    /// Directory with a self-signed certificate, its key and a pre-shared key for a worker.
+    struct Credentials {
+        dir: PathBuf,
+        cert: CertificateDer<'static>,
+    }
+
+    impl Credentials {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("spm-auth-{name}-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+
+            let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+            std::fs::write(dir.join("cert.pem"), cert.cert.pem()).unwrap();
+            std::fs::write(dir.join("key.pem"), cert.key_pair.serialize_pem()).unwrap();
+            std::fs::write(dir.join("psk"), "worker secret\n").unwrap();
+
+            Self {
+                dir,
+                cert: cert.cert.der().clone(),
+            }
+        }
+
+        fn path(&self, name: &str) -> Option<String> {
+            Some(self.dir.join(name).display().to_string())
+        }
+
+        fn worker_auth(&self) -> WorkerAuth {
+            WorkerAuth::from_args(&Args {
+                tls_cert: self.path("cert.pem"),
+                tls_key: self.path("key.pem"),
+                psk_file: self.path("psk"),
+                ..Default::default()
+            })
+            .unwrap()
+        }
+    }
+
+    impl Drop for Credentials {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    async fn connect(address: &str, auth: &Auth) -> Result<Client> {
+        Client::new(
+            candle_core::Device::Cpu,
+            address,
+            "probe",
+            &stand_in::hello(),
+            auth,
+            &stand_in::client_options(),
+        )
+        .await
+    }
+
+    #[test]
+    fn hmac_depends_on_key_and_challenge() {
+        let worker = WorkerAuth {
+            tls: None,
+            psk: Some(b"secret".to_vec()),
+        };
+        let challenge = worker.challenge().unwrap();
+        assert_eq!(challenge.len(), CHALLENGE_SIZE);
+        assert_ne!(challenge, worker.challenge().unwrap());
+
+        let master = Auth {
+            cert: None,
+            psk: Some(b"secret".to_vec()),
+        };
+        let response = master.respond(&challenge).unwrap();
+        assert!(worker.verify(&challenge, &response));
+        assert!(!worker.verify(&worker.challenge().unwrap(), &response));
+
+        let wrong = Auth {
+            cert: None,
+            psk: Some(b"wrong".to_vec()),
+        };
+        assert!(!worker.verify(&challenge, &wrong.respond(&challenge).unwrap()));
+        assert!(Auth::default().respond(&challenge).is_err());
+    }
+
+    #[tokio::test]
+    async fn pinned_certificate_is_accepted() {
+        let credentials = Credentials::new("pinned");
+        let address = stand_in::spawn_with_auth(1, Duration::ZERO, credentials.worker_auth())
+            .await
+            .unwrap();
+
+        let auth = Auth {
+            cert: Some(credentials.cert.clone()),
+            psk: Some(b"worker secret".to_vec()),
+        };
+        let mut client = connect(&address, &auth).await.unwrap();
+
+        let x = candle_core::Tensor::zeros(
+            (1, 1, stand_in::HIDDEN_SIZE),
+            candle_core::DType::F32,
+            &candle_core::Device::Cpu,
+        )
+        .unwrap();
+        let (result, _) = client.probe(&x, 1).await.unwrap();
+        assert_eq!(result.memory, 1);
+    }
+
+    #[tokio::test]
+    async fn wrong_pin_is_rejected() {
+        let credentials = Credentials::new("wrong-pin");
+        let other = Credentials::new("other");
+        let address = stand_in::spawn_with_auth(1, Duration::ZERO, credentials.worker_auth())
+            .await
+            .unwrap();
+
+        let auth = Auth {
+            cert: Some(other.cert.clone()),
+            psk: Some(b"worker secret".to_vec()),
+        };
+        let err = connect(&address, &auth).await.unwrap_err();
+        assert!(err.to_string().contains("tls handshake"), "{err}");
+
+        // plain TCP doesn't get past the worker TLS handshake either
+        let auth = Auth {
+            cert: None,
+            psk: Some(b"worker secret".to_vec()),
+        };
+        assert!(connect(&address, &auth).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn wrong_psk_fails_the_challenge() {
+        let credentials = Credentials::new("wrong-psk");
+        let address = stand_in::spawn_with_auth(1, Duration::ZERO, credentials.worker_auth())
+            .await
+            .unwrap();
+
+        let auth = Auth {
+            cert: Some(credentials.cert.clone()),
+            psk: Some(b"wrong secret".to_vec()),
+        };
+        let err = connect(&address, &auth).await.unwrap_err();
+        assert!(err.to_string().contains("authentication failed"), "{err}");
+
+        let auth = Auth {
+            cert: Some(credentials.cert.clone()),
+            psk: None,
+        };
+        let err = connect(&address, &auth).await.unwrap_err();
+        assert!(err.to_string().contains("requires authentication"), "{err}");
+    }
+}
@@ -1,11 +1,104 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
 use anyhow::Result;
 use async_trait::async_trait;
 use candle_core::{Device, Tensor};
-use tokio::net::TcpStream;
+use tokio::{sync::Mutex, task::JoinHandle};
+
+use crate::{
+    models::llama3::{Cache, Config},
+    utils::Weights,
+    Args,
+};
+
+use super::{Auth, ErrorCode, Hello, Message, ProbeResult, RawTensor, Stream, WorkerInfo};
+
+/// Wait time after the first failed reconnection attempt, doubled after every attempt.
+const RECONNECT_BACKOFF: Duration = Duration::from_millis(500);
+/// Max wait time between reconnection attempts.
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(8);
+
+/// An error reported by a worker while processing a request.
+#[derive(Debug, Clone)]
+pub struct WorkerError {
+    /// Worker address.
+    pub worker: String,
+    /// The layer that failed, if the error is specific to one.
+    pub layer_name: Option<String>,
+    /// Error code.
+    pub code: ErrorCode,
+    /// Error message.
+    pub message: String,
+}
+
+impl std::fmt::Display for WorkerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "worker {}", &self.worker)?;
+        if let Some(layer_name) = &self.layer_name {
+            write!(f, " layer {}", layer_name)?;
+        }
+        write!(f, ": {}: {}", self.code, &self.message)
+    }
+}
 
-use crate::models::llama3::{Cache, Config};
+impl std::error::Error for WorkerError {}
 
-use super::{Message, WorkerInfo};
+/// Returned when the connection to a worker has been reestablished after a failure, the
+/// kv-cache of the connection is lost and the request must be repeated from the first token.
+#[derive(Debug, Clone)]
+pub struct Reconnected {
+    /// Worker address.
+    pub worker: String,
+}
+
+impl std::fmt::Display for Reconnected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "reconnected to worker {}, its kv-cache has been lost",
+            &self.worker
+        )
+    }
+}
+
+impl std::error::Error for Reconnected {}
+
+/// Connection settings of the clients.
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    /// Max time to wait for the response to a request.
+    pub timeout: Duration,
+    /// Interval between heartbeats sent on idle connections.
+    pub heartbeat: Option<Duration>,
+    /// Number of attempts to reconnect to a worker before giving up.
+    pub reconnect_attempts: usize,
+}
+
+impl ClientOptions {
+    /// Create the options from the command line arguments.
+    pub fn from_args(args: &Args) -> Self {
+        Self {
+            timeout: Duration::from_secs(args.request_timeout),
+            heartbeat: if args.heartbeat > 0 {
+                Some(Duration::from_secs(args.heartbeat))
+            } else {
+                None
+            },
+            reconnect_attempts: args.reconnect_attempts,
+        }
+    }
+}
+
+/// The connection to a worker, shared with the heartbeat task.
+#[derive(Debug)]
+struct Connection {
+    /// None if the connection has been lost.
+    stream: Option<Box<dyn Stream>>,
+    last_used: Instant,
+}
 
 /// A client object used by the master to connect and orchestrate the workers.
 /// From the spm perspective, each worker is a server and the master uses
@@ -15,56 +108,308 @@ pub struct Client {
     device: Device,
     address: String,
     layer_name: String,
-    stream: TcpStream,
+    hello: Hello,
+    auth: Auth,
+    options: ClientOptions,
+    conn: Arc<Mutex<Connection>>,
     info: WorkerInfo,
+    heartbeat: Option<JoinHandle<()>>,
 }
 
 impl Client {
-    /// Connects to the given worker address.
+    /// Connects to the given worker address and performs the handshake.
     /// NOTE: device and layer_name here are only passed for std::fmt::Display.
-    pub async fn new(device: Device, address: &str, layer_name: &str) -> Result<Self> {
-        let address = address.to_string();
-        let layer_name = layer_name.to_string();
-        let stream = TcpStream::connect(&address)
-            .await
-            .map_err(|e| anyhow!("can't connect to {address}: {e}"))?;
-        let worker_info = WorkerInfo::default();
-
+    pub async fn new(
+        device: Device,
+        address: &str,
+        layer_name: &str,
+        hello: &Hello,
+        auth: &Auth,
+        options: &ClientOptions,
+    ) -> Result<Self> {
         let mut client = Self {
-            address,
+            address: address.to_string(),
             device,
-            stream,
-            layer_name,
-            info: worker_info,
+            layer_name: layer_name.to_string(),
+            hello: hello.clone(),
+            auth: auth.clone(),
+            options: options.clone(),
+            conn: Arc::new(Mutex::new(Connection {
+                stream: None,
+                last_used: Instant::now(),
+            })),
+            info: WorkerInfo::default(),
+            heartbeat: None,
         };
 
-        let resp = client.request(Message::Hello).await?;
-        client.info = if let Message::WorkerInfo(info) = resp {
+        client.connect().await?;
+
+        if let Some(interval) = client.options.heartbeat {
+            if client.supports("heartbeat") {
+                client.heartbeat = Some(tokio::spawn(heartbeat(
+                    client.conn.clone(),
+                    client.address.clone(),
+                    interval,
+                    client.options.timeout,
+                )));
+            }
+        }
+
+        Ok(client)
+    }
+
+    /// Connect to the worker and perform the handshake.
+    async fn connect(&mut self) -> Result<()> {
+        let mut stream = self.auth.connect(&self.address).await?;
+        let timeout = self.options.timeout;
+
+        let mut resp = exchange(
+            &mut stream,
+            &Message::Hello(self.hello.clone()),
+            &self.address,
+            timeout,
+        )
+        .await?;
+        if let Message::Challenge(challenge) = &resp {
+            let response = self
+                .auth
+                .respond(challenge)
+                .map_err(|e| anyhow!("worker {} requires authentication: {e}", &self.address))?;
+            resp = exchange(
+                &mut stream,
+                &Message::Auth(response),
+                &self.address,
+                timeout,
+            )
+            .await?;
+        }
+
+        let info = if let Message::WorkerInfo(info) = resp {
             info
         } else {
-            return Err(anyhow!("unexpected worker info message: {:?}", &resp));
+            return Err(anyhow!("unexpected worker info message: {}", resp.name()));
         };
 
-        Ok(client)
+        if info.version != self.hello.version {
+            return Err(anyhow!(
+                "worker {} uses protocol version {}, expected {}",
+                &self.address,
+                info.version,
+                self.hello.version
+            ));
+        }
+
+        // a worker restarted with different weights can't take over the previous connection
+        if !self.info.fingerprint.is_empty() && info.fingerprint != self.info.fingerprint {
+            return Err(anyhow!(
+                "worker {} now serves a different model",
+                &self.address
+            ));
+        }
+
+        if info.compression != self.hello.compression {
+            log::warn!(
+                "worker {} doesn't support {:?} compression, using {:?}",
+                &self.address,
+                self.hello.compression,
+                info.compression
+            );
+        }
+
+        self.info = info;
+        *self.conn.lock().await = Connection {
+            stream: Some(stream),
+            last_used: Instant::now(),
+        };
+
+        Ok(())
     }
 
-    /// Send a Message to the worker and return a response.
+    /// Try to connect again to the worker, waiting longer after every failed attempt.
+    async fn reconnect(&mut self) -> Result<()> {
+        let mut backoff = RECONNECT_BACKOFF;
+        for attempt in 1..=self.options.reconnect_attempts {
+            match self.connect().await {
+                Ok(()) => {
+                    log::info!("reconnected to {}", &self.address);
+                    return Ok(());
+                }
+                Err(e) => {
+                    log::warn!(
+                        "reconnection to {} failed ({attempt}/{}): {e}",
+                        &self.address,
+                        self.options.reconnect_attempts
+                    );
+                    if attempt < self.options.reconnect_attempts {
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+                    }
+                }
+            }
+        }
+
+        Err(anyhow!(
+            "can't reconnect to {} after {} attempts",
+            &self.address,
+            self.options.reconnect_attempts
+        ))
+    }
+
+    /// Return the information the worker sent during the handshake.
+    pub fn info(&self) -> &WorkerInfo {
+        &self.info
+    }
+
+    /// Return true if the optional protocol feature has been agreed with the worker.
+    pub fn supports(&self, capability: &str) -> bool {
+        self.info.capabilities.iter().any(|c| c == capability)
+    }
+
+    /// Ask the worker to measure its capacity running a layer on x, return the result with
+    /// the time the whole request took.
+    pub async fn probe(&mut self, x: &Tensor, iterations: usize) -> Result<(ProbeResult, Duration)> {
+        if !self.supports("probe") {
+            bail!("worker {} doesn't support probing", &self.address);
+        }
+
+        let x = RawTensor::from_tensor(x, self.info.compression)?;
+        let start = Instant::now();
+        match self.request(Message::Probe { x, iterations }).await? {
+            Message::ProbeResult(result) => Ok((result, start.elapsed())),
+            resp => Err(anyhow!("unexpected response {}", resp.name())),
+        }
+    }
+
+    /// Send a Message to the worker and return a response, errors reported by the worker
+    /// are returned as WorkerError. If the connection fails the client reconnects and
+    /// returns Reconnected.
     async fn request(&mut self, req: Message) -> Result<Message> {
-        req.to_writer(&mut self.stream)
-            .await
-            .map_err(|e| anyhow!("error sending message {:?}: {}", req, e))?;
+        let res = {
+            let mut conn = self.conn.lock().await;
+            let res = match conn.stream.as_mut() {
+                Some(stream) => exchange(stream, &req, &self.address, self.options.timeout).await,
+                None => Err(anyhow!("connection to {} lost", &self.address)),
+            };
+            conn.last_used = Instant::now();
+            res
+        };
 
-        let (_, msg) = super::Message::from_reader(&mut self.stream)
-            .await
-            .map_err(|e| anyhow!("error receiving response for {:?}: {}", req, e))?;
-        Ok(msg)
+        match res {
+            Err(e) if !e.is::<WorkerError>() => {
+                log::warn!("{e}, reconnecting ...");
+                self.conn.lock().await.stream = None;
+                self.reconnect().await?;
+                Err(Reconnected {
+                    worker: self.address.clone(),
+                }
+                .into())
+            }
+            res => res,
+        }
     }
 
     async fn forward_request(&mut self, req: Message) -> Result<Tensor> {
         let resp = self.request(req).await?;
         match resp {
             Message::Tensor(raw) => Ok(raw.to_tensor(&self.device)?),
-            _ => Err(anyhow!("unexpected response {:?}", &resp)),
+            _ => Err(anyhow!("unexpected response {}", resp.name())),
+        }
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        if let Some(heartbeat) = &self.heartbeat {
+            heartbeat.abort();
+        }
+    }
+}
+
+/// Send a Message on the stream and return the response within the timeout.
+async fn exchange(
+    stream: &mut Box<dyn Stream>,
+    req: &Message,
+    address: &str,
+    timeout: Duration,
+) -> Result<Message> {
+    let res: Result<Message> = tokio::time::timeout(timeout, async {
+        req.to_writer(stream)
+            .await
+            .map_err(|e| anyhow!("error sending {} message to {}: {}", req.name(), address, e))?;
+
+        let (_, msg) = Message::from_reader(stream).await.map_err(|e| {
+            anyhow!(
+                "error receiving response for {} from {}: {}",
+                req.name(),
+                address,
+                e
+            )
+        })?;
+
+        Ok(msg)
+    })
+    .await
+    .map_err(|_| {
+        anyhow!(
+            "timeout waiting for the response to {} from {}",
+            req.name(),
+            address
+        )
+    })?;
+
+    match res? {
+        Message::Error {
+            code,
+            message,
+            layer_name,
+        } => Err(WorkerError {
+            worker: address.to_string(),
+            layer_name,
+            code,
+            message,
+        }
+        .into()),
+        msg => Ok(msg),
+    }
+}
+
+/// Periodically ping the worker while the connection is idle, so that a lost connection is
+/// detected and restored before the next request.
+async fn heartbeat(
+    conn: Arc<Mutex<Connection>>,
+    address: String,
+    interval: Duration,
+    timeout: Duration,
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+
+        // a request in progress holds the connection, which isn't idle then
+        let mut conn = match conn.try_lock() {
+            Ok(conn) => conn,
+            Err(_) => continue,
+        };
+        if conn.last_used.elapsed() < interval {
+            continue;
+        }
+        let stream = match conn.stream.as_mut() {
+            Some(stream) => stream,
+            None => continue,
+        };
+
+        match exchange(stream, &Message::Ping, &address, timeout).await {
+            Ok(Message::Pong) => conn.last_used = Instant::now(),
+            Ok(resp) => {
+                log::warn!(
+                    "unexpected heartbeat response {} from {address}",
+                    resp.name()
+                );
+                conn.stream = None;
+            }
+            Err(e) => {
+                log::warn!("heartbeat failed: {e}");
+                conn.stream = None;
+            }
         }
     }
 }
@@ -73,21 +418,25 @@ impl std::fmt::Display for Client {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "{}@{} [{}<{}> {}-{} latency={}ms]",
+            "{}@{} [{}<{}> {}-{} latency={}ms{}]",
             &self.layer_name,
             &self.address,
             &self.info.device,
             &self.info.device_idx,
             &self.info.os,
             &self.info.arch,
-            self.info.latency
+            self.info.latency,
+            match &self.info.quantization {
+                Some(quantization) => format!(" quantization={quantization}"),
+                None => String::new(),
+            }
         )
     }
 }
 
 #[async_trait]
 impl super::Forwarder for Client {
-    fn load(_: String, _: candle_nn::VarBuilder, _: &Config) -> Result<Box<Self>> {
+    fn load(_: String, _: Weights, _: &Config) -> Result<Box<Self>> {
         Err(anyhow!("load should never be called on spm::Client"))
     }
 
@@ -110,7 +459,8 @@ impl super::Forwarder for Client {
             x,
             index_pos,
             block_idx,
-        ))
+            self.info.compression,
+        )?)
         .await
     }
 
@@ -121,10 +471,24 @@ impl super::Forwarder for Client {
         batch: Vec<(String, usize, usize)>,
         _: &mut Cache,
     ) -> Result<Tensor> {
-        self.forward_request(super::Message::from_batch(x, batch))
+        self.forward_request(super::Message::from_batch(x, batch, self.info.compression)?)
             .await
     }
 
+    /// Clears the kv-cache of this connection on the worker.
+    async fn reset_cache(&mut self) -> Result<()> {
+        if !self.supports("reset_cache") {
+            return Err(anyhow!("worker {} can't reset its kv-cache", &self.address));
+        }
+        match self.request(Message::ResetCache).await {
+            Ok(Message::Ok) => Ok(()),
+            // the new connection starts with an empty cache
+            Err(e) if e.is::<Reconnected>() => Ok(()),
+            Ok(resp) => Err(anyhow!("unexpected response {}", resp.name())),
+            Err(e) => Err(e),
+        }
+    }
+
     fn ident(&self) -> &str {
         &self.address
     }
@@ -133,3 +497,134 @@ impl super::Forwarder for Client {
         &self.layer_name
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::DType;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::spm::{stand_in, Forwarder};
+
+    fn options(timeout: Duration, heartbeat: Option<Duration>) -> ClientOptions {
+        ClientOptions {
+            timeout,
+            heartbeat,
+            reconnect_attempts: 5,
+        }
+    }
+
+    async fn connect(address: &str, options: &ClientOptions) -> Result<Client> {
+        Client::new(
+            Device::Cpu,
+            address,
+            crate::spm::PROBE_LAYER,
+            &stand_in::hello(),
+            &Auth::default(),
+            options,
+        )
+        .await
+    }
+
+    /// Run the stand-in layer on the positions from index_pos.
+    async fn forward(client: &mut Client, index_pos: usize) -> Result<Tensor> {
+        let x = Tensor::zeros((1, 1, stand_in::HIDDEN_SIZE), DType::F32, &Device::Cpu)?;
+        let mut cache = stand_in::cache()?;
+        client.forward_mut(&x, index_pos, 0, &mut cache).await
+    }
+
+    #[tokio::test]
+    async fn hung_workers_time_out() {
+        // accepts connections and never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut sockets = vec![];
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+
+        let timeout = Duration::from_millis(200);
+        let start = Instant::now();
+        let err = connect(&address, &options(timeout, None))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("timeout waiting"), "{err}");
+        assert!(start.elapsed() < timeout * 5);
+
+        // a forward pass longer than the timeout is given up and the connection restored
+        let address = stand_in::spawn(1, Duration::from_secs(60)).await.unwrap();
+        let mut client = connect(&address, &options(timeout, None)).await.unwrap();
+        let start = Instant::now();
+        let err = forward(&mut client, 0).await.unwrap_err();
+        assert!(err.is::<Reconnected>(), "{err}");
+        assert!(start.elapsed() < timeout * 5);
+    }
+
+    #[tokio::test]
+    async fn restarted_workers_are_reconnected() {
+        let server = stand_in::serve("127.0.0.1:0", Duration::ZERO)
+            .await
+            .unwrap();
+        let address = server.address.clone();
+        let mut client = connect(&address, &options(Duration::from_secs(10), None))
+            .await
+            .unwrap();
+        forward(&mut client, 0).await.unwrap();
+        forward(&mut client, 1).await.unwrap();
+
+        server.stop().await;
+        let restarted = {
+            let address = address.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(RECONNECT_BACKOFF + RECONNECT_BACKOFF / 2).await;
+                stand_in::serve(&address, Duration::ZERO).await.unwrap()
+            })
+        };
+
+        // the worker is down at the first attempt, it's back after some backoff
+        let start = Instant::now();
+        let err = forward(&mut client, 2).await.unwrap_err();
+        assert!(err.is::<Reconnected>(), "{err}");
+        assert!(start.elapsed() >= RECONNECT_BACKOFF);
+
+        // the new connection has an empty kv-cache, the sequence must start over
+        let err = forward(&mut client, 2).await.unwrap_err();
+        let err = err.downcast::<WorkerError>().unwrap();
+        assert_eq!(err.code, ErrorCode::Forward);
+        forward(&mut client, 0).await.unwrap();
+
+        restarted.await.unwrap().stop().await;
+        client.options.reconnect_attempts = 2;
+        let err = forward(&mut client, 1).await.unwrap_err();
+        assert!(err.to_string().contains("can't reconnect"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn heartbeats_detect_lost_connections() {
+        let server = stand_in::serve("127.0.0.1:0", Duration::ZERO)
+            .await
+            .unwrap();
+        let interval = Duration::from_millis(100);
+        let client = connect(
+            &server.address,
+            &options(Duration::from_secs(10), Some(interval)),
+        )
+        .await
+        .unwrap();
+
+        // idle connections are pinged
+        tokio::time::sleep(interval * 4).await;
+        {
+            let conn = client.conn.lock().await;
+            assert!(conn.stream.is_some());
+            assert!(conn.last_used.elapsed() < interval * 3);
+        }
+
+        // and dropped once the worker is gone
+        server.stop().await;
+        tokio::time::sleep(interval * 4).await;
+        assert!(client.conn.lock().await.stream.is_none());
+    }
+}
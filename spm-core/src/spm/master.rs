@@ -1,7 +1,11 @@
 use std::io::{self, Write};
 
-use crate::models::{chat::Message, Generator};
-use super::Context;
+use crate::models::{params::GenerationParams, FinishReason, Generator};
+use super::{
+    api,
+    repl::{Action, Repl},
+    Context,
+};
 use anyhow::Result;
 
 /// 主节点和工作节点连接，通信和协调
@@ -18,104 +22,508 @@ impl<G: Generator + Send + Sync + 'static> Master<G> {
     }
 
     pub async fn run(mut self) -> Result<()> {
-        loop {
-            println!("请输入问题（输入 'q' 退出）：");
-            let mut input = String::new();
-            io::stdin().read_line(&mut input).expect("读取输入失败");
+        if let Some(address) = self.ctx.args.api.clone() {
+            // run as OpenAI compatible REST api
+            return api::start(self, &address).await;
+        }
 
-            let input = input.trim().to_string();
+        let mut repl = Repl::new(self.ctx.args.system_prompt.clone(), self.default_params());
 
-            // 检查用户是否输入 'q' 以退出循环
-            if input == "q" {
+        loop {
+            println!("请输入问题（输入 'q' 退出，'/help' 查看命令）：");
+            let mut input = String::new();
+            if io::stdin().read_line(&mut input).expect("读取输入失败") == 0 {
+                // stdin closed
                 break;
             }
 
-            // 使用 Message::user 来创建用户消息
-            let message = Message::user(input);
+            match repl.handle(&input) {
+                Ok(Action::None) => {}
+                Ok(Action::Print(text)) => println!("{text}"),
+                Ok(Action::Reset) => match self.reset().await {
+                    Ok(()) => println!("conversation cleared"),
+                    Err(e) => println!("{e}"),
+                },
+                Ok(Action::Generate) => {
+                    // the kv-cache is kept for the previous turns
+                    self.model.reset()?;
+                    for message in &repl.chat {
+                        self.model.add_message(message.clone())?;
+                    }
 
-            self.model.reset()?;
-            self.model.add_message(message)?;
+                    // run one generation to stdout, keeping the reply for the next turns
+                    let mut reply = String::new();
+                    self.generate(&repl.params, |data| {
+                        if data.is_empty() {
+                            println!();
+                        } else {
+                            print!("{data}");
+                            reply += data;
+                        }
+                        io::stdout().flush().unwrap();
+                        Ok(())
+                    })
+                    .await?;
 
-            // just run one generation to stdout
-            self.generate(|data| {
-                if data.is_empty() {
-                    println!();
-                } else {
-                    print!("{data}");
+                    repl.add_reply(reply);
                 }
-                io::stdout().flush().unwrap();
-            })
-            .await?;
+                Ok(Action::Quit) => break,
+                Err(e) => println!("{e}"),
+            }
         }
 
         Ok(())
     }
 
-    /// Reset the master state for a new inference.
-    /// 原本是在api/mod.rs/Responder结构体中调用的
-    /// 该方法会清空分词结果、聊天历史和缓存，重置索引位置和生成的令牌数量
-    pub fn reset(&mut self) -> Result<()> {
-        self.model.reset()
+    /// Return the generation parameters set from the command line.
+    pub fn default_params(&self) -> GenerationParams {
+        GenerationParams::from(&self.ctx.args)
     }
 
-    /// Start the generation loop and call the stream function for every token.
-    pub async fn generate<S>(&mut self, mut stream: S) -> Result<()>
+    /// Reset the master state for a new conversation.
+    /// 该方法会清空聊天历史、生成的令牌数量以及主节点和所有工作节点上的kv缓存
+    pub async fn reset(&mut self) -> Result<()> {
+        self.model.reset()?;
+        self.model.clear_cache().await
+    }
+
+    /// Start the generation loop and call the stream function for every token, an error returned
+    /// by it stops the generation. Return the reason why the generation ended.
+    pub async fn generate<S>(
+        &mut self,
+        params: &GenerationParams,
+        mut stream: S,
+    ) -> Result<FinishReason>
     where
-        S: FnMut(&str),
+        S: FnMut(&str) -> Result<()>,
     {
         log::info!(
             "starting the inference loop (mem={})\n\n",
             human_bytes::human_bytes(memory_stats::memory_stats().unwrap().physical_mem as f64)
         );
 
-        log::debug!("  ctx.args.sample_len = {}", self.ctx.args.sample_len);
+        log::debug!("  params = {params}");
 
-        stream(&self.ctx.args.prompt);
+        self.model.set_params(params.clone())?;
 
         let mut start_gen = std::time::Instant::now();
+        let mut max_tokens = params.max_tokens;
+        let mut finish_reason = FinishReason::Length;
+        let mut stop = StopSequences::new(&params.stop);
 
-        // for index in 0..self.ctx.args.sample_len {
-        //     if index == 1 {
-        //         // record start time again since the first token is the warmup
-        //         start_gen = std::time::Instant::now()
-        //     }
-
-        //     let token = self.model.next_token(index).await?;
-        //     if token.is_end_of_stream {
-        //         break;
-        //     } else {
-        //         stream(&token.to_string());
-        //     }
-        // }
         let mut index = 0;
-        loop {
+        while index < max_tokens {
             if index == 1 {
                 // record start time again since the first token is the warmup
                 start_gen = std::time::Instant::now()
             }
 
             let token = self.model.next_token(index).await?;
+            if index == 0 {
+                // the prompt has been encoded by now, never go past the end of the context window
+                let available = self
+                    .model
+                    .max_sequence_length()
+                    .saturating_sub(self.model.prompt_tokens());
+                if available < max_tokens {
+                    log::debug!("  max_tokens limited to {available} by the context window");
+                    max_tokens = available;
+                }
+            }
+
             if token.is_end_of_stream {
+                finish_reason = FinishReason::Stop;
+                break;
+            }
+
+            let (text, stopped) = stop.push(&token.to_string());
+            if !text.is_empty() {
+                stream(&text)?;
+            }
+            if stopped {
+                finish_reason = FinishReason::Stop;
                 break;
-            } else {
-                stream(&token.to_string());
             }
             index += 1;
         }
 
+        // release the last tokens if they end with an incomplete character
+        if finish_reason == FinishReason::Length {
+            let (text, stopped) = stop.push(&self.model.flush_text()?);
+            if !text.is_empty() {
+                stream(&text)?;
+            }
+            if stopped {
+                finish_reason = FinishReason::Stop;
+            }
+        }
+
+        // release any text held back while matching stop sequences
+        let text = stop.flush();
+        if !text.is_empty() {
+            stream(&text)?;
+        }
+
         // signal end of stream
-        stream("");
+        stream("")?;
 
         let dt = start_gen.elapsed();
         let generated = self.model.generated_tokens();
 
         log::info!(
-            "{} tokens generated ({} token/s) - mem={}",
+            "{} tokens generated ({} token/s, finish_reason={}) - mem={}",
             generated,
             (generated - 1) as f64 / dt.as_secs_f64(),
+            finish_reason,
             human_bytes::human_bytes(memory_stats::memory_stats().unwrap().physical_mem as f64)
         );
 
-        Ok(())
+        Ok(finish_reason)
     }
-}
\ No newline at end of file
+}
+
+/// Matches stop sequences on the generated text.
+/// Text that could be the beginning of a stop sequence is held back until it can be
+/// either released or discarded, so that stop sequences never reach the stream.
+struct StopSequences<'a> {
+    sequences: &'a [String],
+    pending: String,
+}
+
+impl<'a> StopSequences<'a> {
+    fn new(sequences: &'a [String]) -> Self {
+        Self {
+            sequences,
+            pending: String::new(),
+        }
+    }
+
+    /// Add generated text, return the text that can be streamed and true if a stop sequence matched.
+    fn push(&mut self, text: &str) -> (String, bool) {
+        if self.sequences.is_empty() {
+            return (text.to_string(), false);
+        }
+
+        self.pending.push_str(text);
+
+        // text preceding the earliest match is released, the match and anything after it discarded
+        if let Some(pos) = self
+            .sequences
+            .iter()
+            .filter_map(|seq| self.pending.find(seq.as_str()))
+            .min()
+        {
+            let text = self.pending[..pos].to_string();
+            self.pending.clear();
+            return (text, true);
+        }
+
+        // hold back the longest suffix that is the beginning of a stop sequence
+        let held = self
+            .pending
+            .char_indices()
+            .map(|(idx, _)| idx)
+            .find(|&idx| {
+                let suffix = &self.pending[idx..];
+                self.sequences.iter().any(|seq| seq.starts_with(suffix))
+            })
+            .unwrap_or(self.pending.len());
+
+        let text = self.pending[..held].to_string();
+        self.pending.replace_range(..held, "");
+        (text, false)
+    }
+
+    /// Return any text still held back.
+    fn flush(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// Generator replaying the same tokens whatever the prompt, to test the master without a model.
+#[cfg(test)]
+pub(crate) mod scripted {
+    use async_trait::async_trait;
+    use clap::Parser;
+
+    use super::*;
+    use crate::{
+        models::{chat::Message, llama3::testing::Checkpoint, Token},
+        spm::worker::stand_in::StandInLayer,
+        Args,
+    };
+
+    pub struct ScriptedModel {
+        /// Text of the generated tokens, followed by an end of stream token.
+        pub tokens: Vec<&'static str>,
+        /// Repeat the tokens forever instead of ending the stream.
+        pub endless: bool,
+        /// Max number of tokens, prompt included.
+        pub max_sequence_length: usize,
+        /// Messages of the current chat.
+        pub messages: Vec<Message>,
+        /// Parameters of the last generation.
+        pub params: Option<GenerationParams>,
+        /// Number of words of the messages, set once the prompt is encoded.
+        pub prompt_tokens: usize,
+        /// Number of tokens generated for the current chat.
+        pub generated: usize,
+    }
+
+    impl ScriptedModel {
+        pub fn new(tokens: &[&'static str]) -> Self {
+            Self {
+                tokens: tokens.to_vec(),
+                endless: false,
+                max_sequence_length: 1024,
+                messages: vec![],
+                params: None,
+                prompt_tokens: 0,
+                generated: 0,
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Generator for ScriptedModel {
+        type Shardable = StandInLayer;
+        const MODEL_NAME: &'static str = "scripted";
+
+        async fn load(_context: Context) -> Result<Box<Self>> {
+            bail!("the scripted model can't be loaded")
+        }
+        fn add_message(&mut self, message: Message) -> Result<()> {
+            self.messages.push(message);
+            Ok(())
+        }
+        fn reset(&mut self) -> Result<()> {
+            self.messages.clear();
+            self.prompt_tokens = 0;
+            self.generated = 0;
+            Ok(())
+        }
+        async fn clear_cache(&mut self) -> Result<()> {
+            Ok(())
+        }
+        fn set_params(&mut self, params: GenerationParams) -> Result<()> {
+            params.validate()?;
+            self.params = Some(params);
+            Ok(())
+        }
+        async fn next_token(&mut self, index: usize) -> Result<Token> {
+            // give other tasks a chance to run, like a real model waiting for its workers
+            tokio::task::yield_now().await;
+            if index == 0 {
+                self.prompt_tokens = self
+                    .messages
+                    .iter()
+                    .map(|m| m.content.split_whitespace().count())
+                    .sum();
+            }
+            self.generated += 1;
+
+            let text = if self.endless {
+                self.tokens[index % self.tokens.len()]
+            } else if let Some(text) = self.tokens.get(index) {
+                text
+            } else {
+                return Ok(Token {
+                    id: 0,
+                    text: None,
+                    is_end_of_stream: true,
+                });
+            };
+            Ok(Token {
+                id: index as u32 + 1,
+                text: Some(text.to_string()),
+                is_end_of_stream: false,
+            })
+        }
+        fn flush_text(&mut self) -> Result<String> {
+            Ok(String::new())
+        }
+        fn generated_tokens(&self) -> usize {
+            self.generated
+        }
+        fn prompt_tokens(&self) -> usize {
+            self.prompt_tokens
+        }
+        fn max_sequence_length(&self) -> usize {
+            self.max_sequence_length
+        }
+        async fn perplexity(&mut self, _text: &str) -> Result<f64> {
+            bail!("the scripted model has no perplexity")
+        }
+    }
+
+    /// Return a master generating with the model, its context is loaded from the checkpoint.
+    pub fn master(checkpoint: &Checkpoint, model: ScriptedModel) -> Master<ScriptedModel> {
+        let topology = checkpoint.dir.join("topology.yml");
+        std::fs::write(&topology, "{}\n").unwrap();
+        let args = Args::parse_from([
+            "spm",
+            "--model",
+            checkpoint.dir.to_str().unwrap(),
+            "--topology",
+            topology.to_str().unwrap(),
+            "--dtype",
+            "f32",
+            "--cpu",
+        ]);
+        Master {
+            ctx: Context::from_args(args).unwrap(),
+            model: Box::new(model),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{chat::Message, llama3::testing::Checkpoint};
+    use scripted::ScriptedModel;
+
+    /// Run a generation for a prompt of four words, return its finish reason and streamed data.
+    async fn generate(
+        master: &mut Master<ScriptedModel>,
+        max_tokens: usize,
+    ) -> (FinishReason, Vec<String>) {
+        let mut params = master.default_params();
+        params.max_tokens = max_tokens;
+        master.model.reset().unwrap();
+        master
+            .model
+            .add_message(Message::user("one two three four".to_string()))
+            .unwrap();
+
+        let mut streamed = vec![];
+        let finish_reason = master
+            .generate(&params, |data| {
+                streamed.push(data.to_string());
+                Ok(())
+            })
+            .await
+            .unwrap();
+        (finish_reason, streamed)
+    }
+
+    #[tokio::test]
+    async fn generation_stops_at_end_of_stream() {
+        let checkpoint = Checkpoint::new("generate-stop");
+        let mut master = scripted::master(&checkpoint, ScriptedModel::new(&["a", "b"]));
+
+        let (finish_reason, streamed) = generate(&mut master, 10).await;
+        assert_eq!(finish_reason, FinishReason::Stop);
+        // the end of stream is signaled by empty data
+        assert_eq!(streamed, ["a", "b", ""]);
+    }
+
+    #[tokio::test]
+    async fn generation_stops_at_max_tokens() {
+        let checkpoint = Checkpoint::new("generate-length");
+        let mut model = ScriptedModel::new(&["a", "b"]);
+        model.endless = true;
+        let mut master = scripted::master(&checkpoint, model);
+
+        let (finish_reason, streamed) = generate(&mut master, 5).await;
+        assert_eq!(finish_reason, FinishReason::Length);
+        assert_eq!(streamed, ["a", "b", "a", "b", "a", ""]);
+        assert_eq!(master.model.generated_tokens(), 5);
+    }
+
+    #[tokio::test]
+    async fn generation_stops_at_the_end_of_the_context_window() {
+        let checkpoint = Checkpoint::new("generate-window");
+        let mut model = ScriptedModel::new(&["a"]);
+        model.endless = true;
+        model.max_sequence_length = 7;
+        let mut master = scripted::master(&checkpoint, model);
+
+        // only 3 tokens fit after the prompt
+        let (finish_reason, streamed) = generate(&mut master, 100).await;
+        assert_eq!(master.model.prompt_tokens(), 4);
+        assert_eq!(finish_reason, FinishReason::Length);
+        assert_eq!(streamed, ["a", "a", "a", ""]);
+
+        // max_tokens still applies below the limit
+        let (_, streamed) = generate(&mut master, 2).await;
+        assert_eq!(streamed, ["a", "a", ""]);
+
+        // the first token only needs the prompt, it is streamed even if the window is full
+        master.model.max_sequence_length = 4;
+        let (finish_reason, streamed) = generate(&mut master, 100).await;
+        assert_eq!(finish_reason, FinishReason::Length);
+        assert_eq!(streamed, ["a", ""]);
+    }
+
+    /// Push the tokens one at a time, return the streamed text and true if a stop sequence matched.
+    fn run(sequences: &[&str], tokens: &[&str]) -> (String, bool) {
+        let sequences: Vec<String> = sequences.iter().map(|s| s.to_string()).collect();
+        let mut stop = StopSequences::new(&sequences);
+        let mut streamed = String::new();
+        for token in tokens {
+            let (text, stopped) = stop.push(token);
+            streamed += &text;
+            if stopped {
+                return (streamed, true);
+            }
+        }
+        streamed += &stop.flush();
+        (streamed, false)
+    }
+
+    #[test]
+    fn without_stop_sequences_text_is_streamed() {
+        let sequences = vec![];
+        let mut stop = StopSequences::new(&sequences);
+        assert_eq!(stop.push("hello"), ("hello".to_string(), false));
+        assert_eq!(stop.flush(), "");
+    }
+
+    #[test]
+    fn stop_split_across_tokens() {
+        let sequences = vec!["END".to_string()];
+        let mut stop = StopSequences::new(&sequences);
+        assert_eq!(stop.push("hello E"), ("hello ".to_string(), false));
+        assert_eq!(stop.push("N"), (String::new(), false));
+        assert_eq!(stop.push("D and more"), (String::new(), true));
+    }
+
+    #[test]
+    fn partial_matches_are_released() {
+        let sequences = vec!["END".to_string()];
+        let mut stop = StopSequences::new(&sequences);
+        assert_eq!(stop.push("E"), (String::new(), false));
+        assert_eq!(stop.push("Nd"), ("ENd".to_string(), false));
+
+        assert_eq!(
+            run(&["END"], &["a", "EN", "x", "END"]),
+            ("aENx".to_string(), true)
+        );
+    }
+
+    #[test]
+    fn overlapping_prefixes() {
+        // only the part that can't start a match is released
+        assert_eq!(
+            run(&["aab"], &["a", "a", "a", "b"]),
+            ("a".to_string(), true)
+        );
+        assert_eq!(run(&["abc", "bcd"], &["ab", "cd"]), (String::new(), true));
+        assert_eq!(run(&["abc", "bcd"], &["xbc", "d"]), ("x".to_string(), true));
+        // the earliest match wins
+        assert_eq!(
+            run(&["world", "lo"], &["hello world"]),
+            ("hel".to_string(), true)
+        );
+    }
+
+    #[test]
+    fn held_text_is_flushed_at_the_end() {
+        assert_eq!(
+            run(&["END"], &["foo ", "EN"]),
+            ("foo EN".to_string(), false)
+        );
+        assert_eq!(run(&["é!"], &["café"]), ("café".to_string(), false));
+    }
+}
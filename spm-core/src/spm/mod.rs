@@ -1,28 +1,43 @@
 use std::{
     fmt::{Debug, Display},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
 use anyhow::Result;
 use async_trait::async_trait;
 use candle_core::{DType, Device, Tensor};
-use candle_nn::VarBuilder;
 
 use crate::{
     models::llama3::{Cache, Config, LlamaConfig},
-    utils, Args,
+    utils::{self, Gguf, ModelFingerprint, Weights},
+    Args,
 };
 
+#[cfg(feature = "master")]
+mod api;
 #[cfg(feature = "master")]
 mod master;
+#[cfg(feature = "master")]
+mod repl;
 
+mod auth;
 mod client;
+mod planner;
 mod proto;
+mod quantize;
+mod replicas;
+mod split;
 mod topology;
 mod worker;
 
+pub use auth::*;
 pub use client::*;
+pub use planner::*;
 pub use proto::*;
+pub use quantize::*;
+pub use replicas::*;
+pub use split::*;
 pub use topology::*;
 pub use worker::*;
 
@@ -47,20 +62,16 @@ pub struct Context {
     pub data_path: PathBuf, // 模型数据的路径 ../Meta-Llama-3-8B-Instruct/  然后从该路径下读取所有的配置文件和模型参数
     pub device: Device, // 计算设备，如 CPU 或 GPU
     pub config: Config, // 模型的配置信息，例如哪些中检层大小和隐藏层大小
+    pub config_hash: String, // config.json 的哈希值，握手时用于确认主节点和工作节点加载的是同一个模型
     pub cache: Cache, // 用于存储中间结果的缓存对象
-    pub var_builder: VarBuilder<'static>, // 用于加载模型参数的变量构建器
+    pub var_builder: Weights, // 用于加载模型参数的变量构建器，safetensors 或 GGUF
+    pub gguf: Option<Arc<Gguf>>, // 打开的 GGUF 文件，元数据只读取一次；safetensors 模型为 None
 }
 
 impl Context {
     /// 创建上下文通过传入的参数
     pub fn from_args(args: Args) -> Result<Self> {
-        let dtype: DType = match args.dtype.as_deref() {
-            Some("f16") => DType::F16,
-            Some("bf16") => DType::BF16,
-            Some("f32") => DType::F32,
-            Some(dtype) => bail!("unsupported dtype {dtype}"),
-            None => DType::F16,
-        };
+        let dtype: DType = utils::parse_dtype(args.dtype.as_deref())?;
 
         let device = utils::get_inference_device(args.cpu, args.device)
             .map_err(|e| anyhow!("can't attach to device: {:?}", e))?;
@@ -75,14 +86,27 @@ impl Context {
 
         let data_path = PathBuf::from(&args.model);
 
-        let config_filename = data_path.join("config.json");
-        let config = LlamaConfig::from_path(&config_filename)?.into_config();
+        let (config, config_hash, gguf) = read_model(&data_path)?;
 
         let topology = Topology::from_path(&args.topology)?;
+        topology.check(config.num_hidden_layers)?;
 
-        let model_tensors_index: PathBuf = data_path.join("model.safetensors.index.json");
-        let var_builder =
-            utils::load_var_builder_from_index(model_tensors_index, dtype, device.clone())?;
+        // only map the files holding the tensors this node needs
+        let prefixes = match args.mode {
+            Mode::Master => master_prefixes(&topology, config.num_hidden_layers),
+            Mode::Worker => worker_prefixes(&topology, args.name.as_deref().unwrap_or_default()),
+        };
+        if let Some(quantization) = args.quantize {
+            log::info!("quantizing the linear layers to {quantization}");
+        }
+        let var_builder = utils::load_weights(
+            &data_path,
+            gguf.as_deref(),
+            &prefixes,
+            dtype,
+            device.clone(),
+        )?
+        .with_quantization(args.quantize);
 
         let cache = Cache::new(true, dtype, &config, &device)?;
 
@@ -93,10 +117,34 @@ impl Context {
             data_path,
             device,
             config,
+            config_hash,
             cache,
             var_builder,
+            gguf,
         })
     }
+
+    /// Return the fingerprint of the model, reusing the GGUF file if the model is one.
+    pub fn fingerprint(&self) -> Result<ModelFingerprint> {
+        match &self.gguf {
+            Some(gguf) => Ok(ModelFingerprint::from_gguf(gguf.clone())),
+            None => ModelFingerprint::new(&self.data_path),
+        }
+    }
+}
+
+/// Read the configuration of the model in data_path and its hash. GGUF files are returned open
+/// since their metadata also holds the tokenizer and the tensor infos.
+pub(crate) fn read_model(data_path: &Path) -> Result<(Config, String, Option<Arc<Gguf>>)> {
+    if utils::is_gguf(data_path) {
+        let gguf = Gguf::open(data_path)?;
+        let config = LlamaConfig::from_gguf(&gguf)?.into_config();
+        Ok((config, gguf.config_hash(), Some(Arc::new(gguf))))
+    } else {
+        let config_filename = data_path.join("config.json");
+        let config = LlamaConfig::from_path(&config_filename)?.into_config();
+        Ok((config, utils::config_hash(&config_filename)?, None))
+    }
 }
 
 /// trait 是一种定义共享行为的机制，类似于其他编程语言里的接口。它能让你指定类型需要实现的一组方法，不过并不需要实现这些方法的具体内容
@@ -107,9 +155,9 @@ impl Context {
 #[async_trait]
 pub trait Forwarder: Debug + Send + Sync + Display {
 
-    /// Create an instance of this object loading the specified layer(s) from a VarBuilder.
-    /// 从 VarBuilder 加载指定的层来创建对象实例
-    fn load(name: String, vb: VarBuilder, cfg: &Config) -> Result<Box<Self>>
+    /// Create an instance of this object loading the specified layer(s) from the weights.
+    /// 从模型权重加载指定的层来创建对象实例
+    fn load(name: String, vb: Weights, cfg: &Config) -> Result<Box<Self>>
     where
         Self: Sized;
 
@@ -144,6 +192,12 @@ pub trait Forwarder: Debug + Send + Sync + Display {
         unimplemented!()
     }
 
+    /// Clear any kv-cache state held outside of the master cache.
+    /// 清空主节点缓存之外的kv缓存（例如远程工作节点上的缓存）
+    async fn reset_cache(&mut self) -> Result<()> {
+        Ok(())
+    }
+
     /// Return the layer name.
     /// 返回层的名称
     fn layer_name(&self) -> &str;
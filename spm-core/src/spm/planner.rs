@@ -0,0 +1,550 @@
+//! Topology planner, splits the layers of the model across the workers according to the
+//! memory they have and to how fast they run a layer.
+use std::{path::Path, time::Duration};
+
+use anyhow::Result;
+use candle_core::{DType, Device, Tensor};
+use memmap2::MmapOptions;
+use safetensors::SafeTensors;
+
+use super::{Auth, Client, ClientOptions, Hello, Topology};
+use crate::{
+    utils::{self, Gguf, Quantization, SafetensorsIndex},
+    Args,
+};
+
+/// Number of forward passes the workers run to measure their speed.
+const PROBE_ITERATIONS: usize = 10;
+
+/// Fraction of the worker memory used for the weights, the rest is left for the kv-cache and
+/// the activations.
+const MEMORY_HEADROOM: f64 = 0.8;
+
+/// Capacity of a worker measured by a probe.
+#[derive(Debug, Clone)]
+pub struct WorkerCapacity {
+    pub name: String,
+    /// Memory in bytes available for layers.
+    pub memory: u64,
+    /// Time of the forward pass of a single layer.
+    pub forward_time: Duration,
+    /// Time to send a hidden state to the worker and get the response back.
+    pub round_trip: Duration,
+}
+
+impl WorkerCapacity {
+    /// Return the time this worker takes to process a token through the given number of layers.
+    pub fn stage_time(&self, num_layers: usize) -> Duration {
+        if num_layers == 0 {
+            Duration::ZERO
+        } else {
+            self.forward_time * num_layers as u32 + self.round_trip
+        }
+    }
+}
+
+/// Contiguous layers assigned to a worker.
+#[derive(Debug, Clone)]
+pub struct Assignment {
+    pub name: String,
+    /// First layer index.
+    pub start: usize,
+    /// Last layer index, inclusive.
+    pub stop: usize,
+    /// Size in bytes of the layers.
+    pub memory: u64,
+    /// Estimated time to process a token through the layers.
+    pub time: Duration,
+}
+
+impl std::fmt::Display for Assignment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} model.layers.{}-{} ({} layers, {}) {:?}/token",
+            &self.name,
+            self.start,
+            self.stop,
+            self.stop - self.start + 1,
+            human_bytes::human_bytes(self.memory as f64),
+            self.time
+        )
+    }
+}
+
+/// Splits the model layers across the workers of a topology.
+pub struct Planner {
+    topology: Topology,
+    layer_sizes: Vec<u64>,
+    workers: Vec<WorkerCapacity>,
+}
+
+impl Planner {
+    /// Create a planner from the size of every layer and the capacity of the workers, the
+    /// layers are assigned to the workers in the given order.
+    pub fn new(topology: Topology, layer_sizes: Vec<u64>, workers: Vec<WorkerCapacity>) -> Self {
+        Self {
+            topology,
+            layer_sizes,
+            workers,
+        }
+    }
+
+    /// Read the layer sizes of the model and probe every worker of the topology, whatever
+    /// layers they're currently assigned.
+    pub async fn from_args(args: &Args, model_name: &str) -> Result<Self> {
+        let data_path = Path::new(&args.model);
+        let (config, config_hash, gguf) = super::read_model(data_path)?;
+        let dtype = utils::parse_dtype(args.dtype.as_deref())?;
+        let topology = Topology::from_path(&args.topology)?;
+
+        let layer_sizes = layer_sizes(
+            data_path,
+            gguf.as_deref(),
+            config.num_hidden_layers,
+            dtype,
+            args.quantize,
+        )?;
+
+        let hello = Hello::new(model_name, &config_hash, dtype, args.compression);
+        let auth = Auth::from_args(args)?;
+        let options = ClientOptions {
+            // probes are one shot requests
+            heartbeat: None,
+            ..ClientOptions::from_args(args)
+        };
+
+        Self::probe(
+            topology,
+            layer_sizes,
+            config.hidden_size,
+            dtype,
+            &hello,
+            &auth,
+            &options,
+        )
+        .await
+    }
+
+    /// Probe every worker of the topology with a hidden state of the given size.
+    pub async fn probe(
+        topology: Topology,
+        layer_sizes: Vec<u64>,
+        hidden_size: usize,
+        dtype: DType,
+        hello: &Hello,
+        auth: &Auth,
+        options: &ClientOptions,
+    ) -> Result<Self> {
+        // a single token hidden state, like the ones sent while generating
+        let x = Tensor::randn(0f32, 1.0, (1, 1, hidden_size), &Device::Cpu)?.to_dtype(dtype)?;
+
+        let mut names: Vec<&String> = topology.keys().collect();
+        names.sort();
+
+        let mut workers = vec![];
+        for name in names {
+            let node = &topology[name];
+            log::info!("probing {name} at {} ...", &node.host);
+
+            let mut client = Client::new(
+                Device::Cpu,
+                &node.host,
+                "probe",
+                hello,
+                &auth.for_node(node)?,
+                options,
+            )
+            .await
+            .map_err(|e| anyhow!("can't probe {name}: {e}"))?;
+
+            let (result, elapsed) = client.probe(&x, PROBE_ITERATIONS).await?;
+            let forward_time = Duration::from_micros(result.forward_time);
+            let round_trip = elapsed.saturating_sub(forward_time * (PROBE_ITERATIONS + 1) as u32);
+
+            log::info!(
+                "  {name} [{}<{}>] memory={} forward={:?} round_trip={:?}",
+                &client.info().device,
+                client.info().device_idx,
+                human_bytes::human_bytes(result.memory as f64),
+                forward_time,
+                round_trip
+            );
+
+            workers.push(WorkerCapacity {
+                name: name.to_string(),
+                memory: result.memory,
+                forward_time,
+                round_trip,
+            });
+        }
+
+        Ok(Self::new(topology, layer_sizes, workers))
+    }
+
+    /// Split the layers in contiguous ranges, one per worker, so that the layers fit in the
+    /// memory of the workers and a token goes through the whole model as fast as possible.
+    /// The master doesn't pipeline the stages, every token goes through them one after the
+    /// other, so the time per token is the sum of the stage times including the round trip to
+    /// every worker used. Workers may get no layers if that doesn't slow down the model.
+    pub fn plan(&self) -> Result<Vec<Assignment>> {
+        let num_layers = self.layer_sizes.len();
+        let mut offsets = vec![0u64; num_layers + 1];
+        for (i, size) in self.layer_sizes.iter().enumerate() {
+            offsets[i + 1] = offsets[i] + size;
+        }
+
+        // best[k][l] is the (time per token, start of the last range) of the best split of the
+        // first l layers across the first k workers
+        let mut best: Vec<Vec<Option<(f64, usize)>>> =
+            vec![vec![None; num_layers + 1]; self.workers.len() + 1];
+        best[0][0] = Some((0.0, 0));
+
+        for (k, worker) in self.workers.iter().enumerate() {
+            let budget = (worker.memory as f64 * MEMORY_HEADROOM) as u64;
+            for stop in 0..=num_layers {
+                for start in 0..=stop {
+                    let (total, _) = match best[k][start] {
+                        Some(prev) => prev,
+                        None => continue,
+                    };
+                    if offsets[stop] - offsets[start] > budget {
+                        continue;
+                    }
+
+                    let candidate = total + worker.stage_time(stop - start).as_secs_f64();
+                    let better = match best[k + 1][stop] {
+                        Some((t, _)) => candidate < t,
+                        None => true,
+                    };
+                    if better {
+                        best[k + 1][stop] = Some((candidate, start));
+                    }
+                }
+            }
+        }
+
+        if best[self.workers.len()][num_layers].is_none() {
+            let budget: f64 = self
+                .workers
+                .iter()
+                .map(|w| w.memory as f64 * MEMORY_HEADROOM)
+                .sum();
+            bail!(
+                "the layers need {} but the workers only have {} available",
+                human_bytes::human_bytes(offsets[num_layers] as f64),
+                human_bytes::human_bytes(budget)
+            );
+        }
+
+        // walk back from the last worker
+        let mut plan = vec![];
+        let mut stop = num_layers;
+        for k in (1..=self.workers.len()).rev() {
+            let (_, start) = best[k][stop].unwrap();
+            if start < stop {
+                let worker = &self.workers[k - 1];
+                plan.push(Assignment {
+                    name: worker.name.clone(),
+                    start,
+                    stop: stop - 1,
+                    memory: offsets[stop] - offsets[start],
+                    time: worker.stage_time(stop - start),
+                });
+            }
+            stop = start;
+        }
+        plan.reverse();
+
+        Ok(plan)
+    }
+
+    /// Return the topology with the planned layers, workers without layers are left out.
+    pub fn to_topology(&self, plan: &[Assignment]) -> Topology {
+        let mut topology = Topology::default();
+        for assignment in plan {
+            let mut node = self.topology[&assignment.name].clone();
+            node.layers = if assignment.start == assignment.stop {
+                vec![format!("model.layers.{}", assignment.start)]
+            } else {
+                vec![format!(
+                    "model.layers.{}-{}",
+                    assignment.start, assignment.stop
+                )]
+            };
+            topology.insert(assignment.name.clone(), node);
+        }
+        topology
+    }
+}
+
+/// Return the size in bytes of every transformer block once loaded with the given dtype and
+/// quantization, GGUF layers keep the size of their quantized tensors.
+pub fn layer_sizes(
+    data_path: &Path,
+    gguf: Option<&Gguf>,
+    num_layers: usize,
+    dtype: DType,
+    quantization: Option<Quantization>,
+) -> Result<Vec<u64>> {
+    let sizes = read_layer_sizes(data_path, gguf, num_layers, dtype, quantization)?;
+
+    if let Some(missing) = sizes.iter().position(|size| *size == 0) {
+        bail!("no tensors found for model.layers.{missing}");
+    }
+
+    Ok(sizes)
+}
+
+/// Return the size in bytes of the given layers once loaded, workers add it to their available
+/// memory since the layers they hold are freed when they're assigned others.
+pub fn loaded_layers_size(
+    data_path: &Path,
+    gguf: Option<&Gguf>,
+    layer_names: &[String],
+    dtype: DType,
+    quantization: Option<Quantization>,
+) -> Result<u64> {
+    let mut indexes = vec![];
+    for layer_name in layer_names {
+        match layer_name
+            .strip_prefix("model.layers.")
+            .and_then(|idx| idx.parse::<usize>().ok())
+        {
+            Some(idx) => indexes.push(idx),
+            None => bail!("invalid layer name {layer_name}"),
+        }
+    }
+    let num_layers = match indexes.iter().max() {
+        Some(max) => max + 1,
+        None => return Ok(0),
+    };
+
+    let sizes = read_layer_sizes(data_path, gguf, num_layers, dtype, quantization)?;
+    Ok(indexes.iter().map(|idx| sizes[*idx]).sum())
+}
+
+fn read_layer_sizes(
+    data_path: &Path,
+    gguf: Option<&Gguf>,
+    num_layers: usize,
+    dtype: DType,
+    quantization: Option<Quantization>,
+) -> Result<Vec<u64>> {
+    match gguf {
+        Some(gguf) => Ok(gguf_layer_sizes(gguf, num_layers)),
+        None => safetensors_layer_sizes(data_path, num_layers, dtype, quantization),
+    }
+}
+
+fn gguf_layer_sizes(gguf: &Gguf, num_layers: usize) -> Vec<u64> {
+    let mut sizes = vec![0u64; num_layers];
+    for (layer, size) in sizes.iter_mut().enumerate() {
+        for name in gguf.tensors(&format!("model.layers.{layer}")) {
+            let info = gguf.tensor_info(name).unwrap();
+            *size += gguf.tensor_range(info).len() as u64;
+        }
+    }
+    sizes
+}
+
+fn safetensors_layer_sizes(
+    data_path: &Path,
+    num_layers: usize,
+    dtype: DType,
+    quantization: Option<Quantization>,
+) -> Result<Vec<u64>> {
+    let prefixes: Vec<String> = (0..num_layers)
+        .map(|i| format!("model.layers.{i}"))
+        .collect();
+    let filenames = SafetensorsIndex::from_path(data_path)?.files(&prefixes)?;
+
+    let mut sizes = vec![0u64; num_layers];
+    for path in filenames {
+        let file = std::fs::File::open(&path)
+            .map_err(|e| anyhow!("can't open {}: {:?}", path.display(), e))?;
+        let mmap = unsafe { MmapOptions::new().map(&file) }
+            .map_err(|e| anyhow!("can't map {}: {:?}", path.display(), e))?;
+        let (_, metadata) = SafeTensors::read_metadata(&mmap)
+            .map_err(|e| anyhow!("can't read {}: {:?}", path.display(), e))?;
+
+        for (name, info) in metadata.tensors() {
+            let layer = name
+                .strip_prefix("model.layers.")
+                .and_then(|rest| rest.split('.').next())
+                .and_then(|idx| idx.parse::<usize>().ok());
+            if let Some(layer) = layer.filter(|l| *l < num_layers) {
+                // the weights of the linear layers are the only 2d tensors of a block
+                let quantized = quantization
+                    .filter(|_| info.shape.len() == 2)
+                    .and_then(|q| q.quantized_size(&info.shape));
+                let elements: usize = info.shape.iter().product();
+                sizes[layer] += quantized.unwrap_or((elements * dtype.size_in_bytes()) as u64);
+            }
+        }
+    }
+
+    Ok(sizes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::spm::{stand_in, Node};
+
+    const MB: u64 = 1024 * 1024;
+
+    fn node(host: &str) -> Node {
+        Node {
+            host: host.to_string(),
+            description: None,
+            cert: None,
+            layers: vec![],
+        }
+    }
+
+    fn worker(name: &str, memory: u64, forward_ms: u64, round_trip_ms: u64) -> WorkerCapacity {
+        WorkerCapacity {
+            name: name.to_string(),
+            memory,
+            forward_time: Duration::from_millis(forward_ms),
+            round_trip: Duration::from_millis(round_trip_ms),
+        }
+    }
+
+    fn planner(num_layers: usize, workers: Vec<WorkerCapacity>) -> Planner {
+        let mut topology = Topology::default();
+        for worker in &workers {
+            topology.insert(worker.name.clone(), node("127.0.0.1:10128"));
+        }
+        Planner::new(topology, vec![MB; num_layers], workers)
+    }
+
+    /// Check that the ranges are contiguous, in order and cover every layer.
+    fn assert_contiguous(plan: &[Assignment], num_layers: usize) {
+        let mut next = 0;
+        for assignment in plan {
+            assert_eq!(assignment.start, next, "{plan:?}");
+            assert!(assignment.stop >= assignment.start, "{plan:?}");
+            next = assignment.stop + 1;
+        }
+        assert_eq!(next, num_layers, "{plan:?}");
+    }
+
+    #[test]
+    fn plan_is_contiguous() {
+        // every worker fits 4 layers
+        let workers = vec![
+            worker("a", 5 * MB, 1, 1),
+            worker("b", 5 * MB, 1, 1),
+            worker("c", 5 * MB, 1, 1),
+        ];
+        let plan = planner(10, workers).plan().unwrap();
+
+        assert_contiguous(&plan, 10);
+        assert_eq!(plan.len(), 3);
+        let names: Vec<&str> = plan.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn plan_respects_memory() {
+        // the fast worker fits 2 layers, the rest spills to the slow one
+        let workers = vec![
+            worker("fast", 3 * MB, 1, 1),
+            worker("slow", 100 * MB, 10, 1),
+        ];
+        let plan = planner(6, workers).plan().unwrap();
+
+        assert_contiguous(&plan, 6);
+        assert_eq!(plan.len(), 2);
+        assert_eq!((plan[0].start, plan[0].stop), (0, 1));
+        assert_eq!(plan[0].memory, 2 * MB);
+        assert_eq!((plan[1].start, plan[1].stop), (2, 5));
+        for (assignment, worker) in plan.iter().zip([3 * MB, 100 * MB]) {
+            assert!(assignment.memory as f64 <= worker as f64 * MEMORY_HEADROOM);
+        }
+    }
+
+    #[test]
+    fn plan_minimizes_total_time() {
+        // splitting the layers only adds a round trip
+        let workers = vec![worker("a", 100 * MB, 2, 5), worker("b", 100 * MB, 2, 5)];
+        let plan = planner(8, workers).plan().unwrap();
+        assert_eq!(plan.len(), 1);
+        assert_contiguous(&plan, 8);
+
+        // the slow worker is left out
+        let workers = vec![
+            worker("slow", 100 * MB, 10, 1),
+            worker("fast", 100 * MB, 1, 1),
+        ];
+        let plan = planner(8, workers).plan().unwrap();
+        assert_eq!(plan.len(), 1);
+        assert_eq!(plan[0].name, "fast");
+        assert_eq!(plan[0].time, Duration::from_millis(9));
+    }
+
+    #[test]
+    fn plan_fails_without_memory() {
+        let workers = vec![worker("a", 2 * MB, 1, 1), worker("b", 2 * MB, 1, 1)];
+        let err = planner(4, workers).plan().unwrap_err();
+        assert!(err.to_string().contains("only have"), "{err}");
+
+        // enough memory in total but a layer doesn't fit any worker
+        let mut planner = planner(
+            2,
+            vec![worker("a", 4 * MB, 1, 1), worker("b", 4 * MB, 1, 1)],
+        );
+        planner.layer_sizes = vec![MB, 4 * MB];
+        assert!(planner.plan().is_err());
+    }
+
+    #[tokio::test]
+    async fn plan_probed_workers() {
+        let workers = [
+            ("a", 3 * MB, Duration::from_millis(2)),
+            ("b", 3 * MB, Duration::from_millis(5)),
+        ];
+        let mut topology = Topology::default();
+        for (name, memory, forward_time) in workers {
+            let address = stand_in::spawn(memory, forward_time).await.unwrap();
+            topology.insert(name.to_string(), node(&address));
+        }
+
+        let planner = Planner::probe(
+            topology,
+            vec![MB; 4],
+            stand_in::HIDDEN_SIZE,
+            DType::F32,
+            &stand_in::hello(),
+            &Auth::default(),
+            &stand_in::client_options(),
+        )
+        .await
+        .unwrap();
+
+        let probed: HashMap<&str, &WorkerCapacity> = planner
+            .workers
+            .iter()
+            .map(|w| (w.name.as_str(), w))
+            .collect();
+        for (name, memory, forward_time) in workers {
+            assert_eq!(probed[name].memory, memory);
+            assert!(probed[name].forward_time >= forward_time);
+        }
+
+        // each worker fits 2 layers whatever the round trip
+        let plan = planner.plan().unwrap();
+        assert_contiguous(&plan, 4);
+        assert_eq!(plan.len(), 2);
+        assert_eq!((plan[0].name.as_str(), plan[0].stop), ("a", 1));
+        assert_eq!((plan[1].name.as_str(), plan[1].stop), ("b", 3));
+
+        let topology = planner.to_topology(&plan);
+        assert_eq!(topology["a"].layers, ["model.layers.0-1"]);
+        assert_eq!(topology["b"].layers, ["model.layers.2-3"]);
+    }
+}
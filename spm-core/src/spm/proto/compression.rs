@@ -0,0 +1,260 @@
+use anyhow::Result;
+use candle_core::{DType, Device, Tensor};
+use safetensors::View;
+use serde::{Deserialize, Serialize};
+
+/// zstd compression level, low levels are fast enough to keep up with the network.
+const ZSTD_LEVEL: i32 = 1;
+
+/// Compression of the tensors data sent over the wire.
+#[derive(
+    clap::ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq,
+)]
+pub enum Compression {
+    /// Raw tensor data.
+    #[default]
+    None,
+    /// Lossless zstd compression.
+    Zstd,
+    /// Lossless lz4 compression, faster than zstd with a lower ratio.
+    Lz4,
+    /// Lossy 8 bit quantization of float tensors with one absmax scale per token.
+    Int8,
+}
+
+impl Compression {
+    /// Return the capability a worker must support to use this compression, if any.
+    pub fn capability(&self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Zstd => Some("zstd"),
+            Compression::Lz4 => Some("lz4"),
+            Compression::Int8 => Some("int8"),
+        }
+    }
+
+    /// Return the compression that will actually be used for x, int8 only applies to floats.
+    pub fn for_tensor(&self, x: &Tensor) -> Self {
+        match self {
+            Compression::Int8 if !x.dtype().is_float() => Compression::None,
+            compression => *compression,
+        }
+    }
+
+    /// Encode the tensor data.
+    pub fn encode(&self, x: &Tensor) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(x.data().to_vec()),
+            Compression::Zstd => Ok(zstd::bulk::compress(&x.data(), ZSTD_LEVEL)?),
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(&x.data())),
+            Compression::Int8 => encode_int8(x),
+        }
+    }
+
+    /// Decode the tensor data on the given device.
+    pub fn decode(
+        &self,
+        data: &[u8],
+        dtype: DType,
+        shape: &[usize],
+        device: &Device,
+    ) -> Result<Tensor> {
+        let raw_size = shape.iter().product::<usize>() * dtype.size_in_bytes();
+        let raw = match self {
+            Compression::None => {
+                return Tensor::from_raw_buffer(data, dtype, shape, device).map_err(|e| anyhow!(e))
+            }
+            Compression::Zstd => zstd::bulk::decompress(data, raw_size)?,
+            Compression::Lz4 => decode_lz4(data, raw_size)?,
+            Compression::Int8 => return decode_int8(data, dtype, shape, device),
+        };
+
+        if raw.len() != raw_size {
+            bail!("decompressed {} bytes, expected {raw_size}", raw.len());
+        }
+
+        Tensor::from_raw_buffer(&raw, dtype, shape, device).map_err(|e| anyhow!(e))
+    }
+}
+
+/// Decompress data encoded with lz4_flex::compress_prepend_size. The size prefix must match
+/// the size expected from the shape, so that a corrupted one can't make us allocate more.
+fn decode_lz4(data: &[u8], raw_size: usize) -> Result<Vec<u8>> {
+    let (size, data) = match data.split_first_chunk::<4>() {
+        Some((size, data)) => (u32::from_le_bytes(*size) as usize, data),
+        None => bail!("invalid lz4 tensor data size {}", data.len()),
+    };
+    if size != raw_size {
+        bail!("lz4 tensor data holds {size} bytes, expected {raw_size}");
+    }
+
+    Ok(lz4_flex::block::decompress(data, raw_size)?)
+}
+
+/// Quantize every row of the last dimension to i8 using its absolute max as scale.
+/// The data is encoded as the f32 scales of all rows followed by the quantized values.
+fn encode_int8(x: &Tensor) -> Result<Vec<u8>> {
+    let row_size = x.dims().last().copied().unwrap_or(1).max(1);
+    let values = x.to_dtype(DType::F32)?.flatten_all()?.to_vec1::<f32>()?;
+    let rows = values.len() / row_size;
+
+    let mut data = Vec::with_capacity(rows * 4 + values.len());
+    let mut scales = Vec::with_capacity(rows);
+    for row in values.chunks(row_size) {
+        let absmax = row.iter().fold(0.0f32, |max, v| max.max(v.abs()));
+        let scale = absmax / 127.0;
+        data.extend_from_slice(&scale.to_le_bytes());
+        scales.push(scale);
+    }
+    for (row, scale) in values.chunks(row_size).zip(scales) {
+        for v in row {
+            let q = if scale > 0.0 {
+                (v / scale).round().clamp(-127.0, 127.0) as i8
+            } else {
+                0
+            };
+            data.push(q as u8);
+        }
+    }
+
+    Ok(data)
+}
+
+/// Dequantize data encoded by encode_int8.
+fn decode_int8(data: &[u8], dtype: DType, shape: &[usize], device: &Device) -> Result<Tensor> {
+    let size: usize = shape.iter().product();
+    let row_size = shape.last().copied().unwrap_or(1).max(1);
+    let rows = size / row_size;
+    if data.len() != rows * 4 + size {
+        bail!(
+            "invalid int8 tensor data size {} for shape {:?}",
+            data.len(),
+            shape
+        );
+    }
+
+    let (scales, values) = data.split_at(rows * 4);
+    let scales: Vec<f32> = scales
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect();
+
+    let values: Vec<f32> = values
+        .chunks(row_size)
+        .zip(scales)
+        .flat_map(|(row, scale)| row.iter().map(move |q| *q as i8 as f32 * scale))
+        .collect();
+
+    Ok(Tensor::from_vec(values, shape, device)?.to_dtype(dtype)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lossless_round_trip() {
+        let x = Tensor::arange(0f32, 256., &Device::Cpu)
+            .unwrap()
+            .reshape((1, 4, 64))
+            .unwrap();
+        for compression in [Compression::None, Compression::Zstd, Compression::Lz4] {
+            let data = compression.encode(&x).unwrap();
+            let y = compression
+                .decode(&data, DType::F32, &[1, 4, 64], &Device::Cpu)
+                .unwrap();
+            assert_eq!(
+                y.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+                x.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+                "{compression:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn lz4_size_must_match_the_shape() {
+        let x = Tensor::zeros((1, 1, 64), DType::F32, &Device::Cpu).unwrap();
+        let mut data = Compression::Lz4.encode(&x).unwrap();
+
+        // a larger shape than the data holds
+        let err = Compression::Lz4
+            .decode(&data, DType::F32, &[1, 2, 64], &Device::Cpu)
+            .unwrap_err();
+        assert!(err.to_string().contains("expected 512"), "{err}");
+
+        // a corrupted size prefix
+        data[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(Compression::Lz4
+            .decode(&data, DType::F32, &[1, 1, 64], &Device::Cpu)
+            .is_err());
+        assert!(Compression::Lz4
+            .decode(&data[..2], DType::F32, &[1, 1, 64], &Device::Cpu)
+            .is_err());
+    }
+
+    #[test]
+    fn int8_error_is_bounded_by_the_row_scale() {
+        let x = Tensor::randn(0f32, 3.0, (1, 4, 64), &Device::Cpu).unwrap();
+        let data = Compression::Int8.encode(&x).unwrap();
+        assert_eq!(data.len(), 4 * 4 + 4 * 64);
+
+        let y = Compression::Int8
+            .decode(&data, DType::F32, &[1, 4, 64], &Device::Cpu)
+            .unwrap();
+        let x = x.get(0).unwrap().to_vec2::<f32>().unwrap();
+        let y = y.get(0).unwrap().to_vec2::<f32>().unwrap();
+        for (x, y) in x.iter().zip(y) {
+            let absmax = x.iter().fold(0.0f32, |max, v| max.max(v.abs()));
+            for (a, b) in x.iter().zip(y) {
+                // rounding is at most half a step off
+                assert!((a - b).abs() <= absmax / 127.0 / 2.0 + 1e-6, "{a} {b}");
+            }
+        }
+    }
+
+    #[test]
+    fn int8_zero_rows() {
+        let x = Tensor::new(&[[0f32, 0., 0., 0.], [1., -2., 0.5, 4.]], &Device::Cpu).unwrap();
+        let data = Compression::Int8.encode(&x).unwrap();
+        assert_eq!(&data[..4], &0f32.to_le_bytes());
+
+        let y = Compression::Int8
+            .decode(&data, DType::F32, &[2, 4], &Device::Cpu)
+            .unwrap()
+            .to_vec2::<f32>()
+            .unwrap();
+        assert_eq!(y[0], [0., 0., 0., 0.]);
+        // the absmax is exact
+        assert_eq!(y[1][3], 4.);
+    }
+
+    #[test]
+    fn int8_only_applies_to_floats() {
+        let ids = Tensor::new(&[1u32, 2, 3], &Device::Cpu).unwrap();
+        assert_eq!(Compression::Int8.for_tensor(&ids), Compression::None);
+
+        let x = Tensor::zeros(3, DType::F16, &Device::Cpu).unwrap();
+        assert_eq!(Compression::Int8.for_tensor(&x), Compression::Int8);
+        assert_eq!(Compression::Zstd.for_tensor(&ids), Compression::Zstd);
+    }
+
+    #[test]
+    fn int8_size_must_match_the_shape() {
+        let x = Tensor::zeros((1, 2, 8), DType::F32, &Device::Cpu).unwrap();
+        let data = Compression::Int8.encode(&x).unwrap();
+
+        for shape in [[1, 3, 8], [1, 2, 4]] {
+            let err = Compression::Int8
+                .decode(&data, DType::F32, &shape, &Device::Cpu)
+                .unwrap_err();
+            assert!(
+                err.to_string().contains("invalid int8 tensor data size"),
+                "{err}"
+            );
+        }
+        let truncated = &data[..data.len() - 1];
+        assert!(Compression::Int8
+            .decode(truncated, DType::F32, &[1, 2, 8], &Device::Cpu)
+            .is_err());
+    }
+}
@@ -2,10 +2,11 @@ use std::str::FromStr;
 
 use anyhow::Result;
 use candle_core::{DType, Device, Tensor};
-use safetensors::View;
 use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
+use super::Compression;
+
 /// Represents a tensor in spm protocol.
 #[derive(Serialize, Debug, Deserialize)]
 pub struct RawTensor {
@@ -15,21 +16,124 @@ pub struct RawTensor {
     pub dtype: String,
     /// The tensor shape.
     pub shape: Vec<usize>,
+    /// How data is compressed.
+    pub compression: Compression,
 }
 
 impl RawTensor {
-    /// Convert x into a RawTensor.
-    pub fn from_tensor(x: &Tensor) -> Self {
-        let data: Vec<u8> = x.data().to_vec();
+    /// Convert x into a RawTensor, compressing its data.
+    pub fn from_tensor(x: &Tensor, compression: Compression) -> Result<Self> {
+        let mut compression = compression.for_tensor(x);
+        let mut data = compression.encode(x)?;
+        if matches!(compression, Compression::Zstd | Compression::Lz4)
+            && data.len() >= x.elem_count() * x.dtype().size_in_bytes()
+        {
+            // incompressible data is sent as is
+            compression = Compression::None;
+            data = compression.encode(x)?;
+        }
         let dtype = x.dtype().as_str().to_string();
         let shape = x.shape().clone().into_dims();
-        Self { data, dtype, shape }
+        Ok(Self {
+            data,
+            dtype,
+            shape,
+            compression,
+        })
     }
 
     /// Convert the raw tensor in a Tensor allocated on the given device.
     pub fn to_tensor(&self, device: &Device) -> Result<Tensor> {
         let dtype = DType::from_str(&self.dtype)?;
-        Tensor::from_raw_buffer(&self.data, dtype, &self.shape, device).map_err(|e| anyhow!(e))
+        self.compression
+            .decode(&self.data, dtype, &self.shape, device)
+    }
+
+    /// Return the size of the uncompressed tensor data in bytes.
+    pub fn raw_size(&self) -> usize {
+        let elem_size = DType::from_str(&self.dtype)
+            .map(|dtype| dtype.size_in_bytes())
+            .unwrap_or(1);
+        self.shape.iter().product::<usize>() * elem_size
+    }
+}
+
+/// Handshake sent by the master when connecting to a worker.
+#[derive(Serialize, Debug, Clone, Default, Deserialize)]
+pub struct Hello {
+    /// Protocol version.
+    pub version: u32,
+    /// Name of the model served by the master.
+    pub model: String,
+    /// Hash of the model configuration.
+    pub config_hash: String,
+    /// Tensors data type.
+    pub dtype: String,
+    /// Optional protocol features supported by the master.
+    pub capabilities: Vec<String>,
+    /// Compression requested by the master.
+    pub compression: Compression,
+}
+
+impl Hello {
+    /// Create the handshake for the given model and configuration.
+    pub fn new(model: &str, config_hash: &str, dtype: DType, compression: Compression) -> Self {
+        Self {
+            version: super::PROTO_VERSION,
+            model: model.to_string(),
+            config_hash: config_hash.to_string(),
+            dtype: dtype.as_str().to_string(),
+            capabilities: super::CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+            compression,
+        }
+    }
+
+    /// Check that a worker described by the given handshake can serve this master and
+    /// return the capabilities supported by both.
+    pub fn negotiate(&self, worker: &Hello) -> Result<Vec<String>> {
+        if self.version != worker.version {
+            bail!(
+                "protocol version mismatch: master={} worker={}",
+                self.version,
+                worker.version
+            );
+        }
+        if self.model != worker.model {
+            bail!(
+                "model mismatch: master={} worker={}",
+                &self.model,
+                &worker.model
+            );
+        }
+        if self.config_hash != worker.config_hash {
+            bail!(
+                "model configuration mismatch: master={} worker={}",
+                &self.config_hash,
+                &worker.config_hash
+            );
+        }
+        if self.dtype != worker.dtype {
+            bail!(
+                "dtype mismatch: master={} worker={}",
+                &self.dtype,
+                &worker.dtype
+            );
+        }
+
+        Ok(self
+            .capabilities
+            .iter()
+            .filter(|c| worker.capabilities.contains(c))
+            .cloned()
+            .collect())
+    }
+
+    /// Return the requested compression if supported by both sides, or no compression.
+    pub fn agreed_compression(&self, capabilities: &[String]) -> Compression {
+        match self.compression.capability() {
+            Some(cap) if !capabilities.iter().any(|c| c == cap) => Compression::None,
+            _ => self.compression,
+        }
     }
 }
 
@@ -37,7 +141,7 @@ impl RawTensor {
 #[derive(Serialize, Debug, Default, Deserialize)]
 pub struct WorkerInfo {
     /// Protocol version.
-    pub version: String,
+    pub version: u32,
     /// Tensors data type.
     pub dtype: String,
     /// Operating system.
@@ -50,13 +154,70 @@ pub struct WorkerInfo {
     pub device_idx: usize,
     /// Latency in millisenconds.
     pub latency: u128,
+    /// Optional protocol features agreed with the master.
+    pub capabilities: Vec<String>,
+    /// Layers served by the worker.
+    pub layers: Vec<String>,
+    /// Fingerprint of the model configuration and the served layers.
+    pub fingerprint: String,
+    /// Compression agreed with the master.
+    pub compression: Compression,
+    /// Quantization of the weights of the linear layers, like q4k, None if not quantized.
+    pub quantization: Option<String>,
+}
+
+/// Worker capacity measured by a Probe message.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ProbeResult {
+    /// Memory in bytes available for layers.
+    pub memory: u64,
+    /// Average time in microseconds of the forward pass of a single layer.
+    pub forward_time: u64,
+}
+
+/// Error codes reported by workers.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The input tensor could not be loaded on the worker device.
+    InvalidTensor,
+    /// The requested layer is not served by the worker.
+    UnknownLayer,
+    /// The forward operation failed.
+    Forward,
+    /// The message is not valid at this point of the protocol.
+    UnexpectedMessage,
+    /// The master and the worker can't work together.
+    Handshake,
+    /// The master failed to authenticate.
+    Unauthorized,
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                ErrorCode::InvalidTensor => "invalid tensor",
+                ErrorCode::UnknownLayer => "unknown layer",
+                ErrorCode::Forward => "forward error",
+                ErrorCode::UnexpectedMessage => "unexpected message",
+                ErrorCode::Handshake => "handshake failed",
+                ErrorCode::Unauthorized => "unauthorized",
+            }
+        )
+    }
 }
 
 /// A spm protocol message.
 #[derive(Serialize, Debug, Deserialize)]
 pub enum Message {
-    /// First message sent.
-    Hello,
+    /// First message sent, the worker replies with WorkerInfo or Error.
+    Hello(Hello),
+    /// Sent by workers requiring authentication in response to Hello.
+    Challenge(Vec<u8>),
+    /// HMAC of the challenge computed with the pre-shared key.
+    Auth(Vec<u8>),
     /// Message that the worker sends when a master connects with runtime information.
     WorkerInfo(WorkerInfo),
     /// Single inference operation for a given layer.
@@ -73,31 +234,88 @@ pub enum Message {
     },
     /// A message to transmit tensors.
     Tensor(RawTensor),
+    /// Clear the kv-cache of this connection.
+    ResetCache,
+    /// Generic acknowledgement for requests without a result.
+    Ok,
+    /// Heartbeat sent by the master on idle connections.
+    Ping,
+    /// Response to Ping.
+    Pong,
+    /// Ask the worker to measure its capacity running a layer on x several times.
+    Probe { x: RawTensor, iterations: usize },
+    /// Response to Probe.
+    ProbeResult(ProbeResult),
+    /// Sent by the worker instead of the expected response when a request fails.
+    Error {
+        code: ErrorCode,
+        message: String,
+        layer_name: Option<String>,
+    },
 }
 
 impl Message {
     /// Create a Message::SingleOp message.
-    pub fn single_op(layer_name: &str, x: &Tensor, index_pos: usize, block_idx: usize) -> Self {
+    pub fn single_op(
+        layer_name: &str,
+        x: &Tensor,
+        index_pos: usize,
+        block_idx: usize,
+        compression: Compression,
+    ) -> Result<Self> {
         let layer_name = layer_name.to_owned();
-        let x = RawTensor::from_tensor(x);
-        Self::SingleOp {
+        let x = RawTensor::from_tensor(x, compression)?;
+        Ok(Self::SingleOp {
             layer_name,
             x,
             index_pos,
             block_idx,
-        }
+        })
     }
 
     /// Create a Message::Tensor message.
-    pub fn from_tensor(x: &Tensor) -> Self {
-        Self::Tensor(RawTensor::from_tensor(x))
+    pub fn from_tensor(x: &Tensor, compression: Compression) -> Result<Self> {
+        Ok(Self::Tensor(RawTensor::from_tensor(x, compression)?))
+    }
+
+    /// Create a Message::Error message.
+    pub fn error(code: ErrorCode, message: String, layer_name: Option<String>) -> Self {
+        Self::Error {
+            code,
+            message,
+            layer_name,
+        }
     }
 
     /// Create a Message::Batch message.
-    pub fn from_batch(x: &Tensor, batch: Vec<(String, usize, usize)>) -> Self {
-        Self::Batch {
-            x: RawTensor::from_tensor(x),
+    pub fn from_batch(
+        x: &Tensor,
+        batch: Vec<(String, usize, usize)>,
+        compression: Compression,
+    ) -> Result<Self> {
+        Ok(Self::Batch {
+            x: RawTensor::from_tensor(x, compression)?,
             batch,
+        })
+    }
+
+    /// Return the message name, used to describe it without dumping its payload.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Message::Hello(_) => "Hello",
+            Message::Challenge(_) => "Challenge",
+            Message::Auth(_) => "Auth",
+            Message::WorkerInfo(_) => "WorkerInfo",
+            Message::SingleOp { .. } => "SingleOp",
+            Message::Batch { .. } => "Batch",
+            Message::Tensor(_) => "Tensor",
+            Message::ResetCache => "ResetCache",
+            Message::Ok => "Ok",
+            Message::Ping => "Ping",
+            Message::Pong => "Pong",
+            Message::Probe { .. } => "Probe",
+            Message::ProbeResult(_) => "ProbeResult",
+            Message::Error { .. } => "Error",
         }
     }
 
@@ -154,3 +372,65 @@ impl Message {
         Ok(8 + req.len())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello() -> Hello {
+        Hello::new("llama3", "config", DType::F16, Compression::Zstd)
+    }
+
+    #[test]
+    fn negotiate_rejects_a_different_model() {
+        let master = hello();
+        for (worker, error) in [
+            (
+                Hello {
+                    version: master.version + 1,
+                    ..hello()
+                },
+                "protocol version mismatch",
+            ),
+            (
+                Hello {
+                    model: "other".to_string(),
+                    ..hello()
+                },
+                "model mismatch",
+            ),
+            (
+                Hello {
+                    config_hash: "other".to_string(),
+                    ..hello()
+                },
+                "model configuration mismatch",
+            ),
+            (
+                Hello::new("llama3", "config", DType::F32, Compression::None),
+                "dtype mismatch: master=f16 worker=f32",
+            ),
+        ] {
+            let err = master.negotiate(&worker).unwrap_err();
+            assert!(err.to_string().contains(error), "{err}");
+        }
+    }
+
+    #[test]
+    fn negotiate_agrees_on_common_capabilities() {
+        let master = hello();
+        let worker = Hello {
+            capabilities: vec!["lz4".to_string(), "batch".to_string(), "future".to_string()],
+            ..hello()
+        };
+        let capabilities = master.negotiate(&worker).unwrap();
+        assert_eq!(capabilities, ["batch", "lz4"]);
+
+        // zstd isn't supported by the worker
+        assert_eq!(master.agreed_compression(&capabilities), Compression::None);
+        assert_eq!(
+            master.agreed_compression(&master.negotiate(&hello()).unwrap()),
+            Compression::Zstd
+        );
+    }
+}
@@ -6,6 +6,22 @@ const PROTO_MAGIC: u32 = 0x104F4C7;
 /// spm protocol message max size.
 const MESSAGE_MAX_SIZE: u32 = 512 * 1024 * 1024;
 
+/// spm protocol version, increase it for every change to the messages.
+pub const PROTO_VERSION: u32 = 7;
+
+/// Optional protocol features supported by this build.
+pub const CAPABILITIES: &[&str] = &[
+    "batch",
+    "reset_cache",
+    "heartbeat",
+    "zstd",
+    "lz4",
+    "int8",
+    "probe",
+];
+
+mod compression;
 mod message;
 
+pub use compression::*;
 pub use message::*;
@@ -0,0 +1,378 @@
+//! Export a quantized checkpoint as GGUF, so that nodes don't need to quantize the weights every
+//! time they start.
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use candle_core::{
+    quantized::{gguf_file::Value, GgmlDType, QTensor},
+    safetensors::MmapedSafetensors,
+    Device,
+};
+
+use super::{master_prefixes, worker_prefixes, Shard, Topology, MASTER_SHARD};
+use crate::{
+    models::llama3::{tokenizer_to_gguf_metadata, Config},
+    utils::{self, GgufWriter, Quantization, SafetensorsIndex, GGUF_FINGERPRINTS_METADATA_KEY},
+};
+
+/// Length of the hex sha256 fingerprints of the layers.
+const FINGERPRINT_LEN: usize = 64;
+
+/// Quantize the linear layers of the safetensors checkpoint in data_path and write it as a GGUF
+/// file to output. With a topology output is a directory where a GGUF file is written for every
+/// worker with its layers, and one for the master. Embeddings are stored as f16 and norms as f32.
+pub fn quantize_model(
+    data_path: &Path,
+    config: &Config,
+    quantization: Quantization,
+    topology: Option<&Topology>,
+    output: &Path,
+) -> Result<Vec<Shard>> {
+    if utils::is_gguf(data_path) {
+        bail!("only safetensors checkpoints can be quantized");
+    }
+    if topology.is_some_and(|t| t.contains_key(MASTER_SHARD)) {
+        bail!("the worker name '{MASTER_SHARD}' is reserved for the master shard");
+    }
+
+    let (st, names) = open_checkpoint(data_path)?;
+    for i in 0..config.num_hidden_layers {
+        let prefix = format!("model.layers.{i}.");
+        if !names.iter().any(|n| n.starts_with(&prefix)) {
+            bail!("no tensors found for model.layers.{i}");
+        }
+    }
+
+    // every file needs the fingerprints of all the layers in its metadata, which is written
+    // before the tensors: write placeholders of the same size and replace them once all the
+    // layers are quantized, so that every tensor is quantized once, one layer at a time
+    let placeholder = Value::String("0".repeat(FINGERPRINT_LEN));
+    let mut metadata = vec![("general.quantization_version".to_string(), Value::U32(2))];
+    metadata.extend(config.to_gguf_metadata());
+    metadata.extend(tokenizer_to_gguf_metadata(
+        &data_path.join("tokenizer.json"),
+    )?);
+    metadata.push((
+        GGUF_FINGERPRINTS_METADATA_KEY.to_string(),
+        Value::Array(vec![placeholder; config.num_hidden_layers]),
+    ));
+
+    let files: Vec<(String, Vec<&String>, PathBuf)> = match topology {
+        None => vec![(
+            "model".to_string(),
+            names.iter().collect(),
+            output.to_path_buf(),
+        )],
+        Some(topology) => {
+            std::fs::create_dir_all(output)
+                .map_err(|e| anyhow!("can't create {}: {:?}", output.display(), e))?;
+
+            let mut nodes: Vec<(String, Vec<String>)> = vec![(
+                MASTER_SHARD.to_string(),
+                master_prefixes(topology, config.num_hidden_layers),
+            )];
+            let mut workers: Vec<&String> = topology.keys().collect();
+            workers.sort();
+            for name in workers {
+                nodes.push((name.to_string(), worker_prefixes(topology, name)));
+            }
+
+            nodes
+                .into_iter()
+                .map(|(shard_name, prefixes)| {
+                    let prefixes: Vec<String> = prefixes.iter().map(|p| format!("{p}.")).collect();
+                    let shard_names = names
+                        .iter()
+                        .filter(|name| prefixes.iter().any(|p| name.starts_with(p)))
+                        .collect();
+                    let path = output.join(format!("{shard_name}.gguf"));
+                    (shard_name, shard_names, path)
+                })
+                .collect()
+        }
+    };
+
+    let mut fingerprints = vec![None; config.num_hidden_layers];
+    let mut written = vec![];
+    for (shard_name, shard_names, path) in files {
+        let (size, writer) = write_gguf(
+            &st,
+            &shard_names,
+            config,
+            quantization,
+            &metadata,
+            &path,
+            &mut fingerprints,
+        )?;
+        let shard = Shard {
+            name: shard_name,
+            tensors: shard_names.len(),
+            path,
+            size,
+        };
+        written.push((shard, writer));
+    }
+
+    let mut values = vec![];
+    for (i, fingerprint) in fingerprints.into_iter().enumerate() {
+        match fingerprint {
+            Some(fingerprint) => values.push(Value::String(fingerprint)),
+            None => bail!("model.layers.{i} is not in any file"),
+        }
+    }
+    let fingerprints = Value::Array(values);
+
+    let mut shards = vec![];
+    for (shard, mut writer) in written {
+        writer.update_metadata(GGUF_FINGERPRINTS_METADATA_KEY, &fingerprints)?;
+        writer.finish()?;
+        shards.push(shard);
+    }
+
+    Ok(shards)
+}
+
+/// Map the safetensors files of the checkpoint and return the sorted names of the tensors that
+/// have a llama.cpp name.
+fn open_checkpoint(data_path: &Path) -> Result<(MmapedSafetensors, Vec<String>)> {
+    let index = SafetensorsIndex::from_path(data_path)?;
+    let mut prefixes = vec!["model".to_string()];
+    // models with tied embeddings have no lm_head
+    if !index.tensors("lm_head").is_empty() {
+        prefixes.push("lm_head".to_string());
+    }
+    let st = unsafe { MmapedSafetensors::multi(&index.files(&prefixes)?)? };
+
+    let mut names: Vec<String> = st
+        .tensors()
+        .into_iter()
+        .map(|(name, _)| name)
+        .filter(|name| utils::gguf_name(name).is_some())
+        .collect();
+    names.sort();
+
+    Ok((st, names))
+}
+
+/// Return the GGUF type of a tensor: the quantization for the weights of the linear layers that
+/// can be split in its blocks, f16 for the other matrices and f32 for the vectors.
+fn ggml_dtype(name: &str, shape: &[usize], quantization: Quantization) -> GgmlDType {
+    let is_linear = shape.len() == 2 && !name.starts_with("model.embed_tokens.");
+    if is_linear && quantization.quantized_size(shape).is_some() {
+        quantization.ggml_dtype()
+    } else if shape.len() == 2 {
+        GgmlDType::F16
+    } else {
+        GgmlDType::F32
+    }
+}
+
+/// Read the named tensors of the checkpoint, quantizing the weights of the linear layers. Query
+/// and key weights are permuted the way llama.cpp does.
+fn quantize_tensors(
+    st: &MmapedSafetensors,
+    names: &[&String],
+    config: &Config,
+    quantization: Quantization,
+) -> Result<BTreeMap<String, QTensor>> {
+    let mut tensors = BTreeMap::new();
+    for name in names {
+        log::debug!("quantizing {name} ...");
+
+        let mut tensor = st.load(name, &Device::Cpu)?;
+        if name.ends_with(".self_attn.q_proj.weight") {
+            tensor = utils::permute_qk(&tensor, config.num_attention_heads)?;
+        } else if name.ends_with(".self_attn.k_proj.weight") {
+            tensor = utils::permute_qk(&tensor, config.num_key_value_heads)?;
+        }
+
+        let dtype = ggml_dtype(name, tensor.dims(), quantization);
+        if dtype == GgmlDType::F16 && !name.starts_with("model.embed_tokens.") {
+            log::warn!(
+                "{name} {:?} can't be split in {quantization} blocks, keeping it f16",
+                tensor.shape()
+            );
+        }
+
+        tensors.insert(name.to_string(), QTensor::quantize(&tensor, dtype)?);
+    }
+
+    Ok(tensors)
+}
+
+/// Return the layer index of a tensor of the transformer blocks.
+fn layer_index(name: &str) -> Option<usize> {
+    name.strip_prefix("model.layers.")?
+        .split_once('.')?
+        .0
+        .parse()
+        .ok()
+}
+
+/// Write the named tensors to a GGUF file at path with their llama.cpp names, quantizing them one
+/// layer at a time and recording the fingerprints of the layers. Return the size of the tensors
+/// and the writer, which still has the placeholder fingerprints in its metadata.
+fn write_gguf(
+    st: &MmapedSafetensors,
+    names: &[&String],
+    config: &Config,
+    quantization: Quantization,
+    metadata: &[(String, Value)],
+    path: &Path,
+    fingerprints: &mut [Option<String>],
+) -> Result<(u64, GgufWriter)> {
+    log::info!("writing {} ...", path.display());
+
+    let mut infos = vec![];
+    let mut size = 0u64;
+    for name in names {
+        let shape = st.get(name)?.shape().to_vec();
+        let dtype = ggml_dtype(name, &shape, quantization);
+        size += (shape.iter().product::<usize>() / dtype.block_size() * dtype.type_size()) as u64;
+        // names were filtered when reading the checkpoint
+        infos.push((utils::gguf_name(name).unwrap(), dtype, shape));
+    }
+    let mut writer = GgufWriter::create(path, metadata, &infos)?;
+
+    // names are sorted so the tensors of a layer are next to each other
+    let mut start = 0;
+    while start < names.len() {
+        let layer = layer_index(names[start]);
+        let end = match layer {
+            Some(_) => {
+                start
+                    + names[start..]
+                        .iter()
+                        .take_while(|name| layer_index(name) == layer)
+                        .count()
+            }
+            None => start + 1,
+        };
+
+        let tensors = quantize_tensors(st, &names[start..end], config, quantization)?;
+        for tensor in tensors.values() {
+            writer.write_tensor(tensor)?;
+        }
+        if let Some(i) = layer.filter(|i| *i < fingerprints.len()) {
+            let layer: Vec<(&str, &QTensor)> = tensors
+                .iter()
+                .map(|(name, tensor)| (name.as_str(), tensor))
+                .collect();
+            fingerprints[i] = Some(utils::qtensors_fingerprint(&layer)?);
+        }
+
+        start = end;
+    }
+
+    Ok((size, writer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::llama3::testing::Checkpoint,
+        spm::Node,
+        utils::{Gguf, ModelFingerprint},
+    };
+
+    /// Return the fingerprints of the layers quantized from the checkpoint.
+    fn expected_fingerprints(checkpoint: &Checkpoint, quantization: Quantization) -> Vec<String> {
+        let (st, names) = open_checkpoint(&checkpoint.dir).unwrap();
+        (0..checkpoint.config.num_hidden_layers)
+            .map(|i| {
+                let prefix = format!("model.layers.{i}.");
+                let names: Vec<&String> = names.iter().filter(|n| n.starts_with(&prefix)).collect();
+                let tensors =
+                    quantize_tensors(&st, &names, &checkpoint.config, quantization).unwrap();
+                let layer: Vec<(&str, &QTensor)> =
+                    tensors.iter().map(|(n, t)| (n.as_str(), t)).collect();
+                utils::qtensors_fingerprint(&layer).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fingerprints_match_the_written_layers() {
+        let checkpoint = Checkpoint::new("quantize-fingerprints");
+        let output = checkpoint.dir.join("model.gguf");
+        let shards = quantize_model(
+            &checkpoint.dir,
+            &checkpoint.config,
+            Quantization::Q8_0,
+            None,
+            &output,
+        )
+        .unwrap();
+        assert_eq!(shards.len(), 1);
+
+        let expected = expected_fingerprints(&checkpoint, Quantization::Q8_0);
+        let fingerprint = ModelFingerprint::new(&output).unwrap();
+        let gguf = Gguf::open(&output).unwrap();
+        let recorded = match gguf.metadata(GGUF_FINGERPRINTS_METADATA_KEY) {
+            Some(Value::Array(values)) => values.clone(),
+            _ => panic!("no fingerprints in the metadata"),
+        };
+        for (i, expected) in expected.iter().enumerate() {
+            let layer_name = format!("model.layers.{i}");
+            assert_eq!(&fingerprint.layer(&layer_name).unwrap(), expected);
+            assert_eq!(recorded[i].to_string().unwrap(), expected);
+            assert_eq!(
+                gguf.tensor_info(&format!("{layer_name}.mlp.up_proj.weight"))
+                    .unwrap()
+                    .ggml_dtype,
+                GgmlDType::Q8_0
+            );
+        }
+    }
+
+    #[test]
+    fn split_files_hold_their_layers() {
+        let checkpoint = Checkpoint::new("quantize-split");
+        let output = checkpoint.dir.join("split");
+        let mut topology = Topology::default();
+        topology.insert(
+            "w1".to_string(),
+            Node {
+                host: "127.0.0.1:10128".to_string(),
+                description: None,
+                cert: None,
+                layers: vec!["model.layers.1".to_string()],
+            },
+        );
+        let shards = quantize_model(
+            &checkpoint.dir,
+            &checkpoint.config,
+            Quantization::Q8_0,
+            Some(&topology),
+            &output,
+        )
+        .unwrap();
+        let names: Vec<&str> = shards.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, [MASTER_SHARD, "w1"]);
+
+        let master = Gguf::open(&output.join("master.gguf")).unwrap();
+        let worker = Gguf::open(&output.join("w1.gguf")).unwrap();
+        assert!(!master.tensors("model.layers.0").is_empty());
+        assert!(master.tensors("model.layers.1").is_empty());
+        assert!(!master.tensors("model.embed_tokens").is_empty());
+        assert!(worker.tensors("model.layers.0").is_empty());
+        assert!(!worker.tensors("model.layers.1").is_empty());
+        assert!(worker.tensors("model.embed_tokens").is_empty());
+
+        // layers missing from a file use the recorded fingerprints
+        let expected = expected_fingerprints(&checkpoint, Quantization::Q8_0);
+        for shard in ["master", "w1"] {
+            let fingerprint = ModelFingerprint::new(&output.join(format!("{shard}.gguf"))).unwrap();
+            for (i, expected) in expected.iter().enumerate() {
+                assert_eq!(
+                    &fingerprint.layer(&format!("model.layers.{i}")).unwrap(),
+                    expected
+                );
+            }
+        }
+    }
+}
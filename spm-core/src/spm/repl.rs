@@ -0,0 +1,311 @@
+//! Interactive mode, the chat and generation parameters edited by the commands typed by the user.
+use anyhow::Result;
+
+use crate::models::{
+    chat::{Message, MessageRole},
+    params::GenerationParams,
+};
+
+/// What the master has to do after an input.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Action {
+    /// Nothing, wait for the next input.
+    None,
+    /// Print the output of a command.
+    Print(String),
+    /// Reset the model and the workers, the chat has been cleared.
+    Reset,
+    /// Generate the reply to the last user message.
+    Generate,
+    /// Leave the interactive mode.
+    Quit,
+}
+
+/// State of the interactive mode.
+pub(crate) struct Repl {
+    /// System prompt from the command line, a new chat starts with it.
+    system_prompt: String,
+    /// Messages of the current chat.
+    pub chat: Vec<Message>,
+    /// Generation parameters, changed with /set.
+    pub params: GenerationParams,
+}
+
+impl Repl {
+    pub fn new(system_prompt: String, params: GenerationParams) -> Self {
+        let mut repl = Self {
+            system_prompt,
+            chat: vec![],
+            params,
+        };
+        repl.chat = repl.new_chat();
+        repl
+    }
+
+    /// Return a new chat with the system prompt from the command line, if any.
+    fn new_chat(&self) -> Vec<Message> {
+        if self.system_prompt.is_empty() {
+            vec![]
+        } else {
+            vec![Message::system(self.system_prompt.clone())]
+        }
+    }
+
+    /// Handle a line typed by the user, either a command or a new user message.
+    pub fn handle(&mut self, input: &str) -> Result<Action> {
+        let input = input.trim();
+
+        // 检查用户是否输入 'q' 以退出循环
+        if input == "q" {
+            Ok(Action::Quit)
+        } else if input.is_empty() {
+            Ok(Action::None)
+        } else if input.starts_with('/') {
+            // 以 / 开头的输入为命令
+            self.run_command(input)
+        } else {
+            // 使用 Message::user 来创建用户消息，并带上之前的对话
+            self.chat.push(Message::user(input.to_string()));
+            Ok(Action::Generate)
+        }
+    }
+
+    /// Add the generated reply to the chat, so that the next turns can refer to it.
+    pub fn add_reply(&mut self, reply: String) {
+        self.chat.push(Message::assistant(reply));
+    }
+
+    /// Execute an interactive command.
+    fn run_command(&mut self, input: &str) -> Result<Action> {
+        let (command, args) = input.split_once(' ').unwrap_or((input, ""));
+        let args = args.trim();
+
+        match command {
+            "/help" => Ok(Action::Print(
+                [
+                    "/reset                 start a new conversation",
+                    "/system [prompt]       show or set the system prompt",
+                    "/save <file>           save the conversation to a json file",
+                    "/load <file>           load a conversation from a json file",
+                    "/set [<name> <value>]  show or set a generation parameter",
+                    "q                      quit",
+                ]
+                .join("\n"),
+            )),
+            "/reset" => {
+                self.chat = self.new_chat();
+                Ok(Action::Reset)
+            }
+            "/system" => {
+                let current = self
+                    .chat
+                    .first()
+                    .filter(|m| matches!(m.role, MessageRole::System));
+                if args.is_empty() {
+                    return Ok(Action::Print(match current {
+                        Some(message) => message.content.clone(),
+                        None => "no system prompt".to_string(),
+                    }));
+                } else if current.is_some() {
+                    self.chat[0].content = args.to_string();
+                } else {
+                    self.chat.insert(0, Message::system(args.to_string()));
+                }
+                Ok(Action::None)
+            }
+            "/save" => {
+                if args.is_empty() {
+                    bail!("usage: /save <file>");
+                }
+                let data = serde_json::to_string_pretty(&self.chat)?;
+                std::fs::write(args, data).map_err(|e| anyhow!("can't write {args}: {e}"))?;
+                Ok(Action::Print(format!(
+                    "{} messages saved to {args}",
+                    self.chat.len()
+                )))
+            }
+            "/load" => {
+                if args.is_empty() {
+                    bail!("usage: /load <file>");
+                }
+                let data =
+                    std::fs::read_to_string(args).map_err(|e| anyhow!("can't read {args}: {e}"))?;
+                self.chat =
+                    serde_json::from_str(&data).map_err(|e| anyhow!("can't parse {args}: {e}"))?;
+                Ok(Action::Print(format!(
+                    "{} messages loaded from {args}",
+                    self.chat.len()
+                )))
+            }
+            // 使用 /set <参数> <值> 修改本次会话的采样参数
+            "/set" => {
+                if let Some((name, value)) = args.split_once(' ') {
+                    self.params.set(name, value)?;
+                } else if !args.is_empty() {
+                    bail!("usage: /set <name> <value>");
+                }
+                Ok(Action::Print(self.params.to_string()))
+            }
+            _ => bail!("unknown command {command}, type /help for a list of commands"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::Args;
+
+    fn new_repl(system_prompt: &str) -> Repl {
+        let params = GenerationParams::from(&Args::parse_from(["spm"]));
+        Repl::new(system_prompt.to_string(), params)
+    }
+
+    /// Return the chat as (role, content) pairs.
+    fn messages(repl: &Repl) -> Vec<(String, String)> {
+        repl.chat
+            .iter()
+            .map(|m| (m.role.to_string(), m.content.clone()))
+            .collect()
+    }
+
+    fn pairs(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+        expected
+            .iter()
+            .map(|(role, content)| (role.to_string(), content.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn turns_are_kept_in_the_chat() {
+        let mut repl = new_repl("be brief");
+        assert_eq!(repl.handle("").unwrap(), Action::None);
+        assert_eq!(repl.handle("  hello \n").unwrap(), Action::Generate);
+        repl.add_reply("hi".to_string());
+        assert_eq!(repl.handle("how are you?").unwrap(), Action::Generate);
+
+        assert_eq!(
+            messages(&repl),
+            pairs(&[
+                ("system", "be brief"),
+                ("user", "hello"),
+                ("assistant", "hi"),
+                ("user", "how are you?"),
+            ])
+        );
+        assert_eq!(repl.handle("q").unwrap(), Action::Quit);
+    }
+
+    #[test]
+    fn reset_starts_from_the_system_prompt() {
+        let mut repl = new_repl("be brief");
+        repl.handle("/system be verbose").unwrap();
+        repl.handle("hello").unwrap();
+        repl.add_reply("hi".to_string());
+
+        assert_eq!(repl.handle("/reset").unwrap(), Action::Reset);
+        assert_eq!(messages(&repl), pairs(&[("system", "be brief")]));
+
+        let mut repl = new_repl("");
+        repl.handle("hello").unwrap();
+        assert_eq!(repl.handle("/reset").unwrap(), Action::Reset);
+        assert!(repl.chat.is_empty());
+    }
+
+    #[test]
+    fn system_prompt_is_shown_and_replaced() {
+        let mut repl = new_repl("");
+        assert_eq!(
+            repl.handle("/system").unwrap(),
+            Action::Print("no system prompt".to_string())
+        );
+
+        // added before the other messages
+        repl.handle("hello").unwrap();
+        repl.handle("/system be brief").unwrap();
+        assert_eq!(
+            messages(&repl),
+            pairs(&[("system", "be brief"), ("user", "hello")])
+        );
+
+        repl.handle("/system  be   verbose ").unwrap();
+        assert_eq!(
+            messages(&repl),
+            pairs(&[("system", "be   verbose"), ("user", "hello")])
+        );
+        assert_eq!(
+            repl.handle("/system").unwrap(),
+            Action::Print("be   verbose".to_string())
+        );
+    }
+
+    #[test]
+    fn chats_are_saved_and_loaded() {
+        let dir = std::env::temp_dir().join(format!("spm-repl-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("chat.json");
+        let path = path.to_str().unwrap();
+
+        let mut repl = new_repl("be brief");
+        repl.handle("hello \"there\"\nhow are you?").unwrap();
+        repl.add_reply("fine, 你好 🦀".to_string());
+        let saved = messages(&repl);
+        assert_eq!(
+            repl.handle(&format!("/save {path}")).unwrap(),
+            Action::Print(format!("3 messages saved to {path}"))
+        );
+
+        // saved as the messages of a chat completion request
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(json[1]["role"], "user");
+        assert_eq!(json[2]["content"], "fine, 你好 🦀");
+
+        let mut other = new_repl("");
+        assert_eq!(
+            other.handle(&format!("/load {path}")).unwrap(),
+            Action::Print(format!("3 messages loaded from {path}"))
+        );
+        assert_eq!(messages(&other), saved);
+
+        // a failed load keeps the chat
+        std::fs::write(path, "not json").unwrap();
+        assert!(other.handle(&format!("/load {path}")).is_err());
+        assert!(other.handle("/load").is_err());
+        assert!(other.handle("/save").is_err());
+        assert_eq!(messages(&other), saved);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn params_are_set() {
+        let mut repl = new_repl("");
+        let defaults = repl.params.clone();
+        assert_eq!(
+            repl.handle("/set").unwrap(),
+            Action::Print(defaults.to_string())
+        );
+
+        assert!(matches!(
+            repl.handle("/set temperature 0.5").unwrap(),
+            Action::Print(_)
+        ));
+        assert_eq!(repl.params.temperature, 0.5);
+
+        assert!(repl.handle("/set temperature").is_err());
+        assert!(repl.handle("/set temperature -1").is_err());
+        assert_eq!(repl.params.temperature, 0.5);
+    }
+
+    #[test]
+    fn unknown_commands_are_errors() {
+        let mut repl = new_repl("");
+        let e = repl.handle("/quit").unwrap_err().to_string();
+        assert!(e.contains("/quit"), "{e}");
+        assert!(matches!(repl.handle("/help").unwrap(), Action::Print(_)));
+        assert!(repl.chat.is_empty());
+    }
+}
@@ -0,0 +1,308 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use candle_core::Tensor;
+
+use crate::{
+    models::llama3::{Cache, Config},
+    utils::Weights,
+};
+
+use super::{Client, Forwarder, Reconnected, WorkerError};
+
+/// Time after which a failed worker is tried again.
+const RETRY_AFTER: Duration = Duration::from_secs(30);
+
+/// Addresses of the workers that failed and when, shared by all the replica sets.
+pub type Health = Arc<Mutex<HashMap<String, Instant>>>;
+
+/// Connections to several workers serving the same layer. Requests go to the active replica,
+/// when it fails the next healthy one takes over and the caller is asked to replay the token
+/// history to rebuild its kv-cache.
+#[derive(Debug)]
+pub struct ReplicaSet {
+    replicas: Vec<Client>,
+    active: usize,
+    health: Health,
+}
+
+impl ReplicaSet {
+    /// Create a replica set, the first client is the primary.
+    pub fn new(replicas: Vec<Client>, health: Health) -> Result<Self> {
+        if replicas.is_empty() {
+            bail!("a replica set needs at least one worker");
+        }
+        Ok(Self {
+            replicas,
+            active: 0,
+            health,
+        })
+    }
+
+    /// Return the index of the replica to use, skipping the ones that failed less than
+    /// RETRY_AFTER ago.
+    fn current(&self) -> Result<usize> {
+        let down = self.health.lock().unwrap();
+        (0..self.replicas.len())
+            .map(|i| (self.active + i) % self.replicas.len())
+            .find(|&i| match down.get(self.replicas[i].ident()) {
+                Some(failed_at) => failed_at.elapsed() >= RETRY_AFTER,
+                None => true,
+            })
+            .ok_or_else(|| anyhow!("no healthy replica for {}", self.layer_name()))
+    }
+
+    /// Switch to the current replica. If it's not the active one anymore and the request
+    /// depends on previous tokens, return Reconnected since they're not in its kv-cache.
+    fn select(&mut self, index_pos: usize) -> Result<()> {
+        let current = self.current()?;
+        if current != self.active {
+            log::warn!(
+                "{} is down, switching {} to {}",
+                self.replicas[self.active].ident(),
+                self.layer_name(),
+                self.replicas[current].ident()
+            );
+            self.active = current;
+            if index_pos > 0 {
+                return Err(Reconnected {
+                    worker: self.ident().to_string(),
+                }
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle the error of the active replica, failing over to the next healthy one if the
+    /// worker can't be reached anymore.
+    fn failover(&mut self, e: anyhow::Error) -> anyhow::Error {
+        // errors reported by the worker and recovered connections are not failures
+        if e.is::<WorkerError>() || e.is::<Reconnected>() {
+            return e;
+        }
+
+        log::error!("{e}");
+        let address = self.replicas[self.active].ident().to_string();
+        self.health.lock().unwrap().insert(address, Instant::now());
+
+        let failed = self.active;
+        match self.current() {
+            Ok(current) => self.active = current,
+            // the failed replicas are tried again after RETRY_AFTER
+            Err(err) => return anyhow!("{err}: {e}"),
+        }
+
+        log::warn!(
+            "failing over {} from {} to {}",
+            self.layer_name(),
+            self.replicas[failed].ident(),
+            self.ident()
+        );
+
+        Reconnected {
+            worker: self.ident().to_string(),
+        }
+        .into()
+    }
+
+    /// Handle the result of the active replica, a successful request means its worker is up
+    /// again if it failed before.
+    fn check<T>(&mut self, res: Result<T>) -> Result<T> {
+        match res {
+            Ok(res) => {
+                let mut health = self.health.lock().unwrap();
+                if health.remove(self.replicas[self.active].ident()).is_some() {
+                    log::info!("{} is back", self.replicas[self.active].ident());
+                }
+                Ok(res)
+            }
+            Err(e) => Err(self.failover(e)),
+        }
+    }
+}
+
+impl std::fmt::Display for ReplicaSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", &self.replicas[self.active])?;
+        let standby: Vec<&str> = self
+            .replicas
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != self.active)
+            .map(|(_, r)| r.ident())
+            .collect();
+        write!(f, " standby=[{}]", standby.join(","))
+    }
+}
+
+#[async_trait]
+impl Forwarder for ReplicaSet {
+    fn load(_: String, _: Weights, _: &Config) -> Result<Box<Self>> {
+        Err(anyhow!("load should never be called on spm::ReplicaSet"))
+    }
+
+    async fn forward(&self, _: &Tensor, _: usize, _: usize, _: &mut Cache) -> Result<Tensor> {
+        Err(anyhow!(
+            "immutable forward should never be called on spm::ReplicaSet"
+        ))
+    }
+
+    async fn forward_mut(
+        &mut self,
+        x: &Tensor,
+        index_pos: usize,
+        block_idx: usize,
+        cache: &mut Cache,
+    ) -> Result<Tensor> {
+        self.select(index_pos)?;
+        let res = self.replicas[self.active]
+            .forward_mut(x, index_pos, block_idx, cache)
+            .await;
+        self.check(res)
+    }
+
+    async fn forward_batch(
+        &mut self,
+        x: &Tensor,
+        batch: Vec<(String, usize, usize)>,
+        cache: &mut Cache,
+    ) -> Result<Tensor> {
+        let index_pos = batch.first().map(|(_, pos, _)| *pos).unwrap_or(0);
+        self.select(index_pos)?;
+        let res = self.replicas[self.active]
+            .forward_batch(x, batch, cache)
+            .await;
+        self.check(res)
+    }
+
+    /// Clears the kv-cache of the active replica, the standby ones rebuild theirs from the
+    /// first token when they take over.
+    async fn reset_cache(&mut self) -> Result<()> {
+        loop {
+            self.select(0)?;
+            let res = self.replicas[self.active].reset_cache().await;
+            match self.check(res) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    // reset the replica that took over as well
+                    if !e.is::<Reconnected>() {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+
+    fn layer_name(&self) -> &str {
+        self.replicas[self.active].layer_name()
+    }
+
+    fn ident(&self) -> &str {
+        self.replicas[self.active].ident()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device};
+
+    use super::*;
+    use crate::spm::{stand_in, Auth, PROBE_LAYER};
+
+    async fn replica_set(num_replicas: usize) -> (ReplicaSet, Health) {
+        let options = stand_in::client_options();
+        let mut replicas = vec![];
+        for _ in 0..num_replicas {
+            let address = stand_in::spawn(1, Duration::ZERO).await.unwrap();
+            let client = Client::new(
+                Device::Cpu,
+                &address,
+                PROBE_LAYER,
+                &stand_in::hello(),
+                &Auth::default(),
+                &options,
+            )
+            .await
+            .unwrap();
+            replicas.push(client);
+        }
+        let health = Health::default();
+        (ReplicaSet::new(replicas, health.clone()).unwrap(), health)
+    }
+
+    fn hidden_state() -> Tensor {
+        Tensor::zeros((1, 1, stand_in::HIDDEN_SIZE), DType::F32, &Device::Cpu).unwrap()
+    }
+
+    #[tokio::test]
+    async fn failed_replicas_are_skipped() {
+        let (mut replicas, health) = replica_set(2).await;
+        let mut cache = stand_in::cache().unwrap();
+        let primary = replicas.replicas[0].ident().to_string();
+        let standby = replicas.replicas[1].ident().to_string();
+
+        health
+            .lock()
+            .unwrap()
+            .insert(primary.clone(), Instant::now());
+        // the kv-cache of the standby replica doesn't have the previous tokens
+        let err = replicas
+            .forward_mut(&hidden_state(), 1, 0, &mut cache)
+            .await
+            .unwrap_err();
+        assert!(err.is::<Reconnected>(), "{err}");
+        assert_eq!(replicas.ident(), standby);
+
+        replicas
+            .forward_mut(&hidden_state(), 0, 0, &mut cache)
+            .await
+            .unwrap();
+        assert!(health.lock().unwrap().contains_key(&primary));
+    }
+
+    #[tokio::test]
+    async fn no_healthy_replica() {
+        let (mut replicas, health) = replica_set(2).await;
+        let mut cache = stand_in::cache().unwrap();
+        for replica in &replicas.replicas {
+            health
+                .lock()
+                .unwrap()
+                .insert(replica.ident().to_string(), Instant::now());
+        }
+
+        let err = replicas
+            .forward_mut(&hidden_state(), 0, 0, &mut cache)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("no healthy replica"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn failed_replicas_recover() {
+        let (mut replicas, health) = replica_set(2).await;
+        let mut cache = stand_in::cache().unwrap();
+        let failed_at = Instant::now().checked_sub(RETRY_AFTER).unwrap();
+        for replica in &replicas.replicas {
+            health
+                .lock()
+                .unwrap()
+                .insert(replica.ident().to_string(), failed_at);
+        }
+
+        // the retry interval has passed, a successful request marks the replica healthy
+        replicas
+            .forward_mut(&hidden_state(), 0, 0, &mut cache)
+            .await
+            .unwrap();
+        let health = health.lock().unwrap();
+        assert!(!health.contains_key(replicas.replicas[0].ident()));
+        assert!(health.contains_key(replicas.replicas[1].ident()));
+    }
+}
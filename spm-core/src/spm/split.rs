@@ -0,0 +1,303 @@
+//! Split a checkpoint in per-node directories, each holding only the tensors the node loads.
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use memmap2::{Mmap, MmapOptions};
+use safetensors::SafeTensors;
+
+use super::{Topology, PROBE_LAYER};
+use crate::utils::{self, ModelFingerprint, SafetensorsIndex, FINGERPRINTS_METADATA_KEY};
+
+/// Name of the directory holding the tensors of the master.
+pub const MASTER_SHARD: &str = "master";
+
+/// Tensors outside of the transformer blocks, always loaded by the master.
+pub const MASTER_TENSORS: &[&str] = &["model.embed_tokens", "model.norm", "lm_head"];
+
+/// Files copied as they are in every shard, if present.
+const MODEL_FILES: &[&str] = &[
+    "config.json",
+    "generation_config.json",
+    "tokenizer.json",
+    "tokenizer_config.json",
+    "special_tokens_map.json",
+];
+
+/// A directory written by split_model.
+#[derive(Debug, Clone)]
+pub struct Shard {
+    /// Worker name or MASTER_SHARD.
+    pub name: String,
+    pub path: PathBuf,
+    /// Number of tensors in the shard.
+    pub tensors: usize,
+    /// Size in bytes of the tensors.
+    pub size: u64,
+}
+
+impl std::fmt::Display for Shard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} -> {} ({} tensors, {})",
+            &self.name,
+            self.path.display(),
+            self.tensors,
+            human_bytes::human_bytes(self.size as f64)
+        )
+    }
+}
+
+/// Return the tensor prefixes the master loads: the embeddings, the final norm, the lm_head and
+/// the layers that no worker serves.
+pub fn master_prefixes(topology: &Topology, num_layers: usize) -> Vec<String> {
+    let mut prefixes: Vec<String> = MASTER_TENSORS.iter().map(|t| t.to_string()).collect();
+    for i in 0..num_layers {
+        let layer_name = format!("model.layers.{i}");
+        if topology.get_nodes_for_layer(&layer_name).is_empty() {
+            prefixes.push(layer_name);
+        }
+    }
+    prefixes
+}
+
+/// Return the tensor prefixes a worker loads: its layers, or the probe layer if it has none.
+pub fn worker_prefixes(topology: &Topology, worker_name: &str) -> Vec<String> {
+    match topology.get_node_for_worker(worker_name) {
+        Some((_, node)) if !node.layers.is_empty() => node.layers.clone(),
+        _ => vec![PROBE_LAYER.to_string()],
+    }
+}
+
+/// Split the checkpoint in data_path writing a directory for every worker of the topology with
+/// its layers and one for the master, each with its own safetensors index and a copy of the
+/// configuration and tokenizer.
+pub fn split_model(
+    data_path: &Path,
+    topology: &Topology,
+    num_layers: usize,
+    output: &Path,
+) -> Result<Vec<Shard>> {
+    if utils::is_gguf(data_path) {
+        bail!("only safetensors checkpoints can be split");
+    }
+    if topology.contains_key(MASTER_SHARD) {
+        bail!("the worker name '{MASTER_SHARD}' is reserved for the master shard");
+    }
+
+    let mut nodes: Vec<(String, Vec<String>)> = vec![(
+        MASTER_SHARD.to_string(),
+        master_prefixes(topology, num_layers),
+    )];
+    let mut names: Vec<&String> = topology.keys().collect();
+    names.sort();
+    for name in names {
+        nodes.push((name.to_string(), worker_prefixes(topology, name)));
+    }
+
+    // only map the files holding tensors of some node
+    let prefixes: Vec<String> = nodes
+        .iter()
+        .flat_map(|(_, prefixes)| prefixes.iter().cloned())
+        .collect();
+    let filenames = SafetensorsIndex::from_path(data_path)?.files(&prefixes)?;
+
+    let mut files: Vec<(PathBuf, Mmap)> = vec![];
+    for path in filenames {
+        let file = std::fs::File::open(&path)
+            .map_err(|e| anyhow!("can't open {}: {:?}", path.display(), e))?;
+        let mmap = unsafe { MmapOptions::new().map(&file) }
+            .map_err(|e| anyhow!("can't map {}: {:?}", path.display(), e))?;
+        files.push((path, mmap));
+    }
+    // keep the output deterministic
+    files.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    // the master can't hash the layers it doesn't have, record them in every index
+    log::info!("computing the fingerprints of {num_layers} layers ...");
+    let fingerprint = ModelFingerprint::new(data_path)?;
+    let mut fingerprints = serde_json::Map::new();
+    for i in 0..num_layers {
+        let layer_name = format!("model.layers.{i}");
+        let layer_fingerprint = fingerprint.layer(&layer_name)?;
+        fingerprints.insert(layer_name, layer_fingerprint.into());
+    }
+
+    let mut shards = vec![];
+    for (name, prefixes) in nodes {
+        let path = output.join(&name);
+        log::info!("writing {} ...", path.display());
+
+        let shard = write_shard(data_path, &files, &prefixes, &fingerprints, &path)?;
+        shards.push(Shard {
+            name,
+            path,
+            tensors: shard.0,
+            size: shard.1,
+        });
+    }
+
+    Ok(shards)
+}
+
+/// Write the tensors matching the prefixes to path, keeping the original file names. Returns
+/// the number of tensors written and their size.
+fn write_shard(
+    data_path: &Path,
+    files: &[(PathBuf, Mmap)],
+    prefixes: &[String],
+    fingerprints: &serde_json::Map<String, serde_json::Value>,
+    path: &Path,
+) -> Result<(usize, u64)> {
+    std::fs::create_dir_all(path)
+        .map_err(|e| anyhow!("can't create {}: {:?}", path.display(), e))?;
+
+    for file_name in MODEL_FILES {
+        let source = data_path.join(file_name);
+        if source.exists() {
+            std::fs::copy(&source, path.join(file_name))
+                .map_err(|e| anyhow!("can't copy {}: {:?}", source.display(), e))?;
+        }
+    }
+
+    let prefixes: Vec<String> = prefixes.iter().map(|p| format!("{p}.")).collect();
+    let mut found: HashMap<&str, usize> = prefixes.iter().map(|p| (p.as_str(), 0)).collect();
+    let mut weight_map = BTreeMap::new();
+    let mut total_size = 0u64;
+
+    for (source, mmap) in files {
+        let st = SafeTensors::deserialize(mmap)
+            .map_err(|e| anyhow!("can't read {}: {:?}", source.display(), e))?;
+
+        let mut tensors = vec![];
+        for (name, view) in st.tensors() {
+            if let Some(prefix) = prefixes.iter().find(|p| name.starts_with(p.as_str())) {
+                *found.get_mut(prefix.as_str()).unwrap() += 1;
+                total_size += view.data().len() as u64;
+                tensors.push((name, view));
+            }
+        }
+        if tensors.is_empty() {
+            continue;
+        }
+
+        let file_name = source.file_name().unwrap().to_string_lossy().to_string();
+        for (name, _) in &tensors {
+            weight_map.insert(name.to_string(), file_name.clone());
+        }
+
+        let dest = path.join(&file_name);
+        safetensors::serialize_to_file(tensors, &None, &dest)
+            .map_err(|e| anyhow!("can't write {}: {:?}", dest.display(), e))?;
+    }
+
+    let mut missing: Vec<&str> = found
+        .iter()
+        .filter(|(_, count)| **count == 0)
+        .map(|(prefix, _)| prefix.trim_end_matches('.'))
+        .collect();
+    if !missing.is_empty() {
+        missing.sort();
+        bail!("no tensors found for {}", missing.join(", "));
+    }
+
+    let index = serde_json::json!({
+        "metadata": {
+            "total_size": total_size,
+            FINGERPRINTS_METADATA_KEY: fingerprints,
+        },
+        "weight_map": weight_map,
+    });
+    let index_path = path.join("model.safetensors.index.json");
+    std::fs::write(&index_path, serde_json::to_string_pretty(&index)?)
+        .map_err(|e| anyhow!("can't write {}: {:?}", index_path.display(), e))?;
+
+    Ok((weight_map.len(), total_size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::llama3::testing::Checkpoint, spm::Node};
+
+    fn node(layers: &[&str]) -> Node {
+        Node {
+            host: "127.0.0.1:10128".to_string(),
+            description: None,
+            cert: None,
+            layers: layers.iter().map(|l| l.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn shards_hold_what_their_node_loads() {
+        let checkpoint = Checkpoint::new("split");
+        let output = checkpoint.dir.join("split");
+        let num_layers = checkpoint.config.num_hidden_layers;
+        let mut topology = Topology::default();
+        topology.insert("w1".to_string(), node(&["model.layers.1"]));
+        // waiting for the planner, loads the probe layer
+        topology.insert("w2".to_string(), node(&[]));
+
+        let shards = split_model(&checkpoint.dir, &topology, num_layers, &output).unwrap();
+        let names: Vec<&str> = shards.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, [MASTER_SHARD, "w1", "w2"]);
+
+        let expected = ModelFingerprint::new(&checkpoint.dir).unwrap();
+        for shard in &shards {
+            let prefixes = if shard.name == MASTER_SHARD {
+                master_prefixes(&topology, num_layers)
+            } else {
+                worker_prefixes(&topology, &shard.name)
+            };
+            let index = SafetensorsIndex::from_path(&shard.path).unwrap();
+            assert_eq!(
+                index.files(&prefixes).unwrap(),
+                [shard.path.join("model.safetensors")]
+            );
+            assert!(shard.path.join("tokenizer.json").exists());
+
+            // nothing else is in the shard
+            let others: Vec<String> = (0..num_layers)
+                .map(|i| format!("model.layers.{i}"))
+                .chain(MASTER_TENSORS.iter().map(|t| t.to_string()))
+                .filter(|prefix| !prefixes.contains(prefix))
+                .collect();
+            for prefix in others {
+                assert!(
+                    index.tensors(&prefix).is_empty(),
+                    "{} {prefix}",
+                    &shard.name
+                );
+            }
+
+            // every shard knows the fingerprints of all the layers
+            let fingerprint = ModelFingerprint::new(&shard.path).unwrap();
+            for i in 0..num_layers {
+                let layer_name = format!("model.layers.{i}");
+                assert_eq!(
+                    fingerprint.layer(&layer_name).unwrap(),
+                    expected.layer(&layer_name).unwrap()
+                );
+            }
+        }
+        assert_eq!(worker_prefixes(&topology, "w2"), [PROBE_LAYER.to_string()]);
+    }
+
+    #[test]
+    fn master_shard_name_is_reserved() {
+        let checkpoint = Checkpoint::new("split-reserved");
+        let mut topology = Topology::default();
+        topology.insert(MASTER_SHARD.to_string(), node(&["model.layers.1"]));
+        assert!(split_model(
+            &checkpoint.dir,
+            &topology,
+            checkpoint.config.num_hidden_layers,
+            &checkpoint.dir.join("split"),
+        )
+        .is_err());
+    }
+}
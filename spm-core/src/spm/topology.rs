@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use anyhow::Result;
 use lazy_static::lazy_static;
@@ -7,6 +7,52 @@ use serde::{Deserialize, Serialize};
 
 lazy_static! {
     static ref LAYER_RANGE_PARSER: Regex = Regex::new(r"(?m)^(.+[^\d])(\d+)-(\d+)$").unwrap();
+    static ref BLOCK_NAME_PARSER: Regex = Regex::new(r"^model\.layers\.(\d+)$").unwrap();
+}
+
+/// Severity of a problem found in the topology.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// The topology works but probably not as intended.
+    Warning,
+    /// The model can't run with this topology.
+    Error,
+}
+
+/// A problem found while validating the topology.
+#[derive(Clone, Debug)]
+pub struct TopologyIssue {
+    pub severity: Severity,
+    pub worker: Option<String>,
+    pub layer: Option<String>,
+    pub message: String,
+}
+
+impl TopologyIssue {
+    fn new(severity: Severity, worker: Option<&str>, layer: Option<&str>, message: String) -> Self {
+        Self {
+            severity,
+            worker: worker.map(|w| w.to_string()),
+            layer: layer.map(|l| l.to_string()),
+            message,
+        }
+    }
+}
+
+impl std::fmt::Display for TopologyIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.severity {
+            Severity::Warning => write!(f, "warning")?,
+            Severity::Error => write!(f, "error")?,
+        }
+        if let Some(worker) = &self.worker {
+            write!(f, " [{worker}]")?;
+        }
+        if let Some(layer) = &self.layer {
+            write!(f, " {layer}")?;
+        }
+        write!(f, ": {}", &self.message)
+    }
 }
 
 /// A single node (worker).
@@ -15,7 +61,12 @@ pub struct Node {
     /// Address and port of the worker.
     pub host: String,
     /// Optional descriptioon.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
+    /// Optional worker certificate in PEM format, if set the connection uses TLS and only
+    /// this certificate is accepted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cert: Option<String>,
     /// Layers hosted by this worker. Range expressions are supported.
     pub layers: Vec<String>,
 }
@@ -32,8 +83,9 @@ impl Node {
     }
 }
 
-/// The topology is a worker-name -> worker-info map.
-#[derive(Clone, Serialize, Deserialize)]
+/// The topology is a worker-name -> worker-info map. Workers hosting the same layers are
+/// replicas of each other, the master fails over to the next one when a worker goes down.
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct Topology(HashMap<String, Node>);
 
 impl Topology {
@@ -53,9 +105,9 @@ impl Topology {
                     let start = caps.get(2).unwrap().as_str().to_string().parse::<usize>()?;
                     let stop = caps.get(3).unwrap().as_str().to_string().parse::<usize>()?;
 
-                    if stop <= start {
+                    if stop < start {
                         return Err(anyhow!(
-                            "invalid range expression {layer_name}, end must be > start"
+                            "invalid range expression {layer_name}, end must be >= start"
                         ));
                     }
 
@@ -73,16 +125,181 @@ impl Topology {
         Ok(topology)
     }
 
-    /// Return the node serving the specified layer, or None if not found.
-    pub fn get_node_for_layer(&self, layer_name: &str) -> Option<(&str, &Node)> {
-        for (node_name, node) in &self.0 {
-            for node_layer_name in &node.layers {
-                if layer_name == node_layer_name {
-                    return Some((node_name, node));
+    /// Save the topology to a yaml file, sorted by worker name.
+    pub fn save(&self, path: &str) -> Result<()> {
+        let sorted: BTreeMap<&String, &Node> = self.0.iter().collect();
+        let data = serde_yaml::to_string(&sorted)?;
+        std::fs::write(path, data).map_err(|e| anyhow!("can't write {path}: {e}"))
+    }
+
+    /// Check the layers of every worker against the depth of the model, returning all the
+    /// problems found. Layers not served by any worker run on the master, workers serving the
+    /// same layer must serve exactly the same layers to be replicas of each other.
+    pub fn validate(&self, num_layers: usize) -> Vec<TopologyIssue> {
+        let mut issues = vec![];
+        // layer index -> (worker name, worker blocks)
+        let mut owners: BTreeMap<usize, Vec<(&str, Vec<usize>)>> = BTreeMap::new();
+
+        let mut names: Vec<&String> = self.0.keys().collect();
+        names.sort();
+
+        for name in names {
+            let node = &self.0[name];
+            if node.layers.is_empty() {
+                issues.push(TopologyIssue::new(
+                    Severity::Warning,
+                    Some(name),
+                    None,
+                    "no layers assigned".to_string(),
+                ));
+                continue;
+            }
+
+            let mut blocks = vec![];
+            for layer_name in &node.layers {
+                let block = match BLOCK_NAME_PARSER
+                    .captures(layer_name)
+                    .and_then(|caps| caps[1].parse::<usize>().ok())
+                {
+                    Some(block) => block,
+                    None => {
+                        issues.push(TopologyIssue::new(
+                            Severity::Error,
+                            Some(name),
+                            Some(layer_name),
+                            "not a transformer block, expected model.layers.<n>".to_string(),
+                        ));
+                        continue;
+                    }
+                };
+
+                if block >= num_layers {
+                    issues.push(TopologyIssue::new(
+                        Severity::Error,
+                        Some(name),
+                        Some(layer_name),
+                        format!("past the model depth of {num_layers} layers"),
+                    ));
+                } else if blocks.contains(&block) {
+                    issues.push(TopologyIssue::new(
+                        Severity::Error,
+                        Some(name),
+                        Some(layer_name),
+                        "listed more than once".to_string(),
+                    ));
+                } else {
+                    if blocks.last().is_some_and(|last| *last > block) {
+                        issues.push(TopologyIssue::new(
+                            Severity::Warning,
+                            Some(name),
+                            Some(layer_name),
+                            "listed after higher layers, keep the layers in ascending order"
+                                .to_string(),
+                        ));
+                    }
+                    blocks.push(block);
                 }
             }
+
+            let mut sorted = blocks.clone();
+            sorted.sort();
+            for block in blocks {
+                owners
+                    .entry(block)
+                    .or_default()
+                    .push((name.as_str(), sorted.clone()));
+            }
+        }
+
+        for (block, workers) in &owners {
+            let (primary, layers) = &workers[0];
+            for (other, other_layers) in &workers[1..] {
+                if layers != other_layers {
+                    issues.push(TopologyIssue::new(
+                        Severity::Error,
+                        Some(other),
+                        Some(&format!("model.layers.{block}")),
+                        format!("also served by {primary}, replicas must serve the same layers"),
+                    ));
+                }
+            }
+        }
+
+        // group the layers nobody serves in ranges
+        let mut gaps: Vec<(usize, usize)> = vec![];
+        for block in (0..num_layers).filter(|b| !owners.contains_key(b)) {
+            match gaps.last_mut() {
+                Some((_, stop)) if *stop + 1 == block => *stop = block,
+                _ => gaps.push((block, block)),
+            }
+        }
+        for (start, stop) in gaps {
+            let range = if start == stop {
+                format!("model.layers.{start}")
+            } else {
+                format!("model.layers.{start}-{stop}")
+            };
+            issues.push(TopologyIssue::new(
+                Severity::Warning,
+                None,
+                Some(&range),
+                "not served by any worker, will run on the master".to_string(),
+            ));
+        }
+
+        issues
+    }
+
+    /// Validate the topology logging every problem found, fails if any of them is an error.
+    pub fn check(&self, num_layers: usize) -> Result<()> {
+        let issues = self.validate(num_layers);
+        for issue in &issues {
+            match issue.severity {
+                Severity::Warning => log::warn!("{issue}"),
+                Severity::Error => log::error!("{issue}"),
+            }
         }
-        None
+
+        let errors = issues
+            .iter()
+            .filter(|i| i.severity == Severity::Error)
+            .count();
+        if errors > 0 {
+            bail!("invalid topology, {errors} error(s) found");
+        }
+
+        Ok(())
+    }
+
+    /// Return the node of the named worker, or the first one by name if the worker is not in
+    /// the topology.
+    pub fn get_node_for_worker(&self, worker_name: &str) -> Option<(&str, &Node)> {
+        if let Some((name, node)) = self.0.get_key_value(worker_name) {
+            return Some((name.as_str(), node));
+        }
+        self.0
+            .iter()
+            .min_by_key(|(name, _)| name.as_str())
+            .map(|(name, node)| (name.as_str(), node))
+    }
+
+    /// Return the node serving the specified layer, or None if not found.
+    /// When several nodes serve the layer the first one by name is returned.
+    pub fn get_node_for_layer(&self, layer_name: &str) -> Option<(&str, &Node)> {
+        self.get_nodes_for_layer(layer_name).into_iter().next()
+    }
+
+    /// Return all the nodes serving the specified layer sorted by name, the first one is the
+    /// primary and the others are replicas.
+    pub fn get_nodes_for_layer(&self, layer_name: &str) -> Vec<(&str, &Node)> {
+        let mut nodes: Vec<(&str, &Node)> = self
+            .0
+            .iter()
+            .filter(|(_, node)| node.layers.iter().any(|l| l == layer_name))
+            .map(|(name, node)| (name.as_str(), node))
+            .collect();
+        nodes.sort_by_key(|(name, _)| *name);
+        nodes
     }
 }
 
@@ -98,3 +315,134 @@ impl std::ops::DerefMut for Topology {
         &mut self.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Load a topology from its yaml.
+    fn load(name: &str, yaml: &str) -> Result<Topology> {
+        let path =
+            std::env::temp_dir().join(format!("spm-topology-{name}-{}.yml", std::process::id()));
+        std::fs::write(&path, yaml).unwrap();
+        let topology = Topology::from_path(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        topology
+    }
+
+    /// Return the issues as (severity, worker, layer) sorted by worker and layer.
+    fn issues(topology: &Topology, num_layers: usize) -> Vec<(Severity, String, String)> {
+        let mut issues: Vec<_> = topology
+            .validate(num_layers)
+            .into_iter()
+            .map(|i| {
+                (
+                    i.severity,
+                    i.worker.unwrap_or_default(),
+                    i.layer.unwrap_or_default(),
+                )
+            })
+            .collect();
+        issues.sort_by(|a, b| (&a.1, &a.2).cmp(&(&b.1, &b.2)));
+        issues
+    }
+
+    fn issue(severity: Severity, worker: &str, layer: &str) -> (Severity, String, String) {
+        (severity, worker.to_string(), layer.to_string())
+    }
+
+    #[test]
+    fn ranges_are_expanded() {
+        let topology = load(
+            "ranges",
+            "w1:\n  host: 127.0.0.1:10128\n  layers:\n    - model.layers.0-2\n    - model.layers.5-5\n",
+        )
+        .unwrap();
+        assert_eq!(
+            topology["w1"].layers,
+            [
+                "model.layers.0",
+                "model.layers.1",
+                "model.layers.2",
+                "model.layers.5"
+            ]
+        );
+
+        assert!(load(
+            "reversed-range",
+            "w1:\n  host: 127.0.0.1:10128\n  layers:\n    - model.layers.2-1\n",
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn complete_topology_is_valid() {
+        let topology = load(
+            "valid",
+            "w1:\n  host: 127.0.0.1:10128\n  layers:\n    - model.layers.0-3\n\
+             w2:\n  host: 127.0.0.1:10129\n  layers:\n    - model.layers.4-7\n\
+             w3:\n  host: 127.0.0.1:10130\n  layers:\n    - model.layers.4-7\n",
+        )
+        .unwrap();
+        assert!(topology.validate(8).is_empty());
+        assert!(topology.check(8).is_ok());
+    }
+
+    #[test]
+    fn overlapping_workers() {
+        let topology = load(
+            "overlaps",
+            "w1:\n  host: 127.0.0.1:10128\n  layers:\n    - model.layers.0-3\n\
+             w2:\n  host: 127.0.0.1:10129\n  layers:\n    - model.layers.2-5\n",
+        )
+        .unwrap();
+        assert_eq!(
+            issues(&topology, 6),
+            [
+                issue(Severity::Error, "w2", "model.layers.2"),
+                issue(Severity::Error, "w2", "model.layers.3"),
+            ]
+        );
+        assert!(topology.check(6).is_err());
+    }
+
+    #[test]
+    fn gaps_run_on_the_master() {
+        let topology = load(
+            "gaps",
+            "w1:\n  host: 127.0.0.1:10128\n  layers:\n    - model.layers.1-2\n    - model.layers.5-5\n",
+        )
+        .unwrap();
+        assert_eq!(
+            issues(&topology, 8),
+            [
+                issue(Severity::Warning, "", "model.layers.0"),
+                issue(Severity::Warning, "", "model.layers.3-4"),
+                issue(Severity::Warning, "", "model.layers.6-7"),
+            ]
+        );
+        assert!(topology.check(8).is_ok());
+    }
+
+    #[test]
+    fn invalid_layers() {
+        let topology = load(
+            "invalid",
+            "w1:\n  host: 127.0.0.1:10128\n  layers:\n    - model.layers.2-5\n\
+             w2:\n  host: 127.0.0.1:10129\n  layers:\n    - model.layers.1\n    - model.layers.0\n    - model.layers.1\n    - lm_head\n\
+             w3:\n  host: 127.0.0.1:10130\n  layers: []\n",
+        )
+        .unwrap();
+        assert_eq!(
+            issues(&topology, 4),
+            [
+                issue(Severity::Error, "w1", "model.layers.4"),
+                issue(Severity::Error, "w1", "model.layers.5"),
+                issue(Severity::Error, "w2", "lm_head"),
+                issue(Severity::Warning, "w2", "model.layers.0"),
+                issue(Severity::Error, "w2", "model.layers.1"),
+                issue(Severity::Warning, "w3", ""),
+            ]
+        );
+    }
+}
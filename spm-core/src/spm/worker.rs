@@ -5,11 +5,17 @@ use std::{
     time::{Duration, Instant},
 };
 
-use super::{Context, Forwarder, Message, WorkerInfo};
-use crate::models::{llama3::Cache, Generator};
+use super::{
+    Compression, Context, ErrorCode, Forwarder, Hello, Message, ProbeResult, RawTensor, WorkerAuth,
+    WorkerInfo, PROTO_VERSION,
+};
+use crate::{
+    models::{llama3::Cache, Generator},
+    utils,
+};
 
 use anyhow::Result;
-use candle_core::{DType, Device};
+use candle_core::{DType, Device, Tensor};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
@@ -18,21 +24,45 @@ use tokio::{
 /// Determines how often worker statistics are calculated and printed.
 const NUM_OPS_TO_STATS: usize = 5;
 
+/// Layer loaded to answer probes by workers without layers.
+pub const PROBE_LAYER: &str = "model.layers.0";
+
 /// A single worker state.
 #[derive(Clone)]
 struct WorkerContext<F> {
     device: Device,
     device_idx: usize,
     dtype: DType,
+    config_hash: String,
+    fingerprint: String,
+    /// Quantization of the loaded weights, reported to the master.
+    quantization: Option<String>,
     blocks: Arc<HashMap<String, Box<F>>>,
+    /// Layer used to answer probes when the worker doesn't serve any.
+    probe_block: Option<Arc<F>>,
+    /// Memory reported to probes instead of the available memory.
+    max_memory: Option<u64>,
+    /// Size of the loaded layers, reported to probes as available since reassigning the layers
+    /// frees it.
+    loaded_memory: u64,
     cache: Cache,
 }
 
 impl<F: Forwarder> WorkerContext<F> {
+    /// Create the handshake describing what this worker serves.
+    fn to_hello(&self, model: &str) -> Hello {
+        Hello::new(model, &self.config_hash, self.dtype, Compression::None)
+    }
+
     /// Create a WorkerInfo structure to be sent to the master.
-    fn to_info(&self, latency: u128) -> WorkerInfo {
+    fn to_info(
+        &self,
+        latency: u128,
+        capabilities: Vec<String>,
+        compression: Compression,
+    ) -> WorkerInfo {
         WorkerInfo {
-            version: env!("CARGO_PKG_VERSION").to_string(),
+            version: PROTO_VERSION,
             os: std::env::consts::OS.to_string(),
             arch: std::env::consts::ARCH.to_string(),
             device: if self.device.is_cuda() {
@@ -44,17 +74,35 @@ impl<F: Forwarder> WorkerContext<F> {
             },
             device_idx: self.device_idx,
             latency,
-            dtype: format!("{:?}", self.dtype),
+            dtype: self.dtype.as_str().to_string(),
+            capabilities,
+            layers: self.layer_names(),
+            fingerprint: self.fingerprint.clone(),
+            compression,
+            quantization: self.quantization.clone(),
         }
     }
 
+    /// Return the sorted names of the layers served by this worker.
+    fn layer_names(&self) -> Vec<String> {
+        let mut layers: Vec<String> = self.blocks.keys().cloned().collect();
+        layers.sort();
+        layers
+    }
+
     /// Create a copy of self with new kv-cache.
     fn get_client_context(&self) -> Self {
         WorkerContext {
             device: self.device.clone(),
             device_idx: self.device_idx,
             dtype: self.dtype,
+            config_hash: self.config_hash.clone(),
+            fingerprint: self.fingerprint.clone(),
+            quantization: self.quantization.clone(),
             blocks: self.blocks.clone(),
+            probe_block: self.probe_block.clone(),
+            max_memory: self.max_memory,
+            loaded_memory: self.loaded_memory,
             // each client loop gets a new cache
             cache: self.cache.as_new(),
         }
@@ -64,6 +112,7 @@ impl<F: Forwarder> WorkerContext<F> {
 /// spm worker node.
 pub struct Worker<G: Generator> {
     listener: TcpListener,
+    auth: Arc<WorkerAuth>,
     context: WorkerContext<G::Shardable>,
 }
 
@@ -76,20 +125,22 @@ impl<G: Generator + 'static> Worker<G> {
             return Err(anyhow!("no --name provided for worker"));
         };
 
-        let worker_topology = if let Some(node) = ctx.topology.get(&worker_name) {
-            node
-        } else if !ctx.topology.is_empty() {
-            let first = ctx.topology.keys().next().unwrap();
-            log::warn!(
-                "topology for worker name '{}' not found, using '{}'",
-                &worker_name,
-                first
-            );
-            ctx.topology.get(first).unwrap()
-        } else {
-            return Err(anyhow!(
-                "could not find topology for {worker_name} and topology file is empty"
-            ));
+        let worker_topology = match ctx.topology.get_node_for_worker(&worker_name) {
+            Some((name, node)) => {
+                if name != worker_name {
+                    log::warn!(
+                        "topology for worker name '{}' not found, using '{}'",
+                        &worker_name,
+                        name
+                    );
+                }
+                node
+            }
+            None => {
+                return Err(anyhow!(
+                    "could not find topology for {worker_name} and topology file is empty"
+                ))
+            }
         };
 
         let mut blocks = HashMap::new();
@@ -105,13 +156,48 @@ impl<G: Generator + 'static> Worker<G> {
             blocks.insert(block_layer_name.to_string(), block);
         }
 
+        // workers waiting for the topology planner still need a layer to measure their speed
+        let probe_block = if blocks.is_empty() {
+            log::info!("no layers assigned, loading {PROBE_LAYER} to answer probes ...");
+            Some(Arc::from(G::Shardable::load(
+                PROBE_LAYER.to_string(),
+                ctx.var_builder.pp(PROBE_LAYER),
+                &ctx.config,
+            )?))
+        } else {
+            None
+        };
+
+        let mut loaded_layers: Vec<String> = blocks.keys().cloned().collect();
+        if probe_block.is_some() {
+            loaded_layers.push(PROBE_LAYER.to_string());
+        }
+        let loaded_memory = super::loaded_layers_size(
+            &ctx.data_path,
+            ctx.gguf.as_deref(),
+            &loaded_layers,
+            ctx.dtype,
+            ctx.args.quantize,
+        )?;
+
+        let fingerprint = ctx.fingerprint()?.layers(&worker_topology.layers)?;
+
+        log::info!("model fingerprint: {}", &fingerprint);
+
+        let quantization = ctx.var_builder.quantization();
+        if let Some(quantization) = &quantization {
+            log::info!("weights quantization: {quantization}");
+        }
+
         let blocks = Arc::new(blocks);
 
+        let auth = Arc::new(WorkerAuth::from_args(&ctx.args)?);
         let listener = TcpListener::bind(&ctx.args.address).await?;
 
         log::info!(
-            "listening on {} (mem:{}) ...",
+            "listening on {} (auth:{} mem:{}) ...",
             &ctx.args.address,
+            auth.describe(),
             human_bytes::human_bytes(memory_stats::memory_stats().unwrap().physical_mem as f64)
         );
 
@@ -119,16 +205,31 @@ impl<G: Generator + 'static> Worker<G> {
         let device = ctx.device;
         let dtype = ctx.dtype;
         let device_idx = ctx.args.device;
+        let config_hash = ctx.config_hash;
+        let max_memory = ctx
+            .args
+            .max_memory
+            .map(|gib| (gib * 1024.0 * 1024.0 * 1024.0) as u64);
 
         let context = WorkerContext {
             device,
             device_idx,
             dtype,
+            config_hash,
+            fingerprint,
+            quantization,
             blocks,
+            probe_block,
+            max_memory,
+            loaded_memory,
             cache,
         };
 
-        Ok(Self { listener, context })
+        Ok(Self {
+            listener,
+            auth,
+            context,
+        })
     }
 
     /// Read a message from the socket and return elapsed time, message size and message.
@@ -155,26 +256,174 @@ impl<G: Generator + 'static> Worker<G> {
         Ok((latency, size))
     }
 
+    /// Run a batch of operations on the input tensor and return the result, or the error
+    /// message to send back to the master.
+    async fn forward_ops(
+        context: &mut WorkerContext<G::Shardable>,
+        x: RawTensor,
+        ops: Vec<(String, usize, usize)>,
+    ) -> std::result::Result<Tensor, Message> {
+        // load raw tensor to device
+        let mut x = x
+            .to_tensor(&context.device)
+            .map_err(|e| Message::error(ErrorCode::InvalidTensor, e.to_string(), None))?;
+
+        // for each element in the ops batch
+        for (layer_name, index_pos, block_idx) in ops {
+            // get layer block by name
+            let block = match context.blocks.get(&layer_name) {
+                Some(block) => block,
+                None => {
+                    return Err(Message::error(
+                        ErrorCode::UnknownLayer,
+                        "layer not served by this worker".to_string(),
+                        Some(layer_name),
+                    ))
+                }
+            };
+
+            // run forward pass
+            x = match block
+                .forward(&x, index_pos, block_idx, &mut context.cache)
+                .await
+            {
+                Ok(x) => x,
+                Err(e) => {
+                    return Err(Message::error(
+                        ErrorCode::Forward,
+                        e.to_string(),
+                        Some(layer_name),
+                    ))
+                }
+            };
+        }
+
+        Ok(x)
+    }
+
+    /// Measure the memory available for layers and the average forward time of one of them.
+    async fn probe(
+        context: &WorkerContext<G::Shardable>,
+        x: RawTensor,
+        iterations: usize,
+    ) -> Result<ProbeResult> {
+        let block = match (context.blocks.values().next(), &context.probe_block) {
+            (Some(block), _) => block.as_ref(),
+            (None, Some(block)) => block.as_ref(),
+            (None, None) => bail!("no layer to probe"),
+        };
+
+        let x = x.to_tensor(&context.device)?;
+        // don't touch the kv-cache of the connection
+        let mut cache = context.cache.as_new();
+
+        // the first run includes one time initializations
+        block.forward(&x, 0, 0, &mut cache).await?;
+        context.device.synchronize()?;
+
+        let iterations = iterations.max(1);
+        let start = Instant::now();
+        for _ in 0..iterations {
+            block.forward(&x, 0, 0, &mut cache).await?;
+        }
+        context.device.synchronize()?;
+        let forward_time = start.elapsed().as_micros() as u64 / iterations as u64;
+
+        let memory = match context.max_memory {
+            Some(memory) => memory,
+            None => utils::available_memory(&context.device)? + context.loaded_memory,
+        };
+
+        Ok(ProbeResult {
+            memory,
+            forward_time,
+        })
+    }
+
     /// Main loop handling communication with the master.
     async fn handle_master_client(
-        mut socket: TcpStream,
+        socket: TcpStream,
         client: SocketAddr,
         mut context: WorkerContext<G::Shardable>,
+        auth: Arc<WorkerAuth>,
     ) -> Result<()> {
+        let mut socket = auth
+            .accept(socket)
+            .await
+            .map_err(|e| anyhow!("[{}] {}", &client, e))?;
+
         // read and validate Hello
-        let (latency, _size, hello) = Self::read_message_timed(&mut socket).await?;
-        if !matches!(hello, Message::Hello) {
-            return Err(anyhow!(
-                "[{}] unpexpected message instead of hello: {:?}",
-                &client,
-                hello
-            ));
+        let (latency, hello) = match Self::read_message_timed(&mut socket).await {
+            Ok((latency, _size, Message::Hello(hello))) => (latency, hello),
+            Ok((_, _, message)) => {
+                let error = Message::error(
+                    ErrorCode::UnexpectedMessage,
+                    format!("expected Hello, got {}", message.name()),
+                    None,
+                );
+                let _ = Self::write_message_timed(&mut socket, error).await;
+                return Err(anyhow!(
+                    "[{}] unpexpected message instead of hello: {}",
+                    &client,
+                    message.name()
+                ));
+            }
+            Err(e) => {
+                // older masters send a hello this version can't decode
+                let error = Message::error(
+                    ErrorCode::Handshake,
+                    format!("invalid hello, this worker uses protocol version {PROTO_VERSION}"),
+                    None,
+                );
+                let _ = Self::write_message_timed(&mut socket, error).await;
+                return Err(anyhow!("[{}] could not read hello: {}", &client, e));
+            }
+        };
+
+        // masters must prove they know the pre-shared key before anything else
+        if let Some(challenge) = auth.challenge() {
+            let message = Message::Challenge(challenge.clone());
+            if let Err(e) = Self::write_message_timed(&mut socket, message).await {
+                return Err(anyhow!("[{}] could not send challenge: {:?}", &client, e));
+            }
+            let authorized = matches!(
+                Self::read_message_timed(&mut socket).await,
+                Ok((_, _, Message::Auth(response))) if auth.verify(&challenge, &response)
+            );
+            if !authorized {
+                let error = Message::error(
+                    ErrorCode::Unauthorized,
+                    "authentication failed".to_string(),
+                    None,
+                );
+                let _ = Self::write_message_timed(&mut socket, error).await;
+                return Err(anyhow!("[{}] authentication failed", &client));
+            }
         }
 
+        // make sure we're serving the same model and agree on the optional features
+        let capabilities = match hello.negotiate(&context.to_hello(G::MODEL_NAME)) {
+            Ok(capabilities) => capabilities,
+            Err(e) => {
+                let error = Message::error(ErrorCode::Handshake, e.to_string(), None);
+                let _ = Self::write_message_timed(&mut socket, error).await;
+                return Err(anyhow!("[{}] rejected: {}", &client, e));
+            }
+        };
+
+        let compression = hello.agreed_compression(&capabilities);
+
+        log::info!(
+            "[{}] handshake ok, capabilities: {} compression: {:?}",
+            &client,
+            capabilities.join(","),
+            compression
+        );
+
         // send info
         if let Err(e) = Self::write_message_timed(
             &mut socket,
-            Message::WorkerInfo(context.to_info(latency.as_millis())),
+            Message::WorkerInfo(context.to_info(latency.as_millis(), capabilities, compression)),
         )
         .await
         {
@@ -185,12 +434,50 @@ impl<G: Generator + 'static> Worker<G> {
         let mut avg_ops = 0;
         let mut avg_write = 0;
         let mut avg_read = 0;
+        // uncompressed and wire size of the tensors, used to report the compression ratio
+        let mut raw_bytes = 0;
+        let mut wire_bytes = 0;
 
         // keep reading messages
         while let Ok((read_time, read_size, op_message)) =
             Self::read_message_timed(&mut socket).await
         {
             let (x, ops) = match op_message {
+                // new conversation, start from a clean cache
+                Message::ResetCache => {
+                    log::debug!("[{}] clearing cache", &client);
+                    context.cache.clear();
+                    if let Err(e) = Self::write_message_timed(&mut socket, Message::Ok).await {
+                        return Err(anyhow!("[{}] could not send ack: {:?}", &client, e));
+                    }
+                    continue;
+                }
+                // keep alive
+                Message::Ping => {
+                    if let Err(e) = Self::write_message_timed(&mut socket, Message::Pong).await {
+                        return Err(anyhow!("[{}] could not send pong: {:?}", &client, e));
+                    }
+                    continue;
+                }
+                // capacity measurement for the topology planner
+                Message::Probe { x, iterations } => {
+                    let response = match Self::probe(&context, x, iterations).await {
+                        Ok(result) => {
+                            log::info!(
+                                "[{}] probed: memory={} forward={}us",
+                                &client,
+                                human_bytes::human_bytes(result.memory as f64),
+                                result.forward_time
+                            );
+                            Message::ProbeResult(result)
+                        }
+                        Err(e) => Message::error(ErrorCode::Forward, e.to_string(), None),
+                    };
+                    if let Err(e) = Self::write_message_timed(&mut socket, response).await {
+                        return Err(anyhow!("[{}] could not send probe result: {:?}", &client, e));
+                    }
+                    continue;
+                }
                 // single block operation
                 Message::SingleOp {
                     layer_name,
@@ -201,37 +488,63 @@ impl<G: Generator + 'static> Worker<G> {
                 // batched
                 Message::Batch { x, batch } => (x, batch),
                 _ => {
-                    return Err(anyhow!(
-                        "[{}] unhandled message in loop: {:?}",
-                        &client,
-                        op_message
-                    ));
+                    log::error!("[{}] unhandled message in loop: {}", &client, op_message.name());
+                    let error = Message::error(
+                        ErrorCode::UnexpectedMessage,
+                        format!("unexpected {} message", op_message.name()),
+                        None,
+                    );
+                    if let Err(e) = Self::write_message_timed(&mut socket, error).await {
+                        return Err(anyhow!("[{}] could not send error: {:?}", &client, e));
+                    }
+                    continue;
                 }
             };
 
-            // load raw tensor to device
-            let mut x = x.to_tensor(&context.device).unwrap();
+            raw_bytes += x.raw_size();
+            wire_bytes += x.data.len();
+
             let num_ops = ops.len();
             let start_ops = Instant::now();
 
-            // for each element in the ops batch
-            for (layer_name, index_pos, block_idx) in ops {
-                // get layer block by name
-                if let Some(block) = context.blocks.get(&layer_name) {
-                    // run forward pass
-                    x = block
-                        .forward(&x, index_pos, block_idx, &mut context.cache)
-                        .await
-                        .unwrap();
-                } else {
-                    return Err(anyhow!("could not find layer {}", &layer_name));
+            let x = match Self::forward_ops(&mut context, x, ops).await {
+                Ok(x) => x,
+                Err(error) => {
+                    // report the error to the master and keep serving this connection
+                    if let Message::Error {
+                        code,
+                        message,
+                        layer_name,
+                    } = &error
+                    {
+                        log::error!(
+                            "[{}] {} in {}: {}",
+                            &client,
+                            code,
+                            layer_name.as_deref().unwrap_or("-"),
+                            message
+                        );
+                    }
+                    if let Err(e) = Self::write_message_timed(&mut socket, error).await {
+                        return Err(anyhow!("[{}] could not send error: {:?}", &client, e));
+                    }
+                    continue;
                 }
-            }
+            };
 
             let elaps_ops = start_ops.elapsed();
 
+            let response = match RawTensor::from_tensor(&x, compression) {
+                Ok(raw) => {
+                    raw_bytes += raw.raw_size();
+                    wire_bytes += raw.data.len();
+                    Message::Tensor(raw)
+                }
+                Err(e) => Message::error(ErrorCode::InvalidTensor, e.to_string(), None),
+            };
+
             // send response tensor
-            match Self::write_message_timed(&mut socket, Message::from_tensor(&x)).await {
+            match Self::write_message_timed(&mut socket, response).await {
                 Ok((elaps_write, written)) => {
                     let ops_per_sec = (num_ops as f64 / elaps_ops.as_secs_f64()) as usize;
                     let write_bytes_per_sec = (written as f64 / elaps_write.as_secs_f64()) as usize;
@@ -253,14 +566,18 @@ impl<G: Generator + 'static> Worker<G> {
             // compute and print stats every NUM_OPS_TO_STATS operations to avoid spamming stdout
             if msg_idx % NUM_OPS_TO_STATS == 0 {
                 log::info!(
-                    "ops={}/s read={}/s write={}/s",
+                    "ops={}/s read={}/s write={}/s compression={:?} ratio={:.2}",
                     avg_ops / NUM_OPS_TO_STATS,
                     human_bytes::human_bytes(avg_read as f64 / NUM_OPS_TO_STATS as f64),
-                    human_bytes::human_bytes(avg_write as f64 / NUM_OPS_TO_STATS as f64)
+                    human_bytes::human_bytes(avg_write as f64 / NUM_OPS_TO_STATS as f64),
+                    compression,
+                    raw_bytes as f64 / wire_bytes.max(1) as f64
                 );
                 avg_ops = 0;
                 avg_write = 0;
                 avg_read = 0;
+                raw_bytes = 0;
+                wire_bytes = 0;
             }
             msg_idx += 1;
         }
@@ -274,8 +591,9 @@ impl<G: Generator + 'static> Worker<G> {
             log::info!("{} connected", &client);
 
             let context = self.context.get_client_context();
+            let auth = self.auth.clone();
             tokio::spawn(async move {
-                if let Err(e) = Self::handle_master_client(socket, client, context).await {
+                if let Err(e) = Self::handle_master_client(socket, client, context, auth).await {
                     log::error!("{}", e);
                 }
             });
@@ -284,3 +602,358 @@ impl<G: Generator + 'static> Worker<G> {
         Ok(())
     }
 }
+
+/// Workers answering probes with a chosen memory and forward time, to test the master side
+/// without loading a model.
+#[cfg(test)]
+pub(crate) mod stand_in {
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::{
+        models::{chat, llama3::Config, params::GenerationParams, Token},
+        spm::ClientOptions,
+        utils::Weights,
+    };
+
+    /// Name of the model served by the stand-in workers.
+    pub const MODEL_NAME: &str = "stand-in";
+    /// Hidden size of the stand-in model.
+    pub const HIDDEN_SIZE: usize = 8;
+
+    /// Layer returning its input after the chosen forward time, served as PROBE_LAYER. Its input
+    /// is kept in the kv-cache, so that like a real layer it can only continue a cached sequence.
+    #[derive(Debug)]
+    pub struct StandInLayer {
+        forward_time: Duration,
+    }
+
+    impl std::fmt::Display for StandInLayer {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "stand-in ({:?})", self.forward_time)
+        }
+    }
+
+    #[async_trait]
+    impl Forwarder for StandInLayer {
+        fn load(_name: String, _vb: Weights, _cfg: &Config) -> Result<Box<Self>> {
+            bail!("stand-in layers aren't loaded from weights")
+        }
+
+        async fn forward(
+            &self,
+            x: &Tensor,
+            index_pos: usize,
+            block_idx: usize,
+            cache: &mut Cache,
+        ) -> Result<Tensor> {
+            tokio::time::sleep(self.forward_time).await;
+            let kv = x.unsqueeze(1)?;
+            cache.process_kv(block_idx, index_pos, kv.clone(), kv)?;
+            Ok(x.clone())
+        }
+
+        async fn forward_mut(
+            &mut self,
+            x: &Tensor,
+            index_pos: usize,
+            block_idx: usize,
+            cache: &mut Cache,
+        ) -> Result<Tensor> {
+            self.forward(x, index_pos, block_idx, cache).await
+        }
+
+        fn layer_name(&self) -> &str {
+            PROBE_LAYER
+        }
+    }
+
+    /// Model made of stand-in layers, it only exists to be served by workers.
+    pub struct StandInModel;
+
+    #[async_trait]
+    impl Generator for StandInModel {
+        type Shardable = StandInLayer;
+        const MODEL_NAME: &'static str = MODEL_NAME;
+
+        async fn load(_context: Context) -> Result<Box<Self>> {
+            bail!("the stand-in model can't be loaded")
+        }
+        fn add_message(&mut self, _message: chat::Message) -> Result<()> {
+            bail!("stand-in model")
+        }
+        fn reset(&mut self) -> Result<()> {
+            bail!("stand-in model")
+        }
+        async fn clear_cache(&mut self) -> Result<()> {
+            bail!("stand-in model")
+        }
+        fn set_params(&mut self, _params: GenerationParams) -> Result<()> {
+            bail!("stand-in model")
+        }
+        async fn next_token(&mut self, _index: usize) -> Result<Token> {
+            bail!("stand-in model")
+        }
+        fn flush_text(&mut self) -> Result<String> {
+            bail!("stand-in model")
+        }
+        fn generated_tokens(&self) -> usize {
+            0
+        }
+        fn prompt_tokens(&self) -> usize {
+            0
+        }
+        fn max_sequence_length(&self) -> usize {
+            0
+        }
+        async fn perplexity(&mut self, _text: &str) -> Result<f64> {
+            bail!("stand-in model")
+        }
+    }
+
+    /// Return the handshake the master sends to the stand-in workers.
+    pub fn hello() -> Hello {
+        Hello::new(MODEL_NAME, MODEL_NAME, DType::F32, Compression::None)
+    }
+
+    /// Return the connection settings used by the tests, failing fast without retries.
+    pub fn client_options() -> ClientOptions {
+        ClientOptions {
+            timeout: Duration::from_secs(10),
+            heartbeat: None,
+            reconnect_attempts: 0,
+        }
+    }
+
+    /// Return a kv-cache for the stand-in model.
+    pub fn cache() -> Result<Cache> {
+        let config = Config {
+            hidden_size: HIDDEN_SIZE,
+            intermediate_size: HIDDEN_SIZE * 2,
+            vocab_size: 16,
+            num_hidden_layers: 1,
+            num_attention_heads: 2,
+            num_key_value_heads: 2,
+            rms_norm_eps: 1e-5,
+            rope_theta: 10000.0,
+            bos_token_id: None,
+            eos_token_ids: vec![],
+        };
+        Ok(Cache::new(true, DType::F32, &config, &Device::Cpu)?)
+    }
+
+    /// Start a worker on a local port, answering probes with the given memory and forward
+    /// time, and return its address.
+    pub async fn spawn(memory: u64, forward_time: Duration) -> Result<String> {
+        let auth = WorkerAuth::from_args(&crate::Args::default())?;
+        spawn_with_auth(memory, forward_time, auth).await
+    }
+
+    /// Like spawn, requiring the given authentication from the master.
+    pub async fn spawn_with_auth(
+        memory: u64,
+        forward_time: Duration,
+        auth: WorkerAuth,
+    ) -> Result<String> {
+        start(context(memory, forward_time)?, auth).await
+    }
+
+    /// Like spawn, reporting the given fingerprint and dtype in the handshake.
+    pub async fn spawn_serving(fingerprint: &str, dtype: DType) -> Result<String> {
+        let mut context = context(1, Duration::ZERO)?;
+        context.fingerprint = fingerprint.to_string();
+        context.dtype = dtype;
+        start(context, WorkerAuth::from_args(&crate::Args::default())?).await
+    }
+
+    fn context(memory: u64, forward_time: Duration) -> Result<WorkerContext<StandInLayer>> {
+        let mut blocks = HashMap::new();
+        blocks.insert(
+            PROBE_LAYER.to_string(),
+            Box::new(StandInLayer { forward_time }),
+        );
+        Ok(WorkerContext {
+            device: Device::Cpu,
+            device_idx: 0,
+            dtype: DType::F32,
+            config_hash: MODEL_NAME.to_string(),
+            fingerprint: MODEL_NAME.to_string(),
+            quantization: None,
+            blocks: Arc::new(blocks),
+            probe_block: None,
+            max_memory: Some(memory),
+            loaded_memory: 0,
+            cache: cache()?,
+        })
+    }
+
+    async fn start(context: WorkerContext<StandInLayer>, auth: WorkerAuth) -> Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?.to_string();
+        let mut worker = Worker::<StandInModel> {
+            listener,
+            auth: Arc::new(auth),
+            context,
+        };
+        tokio::spawn(async move { worker.run().await });
+
+        Ok(address)
+    }
+
+    /// Start a stand-in worker that can be stopped on address, answering after the given forward time.
+    pub async fn serve(address: &str, forward_time: Duration) -> Result<Server> {
+        let worker = Worker::<StandInModel> {
+            listener: TcpListener::bind(address).await?,
+            auth: Arc::new(WorkerAuth::from_args(&crate::Args::default())?),
+            context: context(1, forward_time)?,
+        };
+        Server::start(worker)
+    }
+
+    /// Return a local address nothing is listening on.
+    pub fn free_address() -> Result<String> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        Ok(listener.local_addr()?.to_string())
+    }
+
+    /// A worker serving its connections until it's stopped, like a killed node.
+    pub struct Server {
+        pub address: String,
+        task: tokio::task::JoinHandle<()>,
+    }
+
+    impl Server {
+        /// Serve the connections to the worker in the background.
+        pub fn start<G: Generator + 'static>(worker: Worker<G>) -> Result<Self> {
+            let address = worker.listener.local_addr()?.to_string();
+            let task = tokio::spawn(async move {
+                // aborted with the server, closing the connections
+                let mut connections = tokio::task::JoinSet::new();
+                while let Ok((socket, client)) = worker.listener.accept().await {
+                    connections.spawn(Worker::<G>::handle_master_client(
+                        socket,
+                        client,
+                        worker.context.get_client_context(),
+                        worker.auth.clone(),
+                    ));
+                }
+            });
+            Ok(Self { address, task })
+        }
+
+        /// Stop listening and close the open connections.
+        pub async fn stop(self) {
+            self.task.abort();
+            let _ = self.task.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spm::{Auth, Stream};
+
+    /// Connect to a worker and perform the handshake.
+    async fn connect(address: &str) -> Box<dyn Stream> {
+        let mut stream = Auth::default().connect(address).await.unwrap();
+        Message::Hello(stand_in::hello())
+            .to_writer(&mut stream)
+            .await
+            .unwrap();
+        let (_, info) = Message::from_reader(&mut stream).await.unwrap();
+        assert!(matches!(info, Message::WorkerInfo(_)), "{}", info.name());
+        stream
+    }
+
+    /// Send a request and return the response.
+    async fn request(stream: &mut Box<dyn Stream>, message: Message) -> Message {
+        message.to_writer(stream).await.unwrap();
+        Message::from_reader(stream).await.unwrap().1
+    }
+
+    /// Return a forward request of seq_len positions starting at index_pos.
+    fn single_op(index_pos: usize, seq_len: usize) -> Message {
+        let shape = (1, seq_len, stand_in::HIDDEN_SIZE);
+        let x = Tensor::zeros(shape, DType::F32, &Device::Cpu).unwrap();
+        Message::single_op(PROBE_LAYER, &x, index_pos, 0, Compression::None).unwrap()
+    }
+
+    #[tokio::test]
+    async fn reset_cache_empties_the_cache() {
+        let address = stand_in::spawn(1, Duration::ZERO).await.unwrap();
+        let mut stream = connect(&address).await;
+
+        let response = request(&mut stream, single_op(0, 3)).await;
+        assert_eq!(response.name(), "Tensor");
+        let response = request(&mut stream, single_op(3, 1)).await;
+        assert_eq!(response.name(), "Tensor");
+
+        let response = request(&mut stream, Message::ResetCache).await;
+        assert_eq!(response.name(), "Ok");
+
+        // the sequence can't be continued anymore
+        let response = request(&mut stream, single_op(4, 1)).await;
+        assert_eq!(response.name(), "Error");
+
+        // but a new one can start
+        let response = request(&mut stream, single_op(0, 1)).await;
+        assert_eq!(response.name(), "Tensor");
+    }
+
+    #[tokio::test]
+    async fn mismatched_hello_is_rejected() {
+        let address = stand_in::spawn(1, Duration::ZERO).await.unwrap();
+        let mut stream = Auth::default().connect(&address).await.unwrap();
+
+        let hello = Hello {
+            model: "other".to_string(),
+            ..stand_in::hello()
+        };
+        Message::Hello(hello).to_writer(&mut stream).await.unwrap();
+        match Message::from_reader(&mut stream).await.unwrap().1 {
+            Message::Error { code, message, .. } => {
+                assert_eq!(code, ErrorCode::Handshake);
+                assert!(message.contains("model mismatch"), "{message}");
+            }
+            message => panic!("unexpected {}", message.name()),
+        }
+
+        // the connection is closed without serving any request
+        let _ = Message::Ping.to_writer(&mut stream).await;
+        assert!(Message::from_reader(&mut stream).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn forward_errors_are_reported() {
+        let address = stand_in::spawn(1, Duration::ZERO).await.unwrap();
+        let mut stream = connect(&address).await;
+
+        // nothing is cached at position 2
+        match request(&mut stream, single_op(2, 1)).await {
+            Message::Error {
+                code, layer_name, ..
+            } => {
+                assert_eq!(code, ErrorCode::Forward);
+                assert_eq!(layer_name.as_deref(), Some(PROBE_LAYER));
+            }
+            message => panic!("unexpected {}", message.name()),
+        }
+
+        let x = Tensor::zeros((1, 1, stand_in::HIDDEN_SIZE), DType::F32, &Device::Cpu).unwrap();
+        let unknown = Message::single_op("model.layers.9", &x, 0, 0, Compression::None).unwrap();
+        match request(&mut stream, unknown).await {
+            Message::Error {
+                code, layer_name, ..
+            } => {
+                assert_eq!(code, ErrorCode::UnknownLayer);
+                assert_eq!(layer_name.as_deref(), Some("model.layers.9"));
+            }
+            message => panic!("unexpected {}", message.name()),
+        }
+
+        // the worker keeps serving the connection
+        let response = request(&mut stream, single_op(0, 1)).await;
+        assert_eq!(response.name(), "Tensor");
+    }
+}
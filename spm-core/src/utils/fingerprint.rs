@@ -0,0 +1,215 @@
+//! Model fingerprints, used to make sure the master and the workers load the same weights.
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use anyhow::Result;
+use candle_core::quantized::{gguf_file::Value, QTensor};
+use memmap2::MmapOptions;
+use safetensors::SafeTensors;
+use sha2::{Digest, Sha256};
+
+use super::{Gguf, SafetensorsIndex};
+
+/// Bytes hashed from the beginning and the end of every tensor. Hashing all the weights takes
+/// too long on large models, samples of the data are enough to tell checkpoints apart.
+const DATA_SAMPLE_SIZE: usize = 4096;
+
+/// Key of the index metadata holding the fingerprints of the layers of a split checkpoint.
+pub const FINGERPRINTS_METADATA_KEY: &str = "layer_fingerprints";
+
+/// Key of the GGUF metadata holding the fingerprints of the layers of a quantized checkpoint,
+/// an array indexed by layer number.
+pub const GGUF_FINGERPRINTS_METADATA_KEY: &str = "spm.layer_fingerprints";
+
+/// Where the tensors are read from.
+enum Source {
+    Safetensors(SafetensorsIndex),
+    Gguf(Arc<Gguf>),
+}
+
+/// Computes fingerprints of the model configuration and of sets of layers.
+pub struct ModelFingerprint {
+    config_hash: String,
+    source: Source,
+    /// Fingerprints recorded in the index when the checkpoint was split, used for the layers
+    /// whose tensors are not in this directory.
+    recorded: HashMap<String, String>,
+}
+
+impl ModelFingerprint {
+    /// Read the configuration and the safetensors index of the model in data_path, or the
+    /// metadata of a GGUF file. Files are only mapped while hashing the layers they hold.
+    pub fn new(data_path: &Path) -> Result<Self> {
+        if super::is_gguf(data_path) {
+            return Ok(Self::from_gguf(Arc::new(Gguf::open(data_path)?)));
+        }
+
+        let config_hash = super::config_hash(&data_path.join("config.json"))?;
+        let index = SafetensorsIndex::from_path(data_path)?;
+
+        let mut recorded = HashMap::new();
+        if let Some(serde_json::Value::Object(map)) =
+            index.metadata().get(FINGERPRINTS_METADATA_KEY)
+        {
+            for (layer_name, fingerprint) in map {
+                if let Some(fingerprint) = fingerprint.as_str() {
+                    recorded.insert(layer_name.to_string(), fingerprint.to_string());
+                }
+            }
+        }
+
+        Ok(Self {
+            config_hash,
+            source: Source::Safetensors(index),
+            recorded,
+        })
+    }
+
+    /// Use the metadata of an open GGUF file.
+    pub fn from_gguf(gguf: Arc<Gguf>) -> Self {
+        let mut recorded = HashMap::new();
+        if let Some(Value::Array(fingerprints)) = gguf.metadata(GGUF_FINGERPRINTS_METADATA_KEY) {
+            for (i, fingerprint) in fingerprints.iter().enumerate() {
+                if let Value::String(fingerprint) = fingerprint {
+                    recorded.insert(format!("model.layers.{i}"), fingerprint.to_string());
+                }
+            }
+        }
+
+        Self {
+            config_hash: gguf.config_hash(),
+            source: Source::Gguf(gguf),
+            recorded,
+        }
+    }
+
+    /// Return the fingerprint of a single layer, computed from the name, data type, shape and
+    /// data samples of its tensors.
+    pub fn layer(&self, layer_name: &str) -> Result<String> {
+        // tensors are hashed separately and sorted by name, so that the way they're
+        // distributed across files doesn't matter
+        let tensors = match &self.source {
+            Source::Safetensors(index) => {
+                if index.tensors(layer_name).is_empty() {
+                    vec![]
+                } else {
+                    Self::safetensors_digests(index, layer_name)?
+                }
+            }
+            Source::Gguf(gguf) => Self::gguf_digests(gguf, layer_name)?,
+        };
+
+        if tensors.is_empty() {
+            return match self.recorded.get(layer_name) {
+                Some(fingerprint) => Ok(fingerprint.to_string()),
+                None => bail!("no tensors found for {layer_name}"),
+            };
+        }
+
+        Ok(combine_digests(tensors))
+    }
+
+    fn safetensors_digests(
+        index: &SafetensorsIndex,
+        layer_name: &str,
+    ) -> Result<Vec<(String, Vec<u8>)>> {
+        let prefix = format!("{layer_name}.");
+        let mut tensors = vec![];
+        for path in index.files(&[layer_name.to_string()])? {
+            let file = std::fs::File::open(&path)
+                .map_err(|e| anyhow!("can't open {}: {:?}", path.display(), e))?;
+            let mmap = unsafe { MmapOptions::new().map(&file) }
+                .map_err(|e| anyhow!("can't map {}: {:?}", path.display(), e))?;
+            let st = SafeTensors::deserialize(&mmap)
+                .map_err(|e| anyhow!("can't read {}: {:?}", path.display(), e))?;
+            for (name, view) in st.tensors() {
+                if name.starts_with(&prefix) {
+                    let digest = tensor_digest(&name, view.dtype(), view.shape(), view.data());
+                    tensors.push((name, digest));
+                }
+            }
+        }
+        Ok(tensors)
+    }
+
+    fn gguf_digests(gguf: &Gguf, layer_name: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        let names = gguf.tensors(layer_name);
+        if names.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let path = gguf.path();
+        let file = std::fs::File::open(path)
+            .map_err(|e| anyhow!("can't open {}: {:?}", path.display(), e))?;
+        let mmap = unsafe { MmapOptions::new().map(&file) }
+            .map_err(|e| anyhow!("can't map {}: {:?}", path.display(), e))?;
+
+        let mut tensors = vec![];
+        for name in names {
+            let info = gguf.tensor_info(name).unwrap();
+            let range = gguf.tensor_range(info);
+            if range.end > mmap.len() {
+                bail!("{} is truncated, can't read {name}", path.display());
+            }
+            let digest = tensor_digest(name, info.ggml_dtype, info.shape.dims(), &mmap[range]);
+            tensors.push((name.to_string(), digest));
+        }
+        Ok(tensors)
+    }
+
+    /// Return the fingerprint of the configuration and the given layers, in any order.
+    pub fn layers(&self, layer_names: &[String]) -> Result<String> {
+        let mut layer_names = layer_names.to_vec();
+        layer_names.sort();
+
+        let mut hasher = Sha256::new();
+        hasher.update(self.config_hash.as_bytes());
+        for layer_name in &layer_names {
+            hasher.update(layer_name.as_bytes());
+            hasher.update(self.layer(layer_name)?.as_bytes());
+        }
+
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+}
+
+/// Return the fingerprint of a layer from its quantized tensors keyed by safetensors name, the
+/// same ModelFingerprint::layer returns once they're written to a GGUF file.
+pub fn qtensors_fingerprint(tensors: &[(&str, &QTensor)]) -> Result<String> {
+    let mut digests = vec![];
+    for (name, tensor) in tensors {
+        let data = tensor.data()?;
+        let digest = tensor_digest(name, tensor.dtype(), tensor.shape().dims(), &data);
+        digests.push((name.to_string(), digest));
+    }
+    Ok(combine_digests(digests))
+}
+
+/// Hash the digests of the tensors of a layer in name order.
+fn combine_digests(mut tensors: Vec<(String, Vec<u8>)>) -> String {
+    tensors.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut hasher = Sha256::new();
+    for (_, digest) in tensors {
+        hasher.update(digest);
+    }
+
+    format!("{:x}", hasher.finalize())
+}
+
+/// Return the digest of the name, data type, shape and data samples of a tensor.
+fn tensor_digest<D: std::fmt::Debug>(
+    name: &str,
+    dtype: D,
+    shape: &[usize],
+    data: &[u8],
+) -> Vec<u8> {
+    let head = &data[..data.len().min(DATA_SAMPLE_SIZE)];
+    let tail = &data[data.len().saturating_sub(DATA_SAMPLE_SIZE)..];
+
+    let mut hasher = Sha256::new();
+    hasher.update(name.as_bytes());
+    hasher.update(format!("{:?}{:?}{}", dtype, shape, data.len()));
+    hasher.update(head);
+    hasher.update(tail);
+    hasher.finalize().to_vec()
+}
@@ -0,0 +1,673 @@
+//! GGUF checkpoints. Tensors are exposed with their safetensors names so that the rest of the
+//! code and the topology files don't need to know about the llama.cpp naming.
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Result;
+use candle_core::{
+    quantized::{
+        gguf_file::{Content, TensorInfo, Value},
+        GgmlDType, QTensor,
+    },
+    Device, Tensor,
+};
+use sha2::{Digest, Sha256};
+
+/// First bytes of a GGUF file.
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+
+/// llama.cpp -> safetensors names of the tensors of a transformer block.
+const BLOCK_TENSORS: &[(&str, &str)] = &[
+    ("attn_q", "self_attn.q_proj"),
+    ("attn_k", "self_attn.k_proj"),
+    ("attn_v", "self_attn.v_proj"),
+    ("attn_output", "self_attn.o_proj"),
+    ("ffn_gate", "mlp.gate_proj"),
+    ("ffn_up", "mlp.up_proj"),
+    ("ffn_down", "mlp.down_proj"),
+    ("attn_norm", "input_layernorm"),
+    ("ffn_norm", "post_attention_layernorm"),
+];
+
+/// llama.cpp -> safetensors names of the tensors outside of the transformer blocks.
+const MODEL_TENSORS: &[(&str, &str)] = &[
+    ("token_embd", "model.embed_tokens"),
+    ("output_norm", "model.norm"),
+    ("output", "lm_head"),
+];
+
+/// Return true if path points to a GGUF file, either by its extension or by its magic.
+pub fn is_gguf(path: &Path) -> bool {
+    if path.extension().is_some_and(|ext| ext == "gguf") {
+        return true;
+    }
+
+    let mut magic = [0u8; 4];
+    path.is_file()
+        && std::fs::File::open(path)
+            .and_then(|mut file| std::io::Read::read_exact(&mut file, &mut magic))
+            .is_ok()
+        && &magic == GGUF_MAGIC
+}
+
+/// Return the safetensors name of a llama.cpp tensor, like model.layers.0.self_attn.q_proj.weight
+/// for blk.0.attn_q.weight, or None for tensors the model doesn't use.
+pub fn safetensors_name(gguf_name: &str) -> Option<String> {
+    let (base, suffix) = gguf_name.rsplit_once('.')?;
+
+    if let Some(rest) = base.strip_prefix("blk.") {
+        let (block, tensor) = rest.split_once('.')?;
+        let block = block.parse::<usize>().ok()?;
+        let (_, name) = BLOCK_TENSORS.iter().find(|(from, _)| *from == tensor)?;
+        return Some(format!("model.layers.{block}.{name}.{suffix}"));
+    }
+
+    let (_, name) = MODEL_TENSORS.iter().find(|(from, _)| *from == base)?;
+    Some(format!("{name}.{suffix}"))
+}
+
+/// Return the llama.cpp name of a safetensors tensor, like blk.0.attn_q.weight for
+/// model.layers.0.self_attn.q_proj.weight, or None for tensors the model doesn't use.
+pub fn gguf_name(safetensors_name: &str) -> Option<String> {
+    let (base, suffix) = safetensors_name.rsplit_once('.')?;
+
+    if let Some(rest) = base.strip_prefix("model.layers.") {
+        let (block, tensor) = rest.split_once('.')?;
+        let block = block.parse::<usize>().ok()?;
+        let (name, _) = BLOCK_TENSORS.iter().find(|(_, to)| *to == tensor)?;
+        return Some(format!("blk.{block}.{name}.{suffix}"));
+    }
+
+    let (name, _) = MODEL_TENSORS.iter().find(|(_, to)| *to == base)?;
+    Some(format!("{name}.{suffix}"))
+}
+
+/// Permute the rows of a query or key weight the way llama.cpp does, so that the rotary
+/// embeddings are applied to interleaved pairs rather than to the two halves of every head.
+pub fn permute_qk(weight: &Tensor, heads: usize) -> candle_core::Result<Tensor> {
+    let (rows, cols) = weight.dims2()?;
+    weight
+        .reshape((heads, 2, rows / heads / 2, cols))?
+        .transpose(1, 2)?
+        .reshape((rows, cols))
+}
+
+/// A GGUF file, only the metadata and the tensor infos are read when opening it.
+pub struct Gguf {
+    path: PathBuf,
+    content: Content,
+    /// safetensors name -> llama.cpp name
+    names: HashMap<String, String>,
+}
+
+impl Gguf {
+    /// Read the metadata and the tensor infos of the GGUF file at path.
+    pub fn open(path: &Path) -> Result<Self> {
+        log::info!("reading {} ...", path.display());
+
+        let file = std::fs::File::open(path)
+            .map_err(|e| anyhow!("can't open {}: {:?}", path.display(), e))?;
+        // the vocabulary is made of hundreds of thousands of small strings
+        let content = Content::read(&mut std::io::BufReader::new(file))
+            .map_err(|e| anyhow!("can't read {}: {:?}", path.display(), e))?;
+
+        let names = content
+            .tensor_infos
+            .keys()
+            .filter_map(|name| safetensors_name(name).map(|st_name| (st_name, name.to_string())))
+            .collect();
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            content,
+            names,
+        })
+    }
+
+    /// Return the path of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Return a metadata value.
+    pub fn metadata(&self, key: &str) -> Option<&Value> {
+        self.content.metadata.get(key)
+    }
+
+    /// Return a metadata value as an integer, whatever its integer type.
+    pub fn metadata_usize(&self, key: &str) -> Result<Option<usize>> {
+        let value = match self.metadata(key) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let n = match value {
+            Value::U8(n) => *n as usize,
+            Value::U16(n) => *n as usize,
+            Value::U32(n) => *n as usize,
+            Value::U64(n) => *n as usize,
+            Value::I8(n) if *n >= 0 => *n as usize,
+            Value::I16(n) if *n >= 0 => *n as usize,
+            Value::I32(n) if *n >= 0 => *n as usize,
+            Value::I64(n) if *n >= 0 => *n as usize,
+            _ => bail!("{key} is not an unsigned integer: {value:?}"),
+        };
+        Ok(Some(n))
+    }
+
+    /// Return a metadata value as a float.
+    pub fn metadata_f64(&self, key: &str) -> Result<Option<f64>> {
+        match self.metadata(key) {
+            Some(Value::F32(n)) => Ok(Some(*n as f64)),
+            Some(Value::F64(n)) => Ok(Some(*n)),
+            Some(value) => bail!("{key} is not a float: {value:?}"),
+            None => Ok(None),
+        }
+    }
+
+    /// Return the sha256 hex digest of the metadata, the equivalent of the config.json hash.
+    pub fn config_hash(&self) -> String {
+        let mut keys: Vec<&String> = self.content.metadata.keys().collect();
+        keys.sort();
+
+        let mut hasher = Sha256::new();
+        for key in keys {
+            hasher.update(key.as_bytes());
+            hasher.update(format!("{:?}", &self.content.metadata[key]));
+        }
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// Return the safetensors names of the tensors starting with prefix, like model.layers.0.
+    pub fn tensors(&self, prefix: &str) -> Vec<&str> {
+        let prefix = format!("{prefix}.");
+        let mut names: Vec<&str> = self
+            .names
+            .keys()
+            .filter(|name| name.starts_with(&prefix))
+            .map(|name| name.as_str())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Return the info of a tensor given its safetensors name.
+    pub fn tensor_info(&self, name: &str) -> Option<&TensorInfo> {
+        self.names
+            .get(name)
+            .and_then(|gguf_name| self.content.tensor_infos.get(gguf_name))
+    }
+
+    /// Return the position of the data of a tensor in the file.
+    pub fn tensor_range(&self, info: &TensorInfo) -> Range<usize> {
+        let start = (self.content.tensor_data_offset + info.offset) as usize;
+        let size =
+            info.shape.elem_count() / info.ggml_dtype.block_size() * info.ggml_dtype.type_size();
+        start..start + size
+    }
+
+    /// Read the tensors starting with the given prefixes, keyed by their safetensors name.
+    /// Fails if any of the prefixes has no tensors. When the file has no output tensor the
+    /// lm_head is tied to the embeddings.
+    pub fn load(
+        &self,
+        prefixes: &[String],
+        device: &Device,
+    ) -> Result<HashMap<String, Arc<QTensor>>> {
+        let mut file = std::fs::File::open(&self.path)
+            .map_err(|e| anyhow!("can't open {}: {:?}", self.path.display(), e))?;
+
+        let mut tensors = HashMap::new();
+        let mut missing = vec![];
+        for prefix in prefixes {
+            let mut names = self.tensors(prefix);
+            let tied = prefix == "lm_head" && names.is_empty();
+            if tied {
+                log::info!("no output tensor, lm_head is tied to the embeddings");
+                names = self.tensors("model.embed_tokens");
+            }
+            if names.is_empty() {
+                missing.push(prefix.as_str());
+            }
+
+            for name in names {
+                let tensor = self
+                    .content
+                    .tensor(&mut file, &self.names[name], device)
+                    .map_err(|e| anyhow!("can't read {name}: {e}"))?;
+                let name = if tied {
+                    name.replace("model.embed_tokens", "lm_head")
+                } else {
+                    name.to_string()
+                };
+                tensors.insert(name, Arc::new(tensor));
+            }
+        }
+
+        if !missing.is_empty() {
+            bail!("tensors not found in the model: {}", missing.join(", "));
+        }
+
+        Ok(tensors)
+    }
+}
+
+/// Version of the GGUF files written, the one candle writes.
+const GGUF_VERSION: u32 = 2;
+
+/// Alignment of the tensor data in the GGUF files written.
+const GGUF_ALIGNMENT: usize = 32;
+
+/// A GGUF file written one tensor at a time, so that only the tensor being written needs to be in
+/// memory. The layout is the same as the one of candle's gguf_file::write.
+pub struct GgufWriter {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    /// type, shape and size of the tensors still to be written, in order
+    pending: VecDeque<(GgmlDType, Vec<usize>, usize)>,
+    /// position and size of every metadata value
+    values: HashMap<String, (u64, usize)>,
+}
+
+impl GgufWriter {
+    /// Create the GGUF file at path and write its metadata and the infos of the tensors, given
+    /// their llama.cpp name, type and shape in the order their data will be written.
+    pub fn create(
+        path: &Path,
+        metadata: &[(String, Value)],
+        tensors: &[(String, GgmlDType, Vec<usize>)],
+    ) -> Result<Self> {
+        let mut header = GGUF_MAGIC.to_vec();
+        header.extend(GGUF_VERSION.to_le_bytes());
+        header.extend((tensors.len() as u64).to_le_bytes());
+        header.extend((metadata.len() as u64).to_le_bytes());
+
+        let mut values = HashMap::new();
+        for (key, value) in metadata {
+            write_gguf_string(&mut header, key);
+            let start = header.len();
+            header.extend(gguf_value_type(value).to_le_bytes());
+            write_gguf_value(&mut header, value)?;
+            values.insert(key.to_string(), (start as u64, header.len() - start));
+        }
+
+        let mut pending = VecDeque::new();
+        let mut offset = 0;
+        for (name, dtype, dims) in tensors {
+            write_gguf_string(&mut header, name);
+            header.extend((dims.len() as u32).to_le_bytes());
+            for dim in dims.iter().rev() {
+                header.extend((*dim as u64).to_le_bytes());
+            }
+            header.extend(ggml_dtype_id(*dtype).to_le_bytes());
+            header.extend((offset as u64).to_le_bytes());
+
+            let size = dims.iter().product::<usize>() / dtype.block_size() * dtype.type_size();
+            offset += size.next_multiple_of(GGUF_ALIGNMENT);
+            pending.push_back((*dtype, dims.clone(), size));
+        }
+        header.resize(header.len().next_multiple_of(GGUF_ALIGNMENT), 0);
+
+        let file =
+            File::create(path).map_err(|e| anyhow!("can't create {}: {:?}", path.display(), e))?;
+        let mut writer = Self {
+            path: path.to_path_buf(),
+            writer: BufWriter::new(file),
+            pending,
+            values,
+        };
+        writer.write(&header)?;
+
+        Ok(writer)
+    }
+
+    /// Write the data of the next tensor, which must have the type and shape it was declared with.
+    pub fn write_tensor(&mut self, tensor: &QTensor) -> Result<()> {
+        let (dtype, dims, size) = match self.pending.pop_front() {
+            Some(pending) => pending,
+            None => bail!("all the tensors of {} are written", self.path.display()),
+        };
+        if tensor.dtype() != dtype || tensor.shape().dims() != dims {
+            bail!(
+                "expected a {dtype:?} {dims:?} tensor, got a {:?} {:?} one",
+                tensor.dtype(),
+                tensor.shape()
+            );
+        }
+
+        let data = tensor.data()?;
+        if data.len() != size {
+            bail!(
+                "expected {size} bytes of {dtype:?} data, got {}",
+                data.len()
+            );
+        }
+        self.write(&data)?;
+        self.write(&vec![0u8; size.next_multiple_of(GGUF_ALIGNMENT) - size])
+    }
+
+    /// Replace a metadata value with one of the same size, for the values that are only known once
+    /// the tensors are written.
+    pub fn update_metadata(&mut self, key: &str, value: &Value) -> Result<()> {
+        let (start, size) = match self.values.get(key) {
+            Some(value) => *value,
+            None => bail!("{key} is not in the metadata of {}", self.path.display()),
+        };
+
+        let mut data = gguf_value_type(value).to_le_bytes().to_vec();
+        write_gguf_value(&mut data, value)?;
+        if data.len() != size {
+            bail!(
+                "{key} is {size} bytes, can't replace it with {} bytes",
+                data.len()
+            );
+        }
+
+        let end = self.seek(SeekFrom::Current(0))?;
+        self.seek(SeekFrom::Start(start))?;
+        self.write(&data)?;
+        self.seek(SeekFrom::Start(end))?;
+        Ok(())
+    }
+
+    /// Flush the file once all the tensors are written.
+    pub fn finish(mut self) -> Result<()> {
+        if !self.pending.is_empty() {
+            bail!(
+                "{} tensors of {} are not written",
+                self.pending.len(),
+                self.path.display()
+            );
+        }
+        self.writer
+            .flush()
+            .map_err(|e| anyhow!("can't write {}: {:?}", self.path.display(), e))
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.writer
+            .write_all(data)
+            .map_err(|e| anyhow!("can't write {}: {:?}", self.path.display(), e))
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.writer
+            .seek(pos)
+            .map_err(|e| anyhow!("can't write {}: {:?}", self.path.display(), e))
+    }
+}
+
+/// Return the GGUF id of a ggml type.
+fn ggml_dtype_id(dtype: GgmlDType) -> u32 {
+    match dtype {
+        GgmlDType::F32 => 0,
+        GgmlDType::F16 => 1,
+        GgmlDType::Q4_0 => 2,
+        GgmlDType::Q4_1 => 3,
+        GgmlDType::Q5_0 => 6,
+        GgmlDType::Q5_1 => 7,
+        GgmlDType::Q8_0 => 8,
+        GgmlDType::Q8_1 => 9,
+        GgmlDType::Q2K => 10,
+        GgmlDType::Q3K => 11,
+        GgmlDType::Q4K => 12,
+        GgmlDType::Q5K => 13,
+        GgmlDType::Q6K => 14,
+        GgmlDType::Q8K => 15,
+    }
+}
+
+/// Return the GGUF id of the type of a metadata value.
+fn gguf_value_type(value: &Value) -> u32 {
+    match value {
+        Value::U8(_) => 0,
+        Value::I8(_) => 1,
+        Value::U16(_) => 2,
+        Value::I16(_) => 3,
+        Value::U32(_) => 4,
+        Value::I32(_) => 5,
+        Value::F32(_) => 6,
+        Value::Bool(_) => 7,
+        Value::String(_) => 8,
+        Value::Array(_) => 9,
+        Value::U64(_) => 10,
+        Value::I64(_) => 11,
+        Value::F64(_) => 12,
+    }
+}
+
+fn write_gguf_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend((s.len() as u64).to_le_bytes());
+    buf.extend(s.as_bytes());
+}
+
+/// Serialize a metadata value without its type.
+fn write_gguf_value(buf: &mut Vec<u8>, value: &Value) -> Result<()> {
+    match value {
+        Value::U8(v) => buf.push(*v),
+        Value::I8(v) => buf.extend(v.to_le_bytes()),
+        Value::U16(v) => buf.extend(v.to_le_bytes()),
+        Value::I16(v) => buf.extend(v.to_le_bytes()),
+        Value::U32(v) => buf.extend(v.to_le_bytes()),
+        Value::I32(v) => buf.extend(v.to_le_bytes()),
+        Value::U64(v) => buf.extend(v.to_le_bytes()),
+        Value::I64(v) => buf.extend(v.to_le_bytes()),
+        Value::F32(v) => buf.extend(v.to_le_bytes()),
+        Value::F64(v) => buf.extend(v.to_le_bytes()),
+        Value::Bool(v) => buf.push(u8::from(*v)),
+        Value::String(v) => write_gguf_string(buf, v),
+        Value::Array(values) => {
+            // the type of an empty array doesn't matter
+            let value_type = match values.first() {
+                Some(first) => gguf_value_type(first),
+                None => 4,
+            };
+            for value in values {
+                if gguf_value_type(value) != value_type {
+                    bail!("values of different types in the same array");
+                }
+            }
+            buf.extend(value_type.to_le_bytes());
+            buf.extend((values.len() as u64).to_le_bytes());
+            for value in values {
+                write_gguf_value(buf, value)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{quantized::gguf_file, DType};
+    use candle_nn::rotary_emb::{rope, rope_i};
+
+    use super::*;
+
+    #[test]
+    fn tensor_names() {
+        for (gguf, safetensors) in [
+            (
+                "blk.0.attn_q.weight",
+                "model.layers.0.self_attn.q_proj.weight",
+            ),
+            (
+                "blk.31.ffn_down.weight",
+                "model.layers.31.mlp.down_proj.weight",
+            ),
+            (
+                "blk.2.attn_norm.weight",
+                "model.layers.2.input_layernorm.weight",
+            ),
+            ("token_embd.weight", "model.embed_tokens.weight"),
+            ("output_norm.weight", "model.norm.weight"),
+            ("output.weight", "lm_head.weight"),
+        ] {
+            assert_eq!(safetensors_name(gguf).as_deref(), Some(safetensors));
+            assert_eq!(gguf_name(safetensors).as_deref(), Some(gguf));
+        }
+
+        for (tensor, _) in BLOCK_TENSORS {
+            let name = format!("blk.7.{tensor}.weight");
+            assert_eq!(gguf_name(&safetensors_name(&name).unwrap()), Some(name));
+        }
+
+        for name in [
+            "rope_freqs.weight",
+            "blk.0.attn_rot_embd.weight",
+            "blk.x.attn_q.weight",
+            "blk.0.attn_q",
+            "model.layers.0.self_attn.rotary_emb.inv_freq",
+            "model.embed_tokens",
+            "weight",
+        ] {
+            assert_eq!(safetensors_name(name), None, "{name}");
+            assert_eq!(gguf_name(name), None, "{name}");
+        }
+    }
+
+    #[test]
+    fn permuted_qk_with_interleaved_rope() {
+        let (heads, head_dim, cols, seq) = (4, 8, 16, 3);
+        let device = Device::Cpu;
+        let weight = Tensor::randn(0f32, 1.0, (heads * head_dim, cols), &device).unwrap();
+        let x = Tensor::randn(0f32, 1.0, (seq, cols), &device).unwrap();
+        let angles = Tensor::randn(0f32, 1.0, (seq, head_dim / 2), &device).unwrap();
+        let (cos, sin) = (angles.cos().unwrap(), angles.sin().unwrap());
+
+        // (batch, heads, seq, head_dim) projection of x
+        let project = |weight: &Tensor| {
+            x.matmul(&weight.t().unwrap())
+                .unwrap()
+                .reshape((1, seq, heads, head_dim))
+                .unwrap()
+                .transpose(1, 2)
+                .unwrap()
+                .contiguous()
+                .unwrap()
+        };
+        let max_diff = |a: &Tensor, b: &Tensor| {
+            (a - b)
+                .unwrap()
+                .abs()
+                .unwrap()
+                .max_all()
+                .unwrap()
+                .to_scalar::<f32>()
+                .unwrap()
+        };
+
+        let q = rope(&project(&weight), &cos, &sin).unwrap();
+        let permuted = permute_qk(&weight, heads).unwrap();
+        let q_i = rope_i(&project(&permuted), &cos, &sin).unwrap();
+
+        // same features with the two halves of every head interleaved
+        let expected = q
+            .reshape((1, heads, seq, 2, head_dim / 2))
+            .unwrap()
+            .transpose(3, 4)
+            .unwrap()
+            .reshape((1, heads, seq, head_dim))
+            .unwrap();
+        assert!(max_diff(&expected, &q_i) < 1e-5);
+
+        // so the attention scores don't change
+        let scores = q.matmul(&q.t().unwrap()).unwrap();
+        let scores_i = q_i.matmul(&q_i.t().unwrap()).unwrap();
+        assert!(max_diff(&scores, &scores_i) < 1e-4);
+    }
+
+    #[test]
+    fn written_files_match_candle() {
+        let device = Device::Cpu;
+        let matrix = Tensor::randn(0f32, 1.0, (4, 64), &device).unwrap();
+        let vector = Tensor::randn(0f32, 1.0, 5, &device).unwrap();
+        let tensors = [
+            (
+                "a.weight",
+                QTensor::quantize(&matrix, GgmlDType::Q8_0).unwrap(),
+            ),
+            (
+                "b.weight",
+                QTensor::quantize(&matrix, GgmlDType::F16).unwrap(),
+            ),
+            (
+                "c.weight",
+                QTensor::quantize(&vector, GgmlDType::F32).unwrap(),
+            ),
+        ];
+        let metadata = |last: &str| {
+            vec![
+                ("u32".to_string(), Value::U32(2)),
+                ("f32".to_string(), Value::F32(0.5)),
+                ("bool".to_string(), Value::Bool(true)),
+                ("string".to_string(), Value::String("llama".to_string())),
+                (
+                    "array".to_string(),
+                    Value::Array(vec![
+                        Value::String("first".to_string()),
+                        Value::String(last.to_string()),
+                    ]),
+                ),
+                ("empty".to_string(), Value::Array(vec![])),
+            ]
+        };
+
+        let mut expected = std::io::Cursor::new(vec![]);
+        let expected_metadata = metadata("last");
+        gguf_file::write(
+            &mut expected,
+            &expected_metadata
+                .iter()
+                .map(|(k, v)| (k.as_str(), v))
+                .collect::<Vec<_>>(),
+            &tensors.iter().map(|(n, t)| (*n, t)).collect::<Vec<_>>(),
+        )
+        .unwrap();
+
+        // values of the same size can be replaced after the tensors are written
+        let path =
+            std::env::temp_dir().join(format!("spm-gguf-writer-{}.gguf", std::process::id()));
+        let infos: Vec<(String, GgmlDType, Vec<usize>)> = tensors
+            .iter()
+            .map(|(n, t)| (n.to_string(), t.dtype(), t.shape().dims().to_vec()))
+            .collect();
+        let mut writer = GgufWriter::create(&path, &metadata("----"), &infos).unwrap();
+        for (_, tensor) in &tensors {
+            writer.write_tensor(tensor).unwrap();
+        }
+        let last = Value::Array(vec![
+            Value::String("first".to_string()),
+            Value::String("last".to_string()),
+        ]);
+        writer.update_metadata("array", &last).unwrap();
+        assert!(writer
+            .update_metadata("string", &Value::String("llama3".to_string()))
+            .is_err());
+        writer.finish().unwrap();
+
+        let written = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written, expected.into_inner());
+    }
+
+    #[test]
+    fn written_tensors_must_match_their_infos() {
+        let path = std::env::temp_dir().join(format!("spm-gguf-infos-{}.gguf", std::process::id()));
+        let tensor = Tensor::zeros((2, 32), DType::F32, &Device::Cpu).unwrap();
+        let infos = [("a.weight".to_string(), GgmlDType::Q8_0, vec![2, 32])];
+
+        let mut writer = GgufWriter::create(&path, &[], &infos).unwrap();
+        let f16 = QTensor::quantize(&tensor, GgmlDType::F16).unwrap();
+        assert!(writer.write_tensor(&f16).is_err());
+
+        let writer = GgufWriter::create(&path, &[], &infos).unwrap();
+        assert!(writer.finish().is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
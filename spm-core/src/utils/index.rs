@@ -0,0 +1,258 @@
+//! Safetensors index, tells which file holds every tensor of the model.
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use memmap2::MmapOptions;
+use safetensors::SafeTensors;
+
+/// Tensor name -> file map of a model directory.
+pub struct SafetensorsIndex {
+    weight_map: BTreeMap<String, PathBuf>,
+    metadata: serde_json::Value,
+}
+
+impl SafetensorsIndex {
+    /// Read model.safetensors.index.json from data_path, or the header of model.safetensors for
+    /// single file models.
+    pub fn from_path(data_path: &Path) -> Result<Self> {
+        let index = data_path.join("model.safetensors.index.json");
+        if index.exists() {
+            Self::from_index(&index)
+        } else {
+            Self::from_model(&data_path.join("model.safetensors"))
+        }
+    }
+
+    fn from_index(index: &Path) -> Result<Self> {
+        let parent_dir = index.parent().unwrap();
+        let data =
+            std::fs::read(index).map_err(|e| anyhow!("can't read {}: {:?}", index.display(), e))?;
+        let mut json: serde_json::Value = serde_json::from_slice(&data)
+            .map_err(|e| anyhow!("can't parse {}: {:?}", index.display(), e))?;
+
+        let weight_map = match json.get("weight_map") {
+            None => bail!("no weight map in {}", index.display()),
+            Some(serde_json::Value::Object(map)) => map
+                .iter()
+                .filter_map(|(name, file)| {
+                    file.as_str()
+                        .map(|file| (name.to_string(), parent_dir.join(file)))
+                })
+                .collect(),
+            Some(_) => bail!("weight map in {} is not a map", index.display()),
+        };
+        let metadata = json
+            .get_mut("metadata")
+            .map(|m| m.take())
+            .unwrap_or_default();
+
+        Ok(Self {
+            weight_map,
+            metadata,
+        })
+    }
+
+    fn from_model(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path)
+            .map_err(|e| anyhow!("can't open {}: {:?}", path.display(), e))?;
+        let mmap = unsafe { MmapOptions::new().map(&file) }
+            .map_err(|e| anyhow!("can't map {}: {:?}", path.display(), e))?;
+        let (_, metadata) = SafeTensors::read_metadata(&mmap)
+            .map_err(|e| anyhow!("can't read {}: {:?}", path.display(), e))?;
+
+        let weight_map = metadata
+            .tensors()
+            .into_keys()
+            .map(|name| (name, path.to_path_buf()))
+            .collect();
+
+        Ok(Self {
+            weight_map,
+            metadata: serde_json::Value::Null,
+        })
+    }
+
+    /// Return the metadata section of the index, Null if missing.
+    pub fn metadata(&self) -> &serde_json::Value {
+        &self.metadata
+    }
+
+    /// Return the names of the tensors starting with prefix, like model.layers.0.
+    pub fn tensors(&self, prefix: &str) -> Vec<&str> {
+        let prefix = format!("{prefix}.");
+        self.weight_map
+            .keys()
+            .filter(|name| name.starts_with(&prefix))
+            .map(|name| name.as_str())
+            .collect()
+    }
+
+    /// Return the files holding the tensors of the given prefixes, failing if any of them has
+    /// no tensors in the index.
+    pub fn files(&self, prefixes: &[String]) -> Result<Vec<PathBuf>> {
+        let mut files = BTreeSet::new();
+        let mut missing = vec![];
+        for prefix in prefixes {
+            let tensors = self.tensors(prefix);
+            if tensors.is_empty() {
+                missing.push(prefix.as_str());
+            }
+            for name in tensors {
+                files.insert(self.weight_map[name].clone());
+            }
+        }
+
+        if !missing.is_empty() {
+            bail!("tensors not found in the model: {}", missing.join(", "));
+        }
+
+        for path in &files {
+            if !path.exists() {
+                bail!("{} is missing", path.display());
+            }
+        }
+
+        Ok(files.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use candle_core::{DType, Device, Tensor};
+
+    use super::*;
+
+    /// Model directory removed when dropped.
+    struct Model {
+        dir: PathBuf,
+    }
+
+    impl Model {
+        /// Write the tensors in the given files, with an index if there's more than one.
+        fn new(name: &str, files: &[(&str, &[&str])]) -> Self {
+            let dir = std::env::temp_dir().join(format!("spm-index-{name}-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+
+            let mut weight_map = serde_json::Map::new();
+            for (file, names) in files {
+                let tensors: HashMap<String, Tensor> = names
+                    .iter()
+                    .map(|name| {
+                        (
+                            name.to_string(),
+                            Tensor::zeros(2, DType::F32, &Device::Cpu).unwrap(),
+                        )
+                    })
+                    .collect();
+                candle_core::safetensors::save(&tensors, dir.join(file)).unwrap();
+                for name in *names {
+                    weight_map.insert(name.to_string(), file.to_string().into());
+                }
+            }
+            if files.len() > 1 {
+                let index = serde_json::json!({ "weight_map": weight_map });
+                std::fs::write(dir.join("model.safetensors.index.json"), index.to_string())
+                    .unwrap();
+            }
+
+            Self { dir }
+        }
+
+        fn files(&self, prefixes: &[&str]) -> Result<Vec<String>> {
+            let prefixes: Vec<String> = prefixes.iter().map(|p| p.to_string()).collect();
+            let files = SafetensorsIndex::from_path(&self.dir)?.files(&prefixes)?;
+            Ok(files
+                .iter()
+                .map(|f| f.strip_prefix(&self.dir).unwrap().display().to_string())
+                .collect())
+        }
+    }
+
+    impl Drop for Model {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn sharded(name: &str) -> Model {
+        Model::new(
+            name,
+            &[
+                (
+                    "model-00001-of-00003.safetensors",
+                    &["model.embed_tokens.weight", "model.layers.0.mlp.weight"],
+                ),
+                (
+                    "model-00002-of-00003.safetensors",
+                    &["model.layers.1.mlp.weight", "model.layers.10.mlp.weight"],
+                ),
+                (
+                    "model-00003-of-00003.safetensors",
+                    &["model.layers.10.attn.weight", "lm_head.weight"],
+                ),
+            ],
+        )
+    }
+
+    #[test]
+    fn only_the_needed_files_are_returned() {
+        let model = sharded("needed");
+        assert_eq!(
+            model.files(&["model.layers.0"]).unwrap(),
+            ["model-00001-of-00003.safetensors"]
+        );
+        // model.layers.1 doesn't match model.layers.10
+        assert_eq!(
+            model.files(&["model.layers.1"]).unwrap(),
+            ["model-00002-of-00003.safetensors"]
+        );
+        assert_eq!(
+            model
+                .files(&["model.layers.10", "model.embed_tokens"])
+                .unwrap(),
+            [
+                "model-00001-of-00003.safetensors",
+                "model-00002-of-00003.safetensors",
+                "model-00003-of-00003.safetensors"
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_tensors_are_reported() {
+        let model = sharded("missing");
+        let err = model
+            .files(&["model.layers.0", "model.layers.2", "model.norm"])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "tensors not found in the model: model.layers.2, model.norm"
+        );
+
+        std::fs::remove_file(model.dir.join("model-00002-of-00003.safetensors")).unwrap();
+        assert!(model.files(&["model.layers.0"]).is_ok());
+        let err = model.files(&["model.layers.1"]).unwrap_err();
+        assert!(err.to_string().ends_with("is missing"), "{err}");
+    }
+
+    #[test]
+    fn single_file_models_have_no_index() {
+        let model = Model::new(
+            "single",
+            &[(
+                "model.safetensors",
+                &["model.layers.0.mlp.weight", "lm_head.weight"],
+            )],
+        );
+        assert_eq!(
+            model.files(&["model.layers.0", "lm_head"]).unwrap(),
+            ["model.safetensors"]
+        );
+        assert!(model.files(&["model.layers.1"]).is_err());
+    }
+}
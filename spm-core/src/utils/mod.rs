@@ -1,6 +1,6 @@
 //! Utility functions and abstractions.
 
-use std::path::PathBuf;
+use std::path::Path;
 
 use candle_core::{
     utils::{cuda_is_available, metal_is_available},
@@ -10,6 +10,17 @@ use candle_core::{
 use anyhow::{bail, Result};
 
 use candle_nn::VarBuilder;
+use sha2::{Digest, Sha256};
+
+mod fingerprint;
+mod gguf;
+mod index;
+mod weights;
+
+pub use fingerprint::*;
+pub use gguf::*;
+pub use index::*;
+pub use weights::*;
 
 /// Returns the best available device at `ordinal` index (in case of multiple GPUs), or CPU if `force_cpu` is true.
 pub fn get_inference_device(force_cpu: bool, ordinal: usize) -> Result<Device> {
@@ -29,73 +40,23 @@ pub fn get_inference_device(force_cpu: bool, ordinal: usize) -> Result<Device> {
     }
 }
 
-pub fn load_safetensors_from_model(
-    tensors_index_json_filename: PathBuf,
-) -> Result<Vec<std::path::PathBuf>> {
-    log::info!("loading tensors from {} ...", "model.safetensors");
-    let parent_dir = tensors_index_json_filename.parent().unwrap();
-    let result = vec![parent_dir.join("model.safetensors")];
-    Ok(result)
-}
-
-/// Load the safetensors files for a model from the hub based on a json index file.
-pub fn load_safetensors_paths_from_index(
-    tensors_index_json_filename: PathBuf,
-) -> Result<Vec<std::path::PathBuf>> {
-    log::info!(
-        "loading tensors from {} ...",
-        tensors_index_json_filename.display()
-    );
-
-    let parent_dir = tensors_index_json_filename.parent().unwrap();
-    let json_file = std::fs::File::open(&tensors_index_json_filename).map_err(|e| {
-        anyhow!(
-            "can't open {}: {:?}",
-            tensors_index_json_filename.display(),
-            e
-        )
-    })?;
-    let json: serde_json::Value = serde_json::from_reader(&json_file).map_err(|e| {
-        anyhow!(
-            "can't parse {}: {:?}",
-            tensors_index_json_filename.display(),
-            e
-        )
-    })?;
-    let weight_map = match json.get("weight_map") {
-        None => bail!("no weight map in {json_file:?}"),
-        Some(serde_json::Value::Object(map)) => map,
-        Some(_) => bail!("weight map in {json_file:?} is not a map"),
-    };
-    let mut safetensors_files = std::collections::HashSet::new();
-    for value in weight_map.values() {
-        if let Some(file) = value.as_str() {
-            safetensors_files.insert(file.to_string());
-        }
-    }
-    let safetensors_files = safetensors_files
-        .iter()
-        .map(|v| parent_dir.join(v))
-        .collect::<Vec<std::path::PathBuf>>();
-
-    Ok(safetensors_files)
-}
-
-/// Create a VarBuilder with the tensors loaded from the index.
+/// Create a VarBuilder mapping only the files that hold the tensors starting with the given
+/// prefixes, fails if any of them is missing.
 pub fn load_var_builder_from_index<'a>(
-    tensor_index: PathBuf,
+    data_path: &Path,
+    prefixes: &[String],
     dtype: DType,
     device: Device,
 ) -> Result<VarBuilder<'a>> {
-    log::info!("loading tensors in {}", tensor_index.display());
+    log::info!("loading tensors in {}", data_path.display());
 
-    let filenames: Vec<std::path::PathBuf> = if tensor_index.exists() {
-        load_safetensors_paths_from_index(tensor_index)
-            .map_err(|e| anyhow!("can't load tensors index: {:?}", e))?
-    } else {
-        load_safetensors_from_model(tensor_index)
-            .map_err(|e| anyhow!("can't load tensors index: {:?}", e))?
-    };
+    let filenames = SafetensorsIndex::from_path(data_path)
+        .and_then(|index| index.files(prefixes))
+        .map_err(|e| anyhow!("can't load tensors index: {:?}", e))?;
+
+    for path in &filenames {
+        log::debug!("mapping {}", path.display());
+    }
 
     unsafe {
         VarBuilder::from_mmaped_safetensors(&filenames, dtype, &device)
@@ -103,6 +64,82 @@ pub fn load_var_builder_from_index<'a>(
     }
 }
 
+/// Load the tensors starting with the given prefixes from the GGUF file of the model if it's
+/// one, or from the safetensors files of the model directory.
+pub fn load_weights(
+    data_path: &Path,
+    gguf: Option<&Gguf>,
+    prefixes: &[String],
+    dtype: DType,
+    device: Device,
+) -> Result<Weights> {
+    match gguf {
+        Some(gguf) => {
+            let tensors = gguf.load(prefixes, &device)?;
+            Ok(Weights::from_gguf(tensors, dtype, device))
+        }
+        None => {
+            let vb = load_var_builder_from_index(data_path, prefixes, dtype, device)?;
+            Ok(Weights::from_var_builder(vb))
+        }
+    }
+}
+
+/// Return the sha256 hex digest of a json configuration file, independent of its formatting and keys order.
+pub fn config_hash(config_filename: &Path) -> Result<String> {
+    let data = std::fs::read(config_filename)
+        .map_err(|e| anyhow!("can't read {}: {:?}", config_filename.display(), e))?;
+    let json: serde_json::Value = serde_json::from_slice(&data)
+        .map_err(|e| anyhow!("can't parse {}: {:?}", config_filename.display(), e))?;
+    // serde_json objects are sorted by key
+    let canonical = serde_json::to_vec(&json)?;
+
+    Ok(format!("{:x}", Sha256::digest(&canonical)))
+}
+
+/// Parse the --dtype argument, f16 is the default.
+pub fn parse_dtype(dtype: Option<&str>) -> Result<DType> {
+    match dtype {
+        Some("f16") | None => Ok(DType::F16),
+        Some("bf16") => Ok(DType::BF16),
+        Some("f32") => Ok(DType::F32),
+        Some(dtype) => bail!("unsupported dtype {dtype}"),
+    }
+}
+
+/// Return the memory in bytes available to new allocations on the device: free VRAM on CUDA,
+/// the recommended working set left on Metal and the available system memory on CPU.
+pub fn available_memory(device: &Device) -> Result<u64> {
+    match device {
+        #[cfg(feature = "cuda")]
+        Device::Cuda(cuda) => {
+            use candle_core::cuda::cudarc::driver::result::mem_get_info;
+
+            cuda.cuda_device()
+                .bind_to_thread()
+                .map_err(|e| anyhow!("can't bind to cuda device: {:?}", e))?;
+            let (free, _total) =
+                mem_get_info().map_err(|e| anyhow!("can't read cuda memory: {:?}", e))?;
+            Ok(free as u64)
+        }
+        #[cfg(feature = "metal")]
+        Device::Metal(metal) => {
+            let device = metal.device();
+            Ok(device
+                .recommended_max_working_set_size()
+                .saturating_sub(device.current_allocated_size()))
+        }
+        _ => {
+            let mut system = sysinfo::System::new();
+            system.refresh_memory();
+            match system.available_memory() {
+                0 => bail!("can't read the available system memory"),
+                memory => Ok(memory),
+            }
+        }
+    }
+}
+
 /// Nasty hack to debug NaN in tensors.
 #[allow(dead_code)]
 pub(crate) fn panic_on_nan(t: &Tensor, name: &str) {
@@ -0,0 +1,358 @@
+//! Model weights, loaded either from safetensors or from a quantized GGUF file.
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Arc,
+};
+
+use candle_core::{
+    quantized::{GgmlDType, QMatMul, QTensor},
+    DType, Device, Result, Shape, Tensor,
+};
+use candle_nn::VarBuilder;
+
+/// Quantization of the weights of the linear layers, applied while loading them.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quantization {
+    /// 8 bit, blocks of 32 weights.
+    #[value(name = "q8_0")]
+    Q8_0,
+    /// 4 bit, blocks of 32 weights.
+    #[value(name = "q4_0")]
+    Q4_0,
+    /// 4 bit k-quants, super blocks of 256 weights.
+    #[value(name = "q4k")]
+    Q4K,
+}
+
+impl Quantization {
+    /// Return the ggml type the weights are quantized to.
+    pub fn ggml_dtype(&self) -> GgmlDType {
+        match self {
+            Quantization::Q8_0 => GgmlDType::Q8_0,
+            Quantization::Q4_0 => GgmlDType::Q4_0,
+            Quantization::Q4K => GgmlDType::Q4K,
+        }
+    }
+
+    /// Return the size in bytes of a weight of the given shape once quantized, or None if its
+    /// rows can't be split in blocks.
+    pub fn quantized_size(&self, shape: &[usize]) -> Option<u64> {
+        let dtype = self.ggml_dtype();
+        match shape.last() {
+            Some(cols) if cols % dtype.block_size() == 0 => {
+                let elements: usize = shape.iter().product();
+                Some((elements / dtype.block_size() * dtype.type_size()) as u64)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Quantization {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Quantization::Q8_0 => "q8_0",
+                Quantization::Q4_0 => "q4_0",
+                Quantization::Q4K => "q4k",
+            }
+        )
+    }
+}
+
+#[derive(Clone)]
+enum Source {
+    Safetensors(VarBuilder<'static>),
+    Gguf(Arc<HashMap<String, Arc<QTensor>>>),
+}
+
+/// Weights of the model. Tensors are always looked up by their safetensors name, like
+/// model.layers.0.mlp.up_proj.weight, whatever the format of the checkpoint.
+#[derive(Clone)]
+pub struct Weights {
+    source: Source,
+    path: Vec<String>,
+    dtype: DType,
+    device: Device,
+    /// Quantization of the dense weights of the linear layers.
+    quantization: Option<Quantization>,
+}
+
+impl Weights {
+    /// Create the weights from a safetensors VarBuilder.
+    pub fn from_var_builder(vb: VarBuilder<'static>) -> Self {
+        let dtype = vb.dtype();
+        let device = vb.device().clone();
+        Self {
+            source: Source::Safetensors(vb),
+            path: vec![],
+            dtype,
+            device,
+            quantization: None,
+        }
+    }
+
+    /// Create the weights from GGUF tensors keyed by their safetensors name, dense tensors are
+    /// converted to dtype when loaded.
+    pub fn from_gguf(tensors: HashMap<String, Arc<QTensor>>, dtype: DType, device: Device) -> Self {
+        Self {
+            source: Source::Gguf(Arc::new(tensors)),
+            path: vec![],
+            dtype,
+            device,
+            quantization: None,
+        }
+    }
+
+    /// Return the weights with the given prefix.
+    pub fn pp<S: ToString>(&self, s: S) -> Self {
+        let mut path = self.path.clone();
+        path.push(s.to_string());
+        Self {
+            source: self.source.clone(),
+            path,
+            dtype: self.dtype,
+            device: self.device.clone(),
+            quantization: self.quantization,
+        }
+    }
+
+    /// Quantize the dense weights of the linear layers as they are loaded.
+    pub fn with_quantization(mut self, quantization: Option<Quantization>) -> Self {
+        self.quantization = quantization;
+        self
+    }
+
+    /// Return true if the weights come from a GGUF file. llama.cpp permutes the query and key
+    /// weights so that the rotary embeddings are applied to interleaved pairs.
+    pub fn is_gguf(&self) -> bool {
+        matches!(self.source, Source::Gguf(_))
+    }
+
+    /// Describe the quantization of the weights of the linear layers, like q4k or q4k+q6k for
+    /// GGUF files mixing types, None if they're not quantized.
+    pub fn quantization(&self) -> Option<String> {
+        let mut types = BTreeSet::new();
+        match &self.source {
+            Source::Safetensors(_) => {
+                if let Some(quantization) = self.quantization {
+                    types.insert(quantization.to_string());
+                }
+            }
+            Source::Gguf(tensors) => {
+                for (name, qtensor) in tensors.iter() {
+                    // embeddings are dequantized when loaded
+                    if qtensor.shape().rank() != 2 || name.starts_with("model.embed_tokens.") {
+                        continue;
+                    }
+                    let dtype = qtensor.dtype();
+                    if dtype.block_size() > 1 {
+                        types.insert(format!("{dtype:?}").to_lowercase());
+                    } else if let Some(quantization) = self.quantization {
+                        types.insert(quantization.to_string());
+                    }
+                }
+            }
+        }
+
+        if types.is_empty() {
+            None
+        } else {
+            Some(types.into_iter().collect::<Vec<_>>().join("+"))
+        }
+    }
+
+    fn path(&self, name: &str) -> String {
+        if self.path.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{name}", self.path.join("."))
+        }
+    }
+
+    fn get_qtensor(
+        tensors: &HashMap<String, Arc<QTensor>>,
+        shape: &Shape,
+        path: &str,
+    ) -> Result<Arc<QTensor>> {
+        let qtensor = match tensors.get(path) {
+            Some(qtensor) => qtensor,
+            None => candle_core::bail!("cannot find tensor {path}"),
+        };
+        if qtensor.shape() != shape {
+            candle_core::bail!(
+                "shape mismatch for {path}, got {:?}, expected {shape:?}",
+                qtensor.shape()
+            );
+        }
+        Ok(qtensor.clone())
+    }
+
+    /// Return a dense tensor, dequantized if needed.
+    pub fn get<S: Into<Shape>>(&self, shape: S, name: &str) -> Result<Tensor> {
+        let shape = shape.into();
+        let path = self.path(name);
+        match &self.source {
+            Source::Safetensors(vb) => vb.get(shape, &path),
+            Source::Gguf(tensors) => Self::get_qtensor(tensors, &shape, &path)?
+                .dequantize(&self.device)?
+                .to_dtype(self.dtype),
+        }
+    }
+
+    /// Return the weight of a linear layer, quantized weights are kept quantized and dense ones
+    /// are quantized if a quantization is set.
+    pub fn get_matmul<S: Into<Shape>>(&self, shape: S, name: &str) -> Result<QMatMul> {
+        let shape = shape.into();
+        let path = self.path(name);
+        let matmul = match &self.source {
+            Source::Safetensors(vb) => QMatMul::Tensor(vb.get(shape, &path)?),
+            Source::Gguf(tensors) => {
+                match QMatMul::from_arc(Self::get_qtensor(tensors, &shape, &path)?)? {
+                    // f16 and f32 tensors are dequantized
+                    QMatMul::Tensor(t) => QMatMul::Tensor(t.to_dtype(self.dtype)?),
+                    matmul => matmul,
+                }
+            }
+        };
+
+        match (matmul, self.quantization) {
+            (QMatMul::Tensor(weight), Some(quantization)) => {
+                if quantization.quantized_size(weight.dims()).is_none() {
+                    log::warn!(
+                        "{path} {:?} can't be split in {quantization} blocks, keeping it {:?}",
+                        weight.shape(),
+                        self.dtype
+                    );
+                    return Ok(QMatMul::Tensor(weight));
+                }
+                let qtensor = QTensor::quantize(&weight, quantization.ggml_dtype())?;
+                Ok(QMatMul::QTensor(Arc::new(qtensor)))
+            }
+            (matmul, _) => Ok(matmul),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_nn::Module;
+
+    use super::*;
+    use crate::{models::llama3::testing::Checkpoint, utils::load_weights};
+
+    /// Linear weights of a block as (name, (rows, cols)).
+    fn linear_weights(checkpoint: &Checkpoint) -> Vec<(&'static str, (usize, usize))> {
+        let config = &checkpoint.config;
+        let hidden = config.hidden_size;
+        let kv = hidden / config.num_attention_heads * config.num_key_value_heads;
+        let intermediate = config.intermediate_size;
+        vec![
+            ("self_attn.q_proj.weight", (hidden, hidden)),
+            ("self_attn.k_proj.weight", (kv, hidden)),
+            ("self_attn.v_proj.weight", (kv, hidden)),
+            ("self_attn.o_proj.weight", (hidden, hidden)),
+            ("mlp.gate_proj.weight", (intermediate, hidden)),
+            ("mlp.up_proj.weight", (intermediate, hidden)),
+            ("mlp.down_proj.weight", (hidden, intermediate)),
+        ]
+    }
+
+    fn layer_weights(checkpoint: &Checkpoint, quantization: Option<Quantization>) -> Weights {
+        load_weights(
+            &checkpoint.dir,
+            None,
+            &["model.layers.0".to_string()],
+            DType::F32,
+            Device::Cpu,
+        )
+        .unwrap()
+        .with_quantization(quantization)
+        .pp("model.layers.0")
+    }
+
+    /// Return the largest difference between the outputs relative to the largest dense output.
+    fn relative_error(dense: &Tensor, quantized: &Tensor) -> f32 {
+        let diff = (dense - quantized)
+            .unwrap()
+            .abs()
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .max(0)
+            .unwrap();
+        let max = dense.abs().unwrap().flatten_all().unwrap().max(0).unwrap();
+        diff.to_scalar::<f32>().unwrap() / max.to_scalar::<f32>().unwrap()
+    }
+
+    #[test]
+    fn quantized_sizes() {
+        // 34 bytes for 32 weights
+        assert_eq!(Quantization::Q8_0.quantized_size(&[64, 64]), Some(4352));
+        // 18 bytes for 32 weights
+        assert_eq!(Quantization::Q4_0.quantized_size(&[2, 96]), Some(108));
+        // 144 bytes for 256 weights
+        assert_eq!(Quantization::Q4K.quantized_size(&[3, 512]), Some(864));
+
+        // rows must be made of whole blocks
+        assert_eq!(Quantization::Q8_0.quantized_size(&[32, 48]), None);
+        assert_eq!(Quantization::Q4K.quantized_size(&[256, 128]), None);
+        assert_eq!(Quantization::Q8_0.quantized_size(&[]), None);
+    }
+
+    #[test]
+    fn linear_weights_are_quantized_when_loaded() {
+        let checkpoint = Checkpoint::new("load-quantized");
+        let dense = layer_weights(&checkpoint, None);
+        assert_eq!(dense.quantization(), None);
+        let cols = checkpoint.config.intermediate_size;
+        let x = Tensor::randn(0f32, 1., (3, cols), &Device::Cpu).unwrap();
+
+        for (quantization, max_error) in [(Quantization::Q8_0, 0.03), (Quantization::Q4_0, 0.25)] {
+            let weights = layer_weights(&checkpoint, Some(quantization));
+            assert_eq!(weights.quantization(), Some(quantization.to_string()));
+
+            for (name, shape) in linear_weights(&checkpoint) {
+                let matmul = weights.get_matmul(shape, name).unwrap();
+                match &matmul {
+                    QMatMul::QTensor(qtensor) => {
+                        assert_eq!(qtensor.dtype(), quantization.ggml_dtype(), "{name}");
+                        assert_eq!(qtensor.shape().dims(), [shape.0, shape.1], "{name}");
+                    }
+                    _ => panic!("{name} isn't quantized to {quantization}"),
+                }
+
+                let x = x.narrow(1, 0, shape.1).unwrap().contiguous().unwrap();
+                let expected = dense.get_matmul(shape, name).unwrap().forward(&x).unwrap();
+                let error = relative_error(&expected, &matmul.forward(&x).unwrap());
+                assert!(error < max_error, "{name} {quantization}: {error}");
+            }
+        }
+    }
+
+    #[test]
+    fn weights_not_split_in_blocks_stay_dense() {
+        let checkpoint = Checkpoint::new("load-not-quantized");
+        // the rows of the checkpoint are shorter than a k-quants super block
+        let weights = layer_weights(&checkpoint, Some(Quantization::Q4K));
+        let dense = layer_weights(&checkpoint, None);
+
+        for (name, shape) in linear_weights(&checkpoint) {
+            assert_eq!(Quantization::Q4K.quantized_size(&[shape.0, shape.1]), None);
+            match weights.get_matmul(shape, name).unwrap() {
+                QMatMul::Tensor(weight) => {
+                    let expected = dense.get(shape, name).unwrap();
+                    assert_eq!(weight.dtype(), DType::F32);
+                    assert_eq!(
+                        weight.to_vec2::<f32>().unwrap(),
+                        expected.to_vec2::<f32>().unwrap(),
+                        "{name}"
+                    );
+                }
+                _ => panic!("{name} was quantized"),
+            }
+        }
+    }
+}